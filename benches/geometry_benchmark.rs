@@ -1,31 +1,37 @@
-use criterion::{BenchmarkId, black_box, Criterion};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use criterion::{BenchmarkGroup, BenchmarkId, black_box, Criterion};
+use criterion::measurement::WallTime;
 use rand::{Rng, SeedableRng};
 use rand::rngs::SmallRng;
 
-use rust_ray_tracer::geometry::{Shape, Hittable};
+use rust_ray_tracer::geometry::{ArcHittable, Hittable, Plane, Sphere};
 use rust_ray_tracer::material::Material;
 use rust_ray_tracer::point3::Point3;
 use rust_ray_tracer::ray::Ray;
-use rust_ray_tracer::scene::{RenderConfig, setup_small_scene, setup_scene};
+use rust_ray_tracer::scene::{read_obj, read_scene, RenderConfig, Scene, setup_small_scene, setup_scene};
 use rust_ray_tracer::vec3::Vec3;
-use rust_ray_tracer::bvh::BVHNode;
+use rust_ray_tracer::bvh::{BVHBuilder, BVHNode, FlatBVH};
+use rust_ray_tracer::bvh_cache::BVHCache;
 
 pub fn hit_by_benchmark(c: &mut Criterion) {
-    let shapes = [
-        ("sphere", Shape::Sphere {
+    let shapes: [(&str, ArcHittable); 2] = [
+        ("sphere", Arc::new(Sphere {
             center: Point3::new(0.0, 0.0, 0.0),
             radius: 2.0,
             material: Material::BlackBody,
-        }),
-        ("plane", Shape::Plane {
+        })),
+        ("plane", Arc::new(Plane {
             center: Point3::new(0.0, 0.0, 0.0),
             normal: Vec3::new(0.0, 0.0, 1.0),
             material: Material::BlackBody,
-        })
+        }))
     ];
     let ray = Ray {
         orig: Point3::new(3.0, 3.0, 3.0),
         dir: Vec3::new(0.0, 0.0, 0.0),
+        time: 0.0,
     };
 
     let mut group = c.benchmark_group("hit_by");
@@ -34,7 +40,7 @@ pub fn hit_by_benchmark(c: &mut Criterion) {
             BenchmarkId::from_parameter(shape_name),
             &shape,
             |b, hittable| b.iter(|| {
-                hittable.hit_by(
+                hittable.hit_by(rng, 
                     &ray,
                     black_box(std::f64::NEG_INFINITY),
                     black_box(std::f64::INFINITY),
@@ -62,9 +68,10 @@ pub fn hit_by_list_benchmark(c: &mut Criterion) {
             let ray = Ray {
                 orig: Point3::new(rng.gen_range(-5.0..5.0), rng.gen_range(-5.0..5.0), rng.gen_range(-5.0..5.0)),
                 dir: Vec3::random_unit_vector(&mut rng),
+                time: 0.0,
             };
 
-            list.hit_by(
+            list.hit_by(rng, 
                 black_box(&ray),
                 black_box(std::f64::NEG_INFINITY),
                 black_box(std::f64::INFINITY),
@@ -94,9 +101,10 @@ pub fn bvh_benchmark(c: &mut Criterion) {
                 let ray = Ray {
                     orig: Point3::new(rng.gen_range(-5.0..5.0), rng.gen_range(-5.0..5.0), rng.gen_range(-5.0..5.0)),
                     dir: Vec3::random_unit_vector(&mut rng),
+                    time: 0.0,
                 };
 
-                list.hit_by(
+                list.hit_by(rng, 
                     black_box(&ray),
                     black_box(0.001),
                     black_box(std::f64::INFINITY),
@@ -113,9 +121,10 @@ pub fn bvh_benchmark(c: &mut Criterion) {
                 let ray = Ray {
                     orig: Point3::new(rng.gen_range(-5.0..5.0), rng.gen_range(-5.0..5.0), rng.gen_range(-5.0..5.0)),
                     dir: Vec3::random_unit_vector(&mut rng),
+                    time: 0.0,
                 };
 
-                bvh.hit_by(
+                bvh.hit_by(rng, 
                     black_box(&ray),
                     black_box(0.001),
                     black_box(std::f64::INFINITY),
@@ -126,4 +135,58 @@ pub fn bvh_benchmark(c: &mut Criterion) {
 
 
     group.finish();
-}
\ No newline at end of file
+}
+
+pub fn bvh_builder_benchmark(c: &mut Criterion) {
+    let mut rng = SmallRng::seed_from_u64(42213);
+    let builders = [("median", BVHBuilder::Median), ("sah", BVHBuilder::default())];
+
+    let triangles = read_obj("teapot.obj", &Material::BlackBody, &HashMap::new(), &HashMap::new(), 0.0, &mut vec![]).unwrap();
+    let scene_large = read_scene("scene_large.yaml", None, BVHCache::Disabled).unwrap();
+
+    let mut group = c.benchmark_group("bvh_build");
+    for (name, builder) in builders.iter() {
+        group.bench_function(BenchmarkId::new("teapot", name), |b| b.iter(|| {
+            BVHNode::build(&mut rng, *builder, triangles.clone().as_mut_slice())
+        }));
+        group.bench_function(BenchmarkId::new("scene_large", name), |b| b.iter(|| {
+            BVHNode::build(&mut rng, *builder, scene_large.hittables.clone().as_mut_slice())
+        }));
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("bvh_hit_by");
+    for (name, builder) in builders.iter() {
+        let teapot = BVHNode::build(&mut rng, *builder, triangles.clone().as_mut_slice());
+        let scene_bvh = BVHNode::build(&mut rng, *builder, scene_large.hittables.clone().as_mut_slice());
+        bench_hit_by(&mut group, &mut rng, name, &teapot, &scene_large, &scene_bvh);
+
+        let flat_name = format!("{}_flat", name);
+        bench_hit_by(&mut group, &mut rng, &flat_name, &FlatBVH::new(teapot), &scene_large, &FlatBVH::new(scene_bvh));
+    }
+    group.finish();
+}
+
+fn bench_hit_by<T: Hittable, S: Hittable>(
+    group: &mut BenchmarkGroup<WallTime>,
+    rng: &mut SmallRng,
+    name: &str,
+    teapot: &T,
+    scene_large: &Scene,
+    scene_bvh: &S,
+) {
+    group.bench_with_input(BenchmarkId::new("teapot", name), teapot, |b, bvh| b.iter(|| {
+        // Rays from around the teapot towards its center.
+        let orig = Point3::new(0.0, 1.5, 0.0) + 6.0 * Vec3::random_unit_vector(rng);
+        let target = Point3::new(rng.gen_range(-2.0..2.0), rng.gen_range(0.0..3.0), rng.gen_range(-2.0..2.0));
+        let ray = Ray { orig, dir: target - orig, time: 0.0 };
+        bvh.hit_by(rng, black_box(&ray), black_box(0.001), black_box(f64::INFINITY))
+    }));
+
+    group.bench_with_input(BenchmarkId::new("scene_large", name), scene_bvh, |b, bvh| b.iter(|| {
+        let s = rng.gen();
+        let t = rng.gen();
+        let ray = scene_large.camera.get_ray(rng, s, t);
+        bvh.hit_by(rng, black_box(&ray), black_box(0.001), black_box(f64::INFINITY))
+    }));
+}
@@ -1,6 +1,6 @@
 use criterion::{criterion_group, criterion_main};
 
-use crate::geometry_benchmark::{hit_by_benchmark, hit_by_list_benchmark, bvh_benchmark};
+use crate::geometry_benchmark::{hit_by_benchmark, hit_by_list_benchmark, bvh_benchmark, bvh_builder_benchmark};
 use crate::material_benchmark::scatter_benchmark;
 use crate::render_benchmark::{ray_color_benchmark, render_image_benchmark};
 
@@ -13,6 +13,7 @@ criterion_group!(
     hit_by_benchmark,
     hit_by_list_benchmark,
     bvh_benchmark,
+    bvh_builder_benchmark,
     scatter_benchmark,
     ray_color_benchmark,
     render_image_benchmark
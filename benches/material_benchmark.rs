@@ -7,14 +7,15 @@ use rust_ray_tracer::material::Material;
 use rust_ray_tracer::point3::Point3;
 use rust_ray_tracer::vec3::Vec3;
 use rust_ray_tracer::ray::Ray;
+use rust_ray_tracer::texture::Texture;
 
 pub fn scatter_benchmark(c: &mut Criterion) {
     let mut rng = SmallRng::seed_from_u64(42213);
 
     let albedo = Color::random(&mut rng);
     let materials = [
-        ("lambertian", Material::Lambertian { albedo }),
-        ("metal", Material::Metal { albedo, fuzz: 0.5 }),
+        ("lambertian", Material::Lambertian { albedo: Texture::Constant(albedo) }),
+        ("metal", Material::Metal { albedo: Texture::Constant(albedo), fuzz: 0.5 }),
         ("dielectric", Material::Dielectric { index_of_refraction: 1.5 }),
         ("black_body", Material::BlackBody)
     ];
@@ -23,16 +24,21 @@ pub fn scatter_benchmark(c: &mut Criterion) {
     let ray = Ray {
         orig: Point3::new(0.0, 0.0, 0.0),
         dir: Vec3::new(1.0, 0.0, 0.0),
+        time: 0.0,
     };
 
     let mut group = c.benchmark_group("scatter");
     for (mat_name, material) in materials.iter() {
         let hit_record = HitRecord {
-            material: *material,
+            material,
             t: 1.0,
+            u: 0.0,
+            v: 0.0,
             front_face: true,
             point: Point3::new(1.0, 0.0, 0.0),
             normal: Vec3::new(angle_rad.cos(), angle_rad.sin(), 0.0),
+            tangent: Vec3::new(-angle_rad.sin(), angle_rad.cos(), 0.0),
+            bitangent: Vec3::new(0.0, 0.0, 1.0),
         };
         group.bench_with_input(
             BenchmarkId::from_parameter(mat_name),
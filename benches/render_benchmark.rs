@@ -25,6 +25,7 @@ pub fn ray_color_benchmark(c: &mut Criterion) {
                 &mut rng,
                 black_box(&ray),
                 black_box(&scene.hittables),
+                black_box(&scene),
                 black_box(scene.render_config.max_depth)
             )
         });
@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+
+use crate::color::Color;
+use crate::environment::EnvironmentMap;
+use crate::vec3::Vec3;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Background {
+    Solid { color: Color },
+    Gradient {
+        bottom: Color,
+        top: Color,
+        up: Vec3,
+    },
+    Environment(EnvironmentMap),
+    None,
+}
+
+impl Default for Background {
+    fn default() -> Background {
+        Background::Gradient {
+            bottom: Color::new(1.0, 1.0, 1.0),
+            top: Color::new(0.5, 0.7, 1.0),
+            up: Vec3::new(0.0, 1.0, 0.0),
+        }
+    }
+}
+
+impl Background {
+    pub fn color(&self, dir: Vec3) -> Color {
+        match *self {
+            Background::Solid { color } => color,
+            Background::Gradient { bottom, top, up } => {
+                let t = 0.5 * (dir.normalize().dot(up.normalize()) + 1.0);
+                (1.0 - t) * bottom + t * top
+            }
+            Background::Environment(ref map) => map.radiance(dir),
+            Background::None => Color::new(0.0, 0.0, 0.0)
+        }
+    }
+
+    /// Returns the environment map if it should be sampled explicitly at diffuse bounces.
+    pub fn sampled_environment(&self) -> Option<&EnvironmentMap> {
+        match self {
+            Background::Environment(map) if map.importance_sampling() => Some(map),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn background(yaml: &str) -> Background {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn rgb(color: Color) -> (f64, f64, f64) {
+        (color.r, color.g, color.b)
+    }
+
+    #[test]
+    fn solid_and_none_backgrounds_ignore_the_direction() {
+        let solid = background("type: Solid\ncolor: [0.1, 0.2, 0.3]");
+        assert_eq!(rgb(solid.color(Vec3::new(0.0, 1.0, 0.0))), (0.1, 0.2, 0.3));
+        assert_eq!(rgb(solid.color(Vec3::new(1.0, -1.0, 0.0))), (0.1, 0.2, 0.3));
+        assert_eq!(rgb(background("type: None").color(Vec3::new(0.0, 1.0, 0.0))), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn gradient_blends_from_bottom_to_top_along_up() {
+        let gradient = background("type: Gradient\nbottom: [1.0, 0.0, 0.0]\ntop: [0.0, 0.0, 1.0]\nup: [0.0, 0.0, 2.0]");
+        assert_eq!(rgb(gradient.color(Vec3::new(0.0, 0.0, 5.0))), (0.0, 0.0, 1.0));
+        assert_eq!(rgb(gradient.color(Vec3::new(0.0, 0.0, -5.0))), (1.0, 0.0, 0.0));
+        assert_eq!(rgb(gradient.color(Vec3::new(3.0, 0.0, 0.0))), (0.5, 0.0, 0.5));
+    }
+
+    #[test]
+    fn default_is_the_sky_gradient() {
+        assert_eq!(rgb(Background::default().color(Vec3::new(0.0, 1.0, 0.0))), (0.5, 0.7, 1.0));
+    }
+}
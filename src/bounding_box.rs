@@ -1,5 +1,6 @@
 use crate::point3::Point3;
 use crate::ray::Ray;
+use crate::vec3::Vec3;
 use std::mem::swap;
 
 #[derive(Debug, Clone, Copy)]
@@ -9,6 +10,21 @@ pub struct BBox {
 }
 
 impl BBox {
+    /// Box around nothing, which no ray hits and which doesn't grow the boxes it surrounds.
+    pub fn empty() -> BBox {
+        BBox {
+            min: Point3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+            max: Point3::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+        }
+    }
+
+    pub fn infinite() -> BBox {
+        BBox {
+            min: Point3::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+            max: Point3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+        }
+    }
+
     pub fn hit(&self, ray: &Ray, mut t_min: f64, mut t_max: f64) -> bool {
         for i in 0..3 {
             let inv_d = 1.0 / ray.dir[i];
@@ -19,7 +35,7 @@ impl BBox {
             }
             t_min = t_min.max(t0);
             t_max = t_max.min(t1);
-            if t_max <= t_min {
+            if t_max < t_min {
                 return false;
             }
         }
@@ -27,6 +43,67 @@ impl BBox {
         return true;
     }
 
+    /// Part of the segment of `ray` between `t_min` and `t_max` that is inside the box.
+    pub fn clip(&self, ray: &Ray, mut t_min: f64, mut t_max: f64) -> Option<(f64, f64)> {
+        for i in 0..3 {
+            let inv_d = 1.0 / ray.dir[i];
+            let mut t0 = (self.min[i] - ray.orig[i]) * inv_d;
+            let mut t1 = (self.max[i] - ray.orig[i]) * inv_d;
+            if inv_d < 0.0 {
+                swap(&mut t0, &mut t1);
+            }
+            t_min = t_min.max(t0);
+            t_max = t_max.min(t1);
+            if t_max < t_min {
+                return None;
+            }
+        }
+
+        Some((t_min, t_max))
+    }
+
+    /// Same as `hit` with the reciprocal of the ray direction computed once by the caller.
+    pub fn hit_inverse(&self, orig: Point3, inv_dir: Vec3, mut t_min: f64, mut t_max: f64) -> bool {
+        for i in 0..3 {
+            let mut t0 = (self.min[i] - orig[i]) * inv_dir[i];
+            let mut t1 = (self.max[i] - orig[i]) * inv_dir[i];
+            if inv_dir[i] < 0.0 {
+                swap(&mut t0, &mut t1);
+            }
+            t_min = t_min.max(t0);
+            t_max = t_max.min(t1);
+            if t_max < t_min {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    pub fn centroid(&self) -> Point3 {
+        Point3::new(
+            0.5 * (self.min[0] + self.max[0]),
+            0.5 * (self.min[1] + self.max[1]),
+            0.5 * (self.min[2] + self.max[2]),
+        )
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let d = self.max - self.min;
+        2.0 * (d[0] * d[1] + d[1] * d[2] + d[2] * d[0])
+    }
+
+    pub fn longest_axis(&self) -> usize {
+        let d = self.max - self.min;
+        if d[0] >= d[1] && d[0] >= d[2] {
+            0
+        } else if d[1] >= d[2] {
+            1
+        } else {
+            2
+        }
+    }
+
     pub fn surrounding_box(box1: BBox, box2: BBox) -> BBox {
         let min = Point3::new(
             box1.min[0].min(box2.min[0]),
@@ -41,3 +118,19 @@ impl BBox {
         BBox { min, max }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::ray;
+    use crate::vec3::Vec3;
+
+    use super::*;
+
+    #[test]
+    fn flat_boxes_are_hit_through_their_face() {
+        let flat = BBox { min: Point3::new(0.0, 0.0, 0.0), max: Point3::new(1.0, 1.0, 0.0) };
+        assert!(flat.hit(&ray(Point3::new(0.5, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f64::INFINITY));
+        assert!(!flat.hit(&ray(Point3::new(1.5, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f64::INFINITY));
+        assert!(!flat.hit(&ray(Point3::new(0.5, 0.5, 1.0), Vec3::new(0.0, 0.0, 1.0)), 0.001, f64::INFINITY));
+    }
+}
@@ -2,39 +2,216 @@ use std::cmp::Ordering;
 use std::fmt;
 use std::fmt::Debug;
 
-use rand::{Rng, RngCore};
+use rand::{Rng, RngCore, SeedableRng};
+use rand::rngs::SmallRng;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use crate::bounding_box::BBox;
 use crate::geometry::{HitRecord, Hittable};
+use crate::point3::Point3;
 use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+/// Cost of traversing an internal node relative to intersecting a primitive.
+const TRAVERSAL_COST: f64 = 0.125;
+
+/// Largest number of internal nodes from the root to a leaf, which bounds the size of the
+/// traversal stacks. Builders turn nodes this deep into leaves, whatever their size.
+pub const MAX_DEPTH: usize = 64;
+
+/// Number of shapes above which the two children of a node are built on separate threads.
+const PARALLEL_BUILD_THRESHOLD: usize = 4096;
+
+/// Strategy used to split the shapes when building a `BVHNode`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum BVHBuilder {
+    /// Sorts along a random axis and splits at the median.
+    Median,
+    /// Surface area heuristic evaluated at the boundaries of `buckets` equal bins of the
+    /// centroid bounds. Nodes with at most `max_leaf_size` shapes may become leaves.
+    Sah {
+        #[serde(default = "default_buckets")]
+        buckets: usize,
+        #[serde(default = "default_max_leaf_size")]
+        max_leaf_size: usize,
+    },
+}
+
+pub const DEFAULT_SAH_BUCKETS: usize = 12;
+pub const DEFAULT_SAH_MAX_LEAF_SIZE: usize = 4;
+
+fn default_buckets() -> usize {
+    DEFAULT_SAH_BUCKETS
+}
+
+fn default_max_leaf_size() -> usize {
+    DEFAULT_SAH_MAX_LEAF_SIZE
+}
+
+impl Default for BVHBuilder {
+    fn default() -> Self {
+        BVHBuilder::Sah { buckets: default_buckets(), max_leaf_size: default_max_leaf_size() }
+    }
+}
 
 pub enum BVHNode<T: Hittable> {
     Internal {
         bbox: BBox,
+        /// Axis the shapes were split along, `left` mostly holding the lower coordinates.
+        axis: usize,
         left: Box<BVHNode<T>>,
         right: Box<BVHNode<T>>,
     },
     Leaf {
-        hittable: T,
+        hittables: Vec<T>,
     },
 }
 
-impl<T: Hittable + Clone> BVHNode<T> {
+/// Index of a shape with its bounds cached for the duration of the SAH build. Partitioning
+/// moves these rather than the shapes themselves, which can be much larger.
+struct BuildItem {
+    index: usize,
+    bbox: BBox,
+    centroid: Point3,
+}
+
+impl<T: Hittable + Clone + Send + Sync> BVHNode<T> {
+    pub fn build(rng: &mut dyn RngCore, builder: BVHBuilder, shapes: &mut [T]) -> BVHNode<T> {
+        match builder {
+            BVHBuilder::Median => BVHNode::from_shapes(rng, shapes),
+            BVHBuilder::Sah { buckets, max_leaf_size } => BVHNode::from_shapes_sah(shapes, buckets, max_leaf_size),
+        }
+    }
+
     pub fn from_shapes(rng: &mut dyn RngCore, shapes: &mut [T]) -> BVHNode<T> {
-        if shapes.len() == 1 {
-            return BVHNode::Leaf { hittable: shapes[0].clone() };
+        BVHNode::build_median(&mut SmallRng::from_rng(rng).unwrap(), shapes, 0)
+    }
+
+    fn build_median(rng: &mut SmallRng, shapes: &mut [T], depth: usize) -> BVHNode<T> {
+        if shapes.len() <= 1 || depth == MAX_DEPTH {
+            return BVHNode::Leaf { hittables: shapes.to_vec() };
         }
 
+        // Only the median has to be in place, the shapes on each side can stay unordered.
         let axis = rng.gen_range(0..3);
-        shapes.sort_by(|s1, s2| BVHNode::compare(axis, s1, s2));
-
-        let (left_slice, right_slice) = shapes.split_at_mut(shapes.len() / 2);
+        let mid = shapes.len() / 2;
+        shapes.select_nth_unstable_by(mid, |s1, s2| BVHNode::compare(axis, s1, s2));
 
-        let left = BVHNode::from_shapes(rng, left_slice);
-        let right = BVHNode::from_shapes(rng, right_slice);
+        let parallel = shapes.len() > PARALLEL_BUILD_THRESHOLD;
+        let mut right_rng = SmallRng::from_rng(&mut *rng).unwrap();
+        let (left_slice, right_slice) = shapes.split_at_mut(mid);
+        let (left, right) = join(
+            parallel,
+            || BVHNode::build_median(rng, left_slice, depth + 1),
+            || BVHNode::build_median(&mut right_rng, right_slice, depth + 1),
+        );
         let bbox = BBox::surrounding_box(left.bounding_box(), right.bounding_box());
 
-        BVHNode::Internal { bbox, left: Box::new(left), right: Box::new(right) }
+        BVHNode::Internal { bbox, axis, left: Box::new(left), right: Box::new(right) }
+    }
+
+    pub fn from_shapes_sah(shapes: &[T], buckets: usize, max_leaf_size: usize) -> BVHNode<T> {
+        let mut items = shapes.par_iter()
+            .enumerate()
+            .map(|(index, shape)| {
+                let bbox = shape.bounding_box();
+                BuildItem { index, bbox, centroid: bbox.centroid() }
+            })
+            .collect::<Vec<_>>();
+        BVHNode::build_sah(shapes, &mut items, buckets.max(2), max_leaf_size.max(1), 0)
+    }
+
+    fn build_sah(shapes: &[T], items: &mut [BuildItem], buckets: usize, max_leaf_size: usize, depth: usize) -> BVHNode<T> {
+        if items.len() <= 1 || depth == MAX_DEPTH {
+            return BVHNode::leaf(shapes, items);
+        }
+
+        let bbox = items.iter().skip(1)
+            .fold(items[0].bbox, |bbox, item| BBox::surrounding_box(bbox, item.bbox));
+        let centroid_bounds = items.iter().skip(1)
+            .fold(BBox { min: items[0].centroid, max: items[0].centroid }, |bounds, item| {
+                BBox::surrounding_box(bounds, BBox { min: item.centroid, max: item.centroid })
+            });
+        let axis = centroid_bounds.longest_axis();
+        let min = centroid_bounds.min[axis];
+        let extent = centroid_bounds.max[axis] - min;
+
+        let split = if extent > 0.0 {
+            let bucket_of = |item: &BuildItem| {
+                // NaN centroids of unbounded shapes end up in the first bucket.
+                (((item.centroid[axis] - min) / extent * buckets as f64) as usize).min(buckets - 1)
+            };
+
+            let mut counts = vec![0usize; buckets];
+            let mut bounds: Vec<Option<BBox>> = vec![None; buckets];
+            for item in items.iter() {
+                let b = bucket_of(item);
+                counts[b] += 1;
+                bounds[b] = Some(bounds[b].map_or(item.bbox, |bbox| BBox::surrounding_box(bbox, item.bbox)));
+            }
+
+            // Sweep from the right to get the cost of every right-hand side, then from the left.
+            let mut right_costs = vec![0.0; buckets];
+            let mut right_bbox: Option<BBox> = None;
+            let mut right_count = 0;
+            for b in (1..buckets).rev() {
+                right_count += counts[b];
+                right_bbox = merge(right_bbox, bounds[b]);
+                right_costs[b] = right_bbox.map_or(0.0, |bbox| bbox.surface_area() * right_count as f64);
+            }
+
+            let mut best: Option<(usize, f64)> = None;
+            let mut left_bbox: Option<BBox> = None;
+            let mut left_count = 0;
+            for b in 0..buckets - 1 {
+                left_count += counts[b];
+                left_bbox = merge(left_bbox, bounds[b]);
+                if left_count == 0 || left_count == items.len() {
+                    continue;
+                }
+
+                let cost = left_bbox.map_or(0.0, |bbox| bbox.surface_area() * left_count as f64) + right_costs[b + 1];
+                let is_better = match best {
+                    Some((_, best_cost)) => cost < best_cost,
+                    None => true
+                };
+                if is_better {
+                    best = Some((b, cost));
+                }
+            }
+
+            let leaf_cost = items.len() as f64 * bbox.surface_area();
+            match best {
+                Some((_, cost)) if items.len() <= max_leaf_size
+                    && leaf_cost <= TRAVERSAL_COST * bbox.surface_area() + cost => None,
+                Some((b, _)) => Some(partition(items, |item| bucket_of(item) <= b)),
+                None => None
+            }
+        } else {
+            None
+        };
+
+        let mid = match split {
+            Some(mid) => mid,
+            None if items.len() <= max_leaf_size => return BVHNode::leaf(shapes, items),
+            // Every centroid falls in the same bucket, so any split is as good as another.
+            None => items.len() / 2
+        };
+
+        let parallel = items.len() > PARALLEL_BUILD_THRESHOLD;
+        let (left_items, right_items) = items.split_at_mut(mid);
+        let (left, right) = join(
+            parallel,
+            || BVHNode::build_sah(shapes, left_items, buckets, max_leaf_size, depth + 1),
+            || BVHNode::build_sah(shapes, right_items, buckets, max_leaf_size, depth + 1),
+        );
+        BVHNode::Internal { bbox, axis, left: Box::new(left), right: Box::new(right) }
+    }
+
+    fn leaf(shapes: &[T], items: &[BuildItem]) -> BVHNode<T> {
+        BVHNode::Leaf { hittables: items.iter().map(|item| shapes[item.index].clone()).collect() }
     }
 
     fn compare(axis: usize, shape1: &T, shape2: &T) -> Ordering {
@@ -47,40 +224,490 @@ impl<T: Hittable + Clone> BVHNode<T> {
 impl<T: Hittable + Debug> Debug for BVHNode<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            BVHNode::Internal { bbox, left, right } => {
+            BVHNode::Internal { bbox, axis, left, right } => {
                 f.debug_struct("BVHNode")
                     .field("bbox", bbox)
+                    .field("axis", axis)
                     .field("left", left)
                     .field("right", right)
                     .finish()
             }
-            BVHNode::Leaf { hittable: shape } => shape.fmt(f)
+            BVHNode::Leaf { hittables } => f.debug_list().entries(hittables).finish()
         }
     }
 }
 
 impl<T: Hittable> Hittable for BVHNode<T> {
-    fn hit_by(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn hit_by(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
         match self {
-            BVHNode::Leaf { hittable } => hittable.hit_by(ray, t_min, t_max),
+            BVHNode::Leaf { hittables } => hittables.hit_by(rng, ray, t_min, t_max),
 
-            BVHNode::Internal { bbox, left, right } => {
+            BVHNode::Internal { bbox, left, right, .. } => {
                 if !bbox.hit(ray, t_min, t_max) {
                     None
                 } else {
-                    let hit_left = left.hit_by(ray, t_min, t_max);
+                    let hit_left = left.hit_by(rng, ray, t_min, t_max);
                     let t_max1 = if let Some(ref rec) = hit_left { rec.t } else { t_max };
-                    let hit_right = right.hit_by(ray, t_min, t_max1);
+                    let hit_right = right.hit_by(rng, ray, t_min, t_max1);
                     hit_right.or(hit_left)
                 }
             }
         }
     }
 
+    fn transmittance(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> f64 {
+        match self {
+            BVHNode::Leaf { hittables } => hittables.transmittance(rng, ray, t_min, t_max),
+            BVHNode::Internal { bbox, .. } if !bbox.hit(ray, t_min, t_max) => 1.0,
+            BVHNode::Internal { left, right, .. } => {
+                let transmittance = left.transmittance(rng, ray, t_min, t_max);
+                if transmittance <= 0.0 {
+                    return 0.0;
+                }
+                transmittance * right.transmittance(rng, ray, t_min, t_max)
+            }
+        }
+    }
+
     fn bounding_box(&self) -> BBox {
         match self {
             BVHNode::Internal { bbox, .. } => *bbox,
-            BVHNode::Leaf { hittable: shape } => shape.bounding_box()
+            BVHNode::Leaf { hittables } => hittables.bounding_box()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LinearNode {
+    pub bbox: BBox,
+    /// First primitive of a leaf, or second child of an internal node. The first child of
+    /// an internal node immediately follows it.
+    pub offset: u32,
+    /// Number of primitives, zero for internal nodes.
+    pub count: u32,
+    pub axis: u8,
+}
+
+/// `BVHNode` laid out depth-first in a single `Vec`, with the primitives of every leaf
+/// stored contiguously. Traversal is iterative and visits the child nearer to the ray origin
+/// first, so that far subtrees are culled by the closest hit found so far.
+pub struct FlatBVH<T: Hittable> {
+    nodes: Vec<LinearNode>,
+    primitives: Vec<T>,
+}
+
+impl<T: Hittable> FlatBVH<T> {
+    pub fn new(root: BVHNode<T>) -> FlatBVH<T> {
+        let mut bvh = FlatBVH { nodes: vec![], primitives: vec![] };
+        bvh.flatten(root, 0);
+        bvh
+    }
+
+    fn flatten(&mut self, node: BVHNode<T>, depth: usize) -> usize {
+        assert!(depth <= MAX_DEPTH, "BVH deeper than {} levels", MAX_DEPTH);
+        let index = self.nodes.len();
+        let bbox = node.bounding_box();
+        match node {
+            BVHNode::Leaf { hittables } => {
+                self.nodes.push(LinearNode {
+                    bbox,
+                    offset: self.primitives.len() as u32,
+                    count: hittables.len() as u32,
+                    axis: 0,
+                });
+                self.primitives.extend(hittables);
+            }
+            BVHNode::Internal { axis, left, right, .. } => {
+                self.nodes.push(LinearNode { bbox, offset: 0, count: 0, axis: axis as u8 });
+                self.flatten(*left, depth + 1);
+                let second = self.flatten(*right, depth + 1);
+                self.nodes[index].offset = second as u32;
+            }
+        }
+        index
+    }
+
+    /// Reassembles a BVH from nodes laid out like the ones returned by `nodes`.
+    pub fn from_parts(nodes: Vec<LinearNode>, primitives: Vec<T>) -> FlatBVH<T> {
+        FlatBVH { nodes, primitives }
+    }
+
+    pub fn nodes(&self) -> &[LinearNode] {
+        &self.nodes
+    }
+
+    pub fn primitives(&self) -> &[T] {
+        &self.primitives
+    }
+
+    /// Same tree over the primitives converted by `f`, which must keep their bounds.
+    pub fn map<U: Hittable>(self, f: impl FnMut(T) -> U) -> FlatBVH<U> {
+        FlatBVH { nodes: self.nodes, primitives: self.primitives.into_iter().map(f).collect() }
+    }
+}
+
+impl<T: Hittable> FlatBVH<T> {
+    /// Number of nodes whose bounding box is tested when looking for the closest hit of `ray`.
+    pub fn nodes_visited(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> usize {
+        let mut visited = 0;
+        self.traverse(rng, ray, t_min, t_max, &mut visited);
+        visited
+    }
+
+    fn traverse(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, mut t_max: f64, visited: &mut usize) -> Option<HitRecord<'_>> {
+        let inv_dir = Vec3::new(1.0 / ray.dir[0], 1.0 / ray.dir[1], 1.0 / ray.dir[2]);
+        let dir_is_neg = [inv_dir[0] < 0.0, inv_dir[1] < 0.0, inv_dir[2] < 0.0];
+
+        let mut closest = None;
+        let mut stack = [0usize; MAX_DEPTH + 1];
+        let mut stack_size = 0;
+        let mut current = 0;
+        loop {
+            let node = &self.nodes[current];
+            *visited += 1;
+            if node.bbox.hit_inverse(ray.orig, inv_dir, t_min, t_max) {
+                if node.count > 0 {
+                    let start = node.offset as usize;
+                    for primitive in &self.primitives[start..start + node.count as usize] {
+                        if let Some(rec) = primitive.hit_by(rng, ray, t_min, t_max) {
+                            t_max = rec.t;
+                            closest = Some(rec);
+                        }
+                    }
+                } else if dir_is_neg[node.axis as usize] {
+                    stack[stack_size] = current + 1;
+                    stack_size += 1;
+                    current = node.offset as usize;
+                    continue;
+                } else {
+                    stack[stack_size] = node.offset as usize;
+                    stack_size += 1;
+                    current += 1;
+                    continue;
+                }
+            }
+
+            if stack_size == 0 {
+                break;
+            }
+            stack_size -= 1;
+            current = stack[stack_size];
+        }
+
+        closest
+    }
+}
+
+impl<T: Hittable> Hittable for FlatBVH<T> {
+    fn hit_by(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        self.traverse(rng, ray, t_min, t_max, &mut 0)
+    }
+
+    fn bounding_box(&self) -> BBox {
+        self.nodes.first().map_or(BBox::empty(), |root| root.bbox)
+    }
+
+    fn transmittance(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> f64 {
+        let inv_dir = Vec3::new(1.0 / ray.dir[0], 1.0 / ray.dir[1], 1.0 / ray.dir[2]);
+        let mut transmittance = 1.0;
+        let mut stack = [0usize; MAX_DEPTH + 1];
+        let mut stack_size = 1;
+        while stack_size > 0 {
+            stack_size -= 1;
+            let current = stack[stack_size];
+            let node = &self.nodes[current];
+            if !node.bbox.hit_inverse(ray.orig, inv_dir, t_min, t_max) {
+                continue;
+            }
+
+            // Every shape along the segment counts, so the order of the children doesn't matter.
+            if node.count == 0 {
+                stack[stack_size] = current + 1;
+                stack[stack_size + 1] = node.offset as usize;
+                stack_size += 2;
+                continue;
+            }
+
+            let start = node.offset as usize;
+            for primitive in &self.primitives[start..start + node.count as usize] {
+                transmittance *= primitive.transmittance(rng, ray, t_min, t_max);
+                if transmittance <= 0.0 {
+                    return 0.0;
+                }
+            }
+        }
+        transmittance
+    }
+}
+
+/// `FlatBVH` over the bounded shapes, with the unbounded ones such as planes kept in a list
+/// that every ray is tested against. In the tree, their infinite box would spread to all
+/// their ancestors, which could then never be culled.
+pub struct SceneBVH<T: Hittable> {
+    bvh: Option<FlatBVH<T>>,
+    unbounded: Vec<T>,
+}
+
+impl<T: Hittable + Clone + Send + Sync> SceneBVH<T> {
+    pub fn build(rng: &mut dyn RngCore, builder: BVHBuilder, shapes: &[T]) -> SceneBVH<T> {
+        let (mut bounded, unbounded): (Vec<T>, Vec<T>) = shapes.iter().cloned().partition(|s| s.is_bounded());
+        let bvh = if bounded.is_empty() {
+            None
+        } else {
+            Some(FlatBVH::new(BVHNode::build(rng, builder, bounded.as_mut_slice())))
+        };
+        SceneBVH { bvh, unbounded }
+    }
+}
+
+impl<T: Hittable> SceneBVH<T> {
+    /// Number of BVH nodes tested when looking for the closest hit of `ray`.
+    pub fn nodes_visited(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> usize {
+        let t_max = self.unbounded.hit_by(rng, ray, t_min, t_max).map_or(t_max, |rec| rec.t);
+        self.bvh.as_ref().map_or(0, |bvh| bvh.nodes_visited(rng, ray, t_min, t_max))
+    }
+}
+
+impl<T: Hittable> Hittable for SceneBVH<T> {
+    fn hit_by(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        // Hitting the unbounded shapes first lets the BVH cull whatever lies behind them.
+        let unbounded_hit = self.unbounded.hit_by(rng, ray, t_min, t_max);
+        let t_max = unbounded_hit.as_ref().map_or(t_max, |rec| rec.t);
+        match self.bvh.as_ref().and_then(|bvh| bvh.hit_by(rng, ray, t_min, t_max)) {
+            Some(rec) => Some(rec),
+            None => unbounded_hit
+        }
+    }
+
+    fn bounding_box(&self) -> BBox {
+        match &self.bvh {
+            Some(_) if !self.unbounded.is_empty() => BBox::infinite(),
+            Some(bvh) => bvh.bounding_box(),
+            None => self.unbounded.bounding_box()
+        }
+    }
+
+    fn is_bounded(&self) -> bool {
+        self.unbounded.is_empty()
+    }
+
+    fn transmittance(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> f64 {
+        let transmittance = self.unbounded.transmittance(rng, ray, t_min, t_max);
+        match &self.bvh {
+            Some(bvh) if transmittance > 0.0 => transmittance * bvh.transmittance(rng, ray, t_min, t_max),
+            _ => transmittance
+        }
+    }
+}
+
+/// Runs both closures, on separate threads if `parallel` is set.
+fn join<A, B, RA, RB>(parallel: bool, a: A, b: B) -> (RA, RB)
+    where A: FnOnce() -> RA + Send, B: FnOnce() -> RB + Send, RA: Send, RB: Send
+{
+    if parallel {
+        rayon::join(a, b)
+    } else {
+        (a(), b())
+    }
+}
+
+fn merge(bbox: Option<BBox>, other: Option<BBox>) -> Option<BBox> {
+    match (bbox, other) {
+        (Some(bbox), Some(other)) => Some(BBox::surrounding_box(bbox, other)),
+        (bbox, other) => bbox.or(other)
+    }
+}
+
+/// Moves the items matching `pred` to the front and returns how many there are.
+fn partition<T>(items: &mut [T], pred: impl Fn(&T) -> bool) -> usize {
+    let mut mid = 0;
+    for i in 0..items.len() {
+        if pred(&items[i]) {
+            items.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    use crate::geometry::{ArcHittable, Sphere};
+    use crate::material::Material;
+    use crate::test_util::ray;
+    use crate::vec3::Vec3;
+
+    use super::*;
+
+    fn sphere(center: Point3, radius: f64) -> ArcHittable {
+        Arc::new(Sphere { center, radius, material: Material::BlackBody })
+    }
+
+    fn random_spheres(rng: &mut SmallRng, count: usize) -> Vec<ArcHittable> {
+        (0..count)
+            .map(|_| {
+                let center = Point3::new(rng.gen_range(-10.0..10.0), rng.gen_range(-10.0..10.0), rng.gen_range(-10.0..10.0));
+                sphere(center, rng.gen_range(0.05..1.0))
+            })
+            .collect()
+    }
+
+    fn random_rays(rng: &mut SmallRng, count: usize) -> Vec<Ray> {
+        (0..count)
+            .map(|_| {
+                let orig = Point3::new(rng.gen_range(-12.0..12.0), rng.gen_range(-12.0..12.0), rng.gen_range(-12.0..12.0));
+                ray(orig, Vec3::random_unit_vector(rng))
+            })
+            .collect()
+    }
+
+    /// Checks that `bvh` finds the same closest hit as testing every shape.
+    fn assert_brute_force_hits(rng: &mut SmallRng, bvh: &impl Hittable, shapes: &[ArcHittable], rays: &[Ray]) {
+        for ray in rays {
+            let expected = shapes.iter()
+                .filter_map(|shape| shape.hit_by(rng, ray, 0.001, f64::INFINITY))
+                .map(|rec| rec.t)
+                .fold(None, |closest: Option<f64>, t| Some(closest.map_or(t, |closest| closest.min(t))));
+            let actual = bvh.hit_by(rng, ray, 0.001, f64::INFINITY).map(|rec| rec.t);
+            assert_eq!(actual, expected, "closest hit of {:?} {:?}", ray.orig, ray.dir);
+        }
+    }
+
+    fn depth<T: Hittable>(node: &BVHNode<T>) -> usize {
+        match node {
+            BVHNode::Internal { left, right, .. } => 1 + depth(left).max(depth(right)),
+            BVHNode::Leaf { .. } => 0
+        }
+    }
+
+    #[test]
+    fn median_bvh_matches_brute_force() {
+        let mut rng = SmallRng::seed_from_u64(13);
+        let shapes = random_spheres(&mut rng, 500);
+        let rays = random_rays(&mut rng, 2000);
+        let bvh = BVHNode::from_shapes(&mut rng, &mut shapes.clone());
+        assert_brute_force_hits(&mut rng, &bvh, &shapes, &rays);
+    }
+
+    #[test]
+    fn sah_bvh_matches_brute_force() {
+        let mut rng = SmallRng::seed_from_u64(13);
+        let shapes = random_spheres(&mut rng, 500);
+        let rays = random_rays(&mut rng, 2000);
+        for &(buckets, max_leaf_size) in [(2, 1), (DEFAULT_SAH_BUCKETS, DEFAULT_SAH_MAX_LEAF_SIZE), (32, 16)].iter() {
+            let bvh = BVHNode::from_shapes_sah(&shapes, buckets, max_leaf_size);
+            assert_brute_force_hits(&mut rng, &bvh, &shapes, &rays);
+        }
+    }
+
+    #[test]
+    fn sah_bvh_splits_identical_centroids() {
+        let shapes = (0..20).map(|i| sphere(Point3::new(0.0, 0.0, 0.0), 0.1 + 0.1 * i as f64)).collect::<Vec<_>>();
+        let mut rng = SmallRng::seed_from_u64(13);
+        let rays = random_rays(&mut rng, 200);
+        let bvh = BVHNode::from_shapes_sah(&shapes, DEFAULT_SAH_BUCKETS, 2);
+        assert!(depth(&bvh) > 0);
+        assert_brute_force_hits(&mut rng, &bvh, &shapes, &rays);
+    }
+
+    #[test]
+    fn empty_bvhs_are_never_hit() {
+        let rng = &mut SmallRng::seed_from_u64(13);
+        let rays = random_rays(rng, 10);
+        for &builder in [BVHBuilder::Median, BVHBuilder::default()].iter() {
+            let bvh = BVHNode::<ArcHittable>::build(rng, builder, &mut []);
+            let bbox = bvh.bounding_box();
+            assert!(rays.iter().all(|ray| !bbox.hit(ray, 0.001, f64::INFINITY)));
+            assert_brute_force_hits(rng, &bvh, &[], &rays);
+        }
+    }
+
+    #[test]
+    fn flat_bvh_matches_brute_force() {
+        let mut rng = SmallRng::seed_from_u64(14);
+        let mut shapes = random_spheres(&mut rng, 500);
+        let rays = random_rays(&mut rng, 2000);
+        for &builder in [BVHBuilder::Median, BVHBuilder::default()].iter() {
+            let bvh = FlatBVH::new(BVHNode::build(&mut rng, builder, shapes.as_mut_slice()));
+            assert_brute_force_hits(&mut rng, &bvh, &shapes, &rays);
+        }
+    }
+
+    #[test]
+    fn lopsided_trees_are_capped() {
+        // Every SAH split cuts off the farthest sphere, for one level per sphere.
+        let shapes = (0..200).map(|i| sphere(Point3::new(4f64.powi(i), 0.0, 0.0), 0.1)).collect::<Vec<_>>();
+        let root = BVHNode::from_shapes_sah(&shapes, DEFAULT_SAH_BUCKETS, 1);
+        assert_eq!(depth(&root), MAX_DEPTH);
+
+        let mut rng = SmallRng::seed_from_u64(14);
+        let rays = (0..200)
+            .map(|i| ray(Point3::new(4f64.powi(i), 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0)))
+            .collect::<Vec<_>>();
+        assert_brute_force_hits(&mut rng, &FlatBVH::new(root), &shapes, &rays);
+    }
+
+    #[test]
+    fn empty_flat_bvhs_are_never_hit() {
+        let mut rng = SmallRng::seed_from_u64(14);
+        let rays = random_rays(&mut rng, 10);
+        let bvh = FlatBVH::new(BVHNode::<ArcHittable>::from_shapes_sah(&[], DEFAULT_SAH_BUCKETS, 1));
+        let bbox = bvh.bounding_box();
+        assert!(rays.iter().all(|ray| !bbox.hit(ray, 0.001, f64::INFINITY)));
+        assert_brute_force_hits(&mut rng, &bvh, &[], &rays);
+    }
+
+    fn leaf_boxes<T: Hittable>(node: &BVHNode<T>, boxes: &mut Vec<Point3>) {
+        match node {
+            BVHNode::Internal { left, right, .. } => {
+                leaf_boxes(left, boxes);
+                leaf_boxes(right, boxes);
+            }
+            BVHNode::Leaf { hittables } => boxes.extend(hittables.iter().map(|h| h.bounding_box().min))
+        }
+    }
+
+    #[test]
+    fn bvhs_built_in_parallel_match_brute_force() {
+        let mut rng = SmallRng::seed_from_u64(15);
+        let mut shapes = random_spheres(&mut rng, 3 * PARALLEL_BUILD_THRESHOLD);
+        let rays = random_rays(&mut rng, 200);
+        for &builder in [BVHBuilder::Median, BVHBuilder::default()].iter() {
+            let bvh = FlatBVH::new(BVHNode::build(&mut rng, builder, shapes.as_mut_slice()));
+            assert_eq!(bvh.primitives().len(), shapes.len());
+            assert_brute_force_hits(&mut rng, &bvh, &shapes, &rays);
         }
     }
+
+    #[test]
+    fn parallel_median_builds_only_depend_on_the_seed() {
+        let shapes = random_spheres(&mut SmallRng::seed_from_u64(15), 3 * PARALLEL_BUILD_THRESHOLD);
+        let build = || {
+            let bvh = BVHNode::from_shapes(&mut SmallRng::seed_from_u64(16), &mut shapes.clone());
+            let mut boxes = vec![];
+            leaf_boxes(&bvh, &mut boxes);
+            boxes
+        };
+        let first = build();
+        assert_eq!(first.len(), shapes.len());
+        assert!(first.iter().zip(build().iter()).all(|(a, b)| (*a - *b).length() == 0.0));
+    }
+
+    #[test]
+    fn flat_bvh_counts_visited_nodes() {
+        let mut rng = SmallRng::seed_from_u64(14);
+        let mut shapes = random_spheres(&mut rng, 100);
+        let bvh = FlatBVH::new(BVHNode::build(&mut rng, BVHBuilder::default(), shapes.as_mut_slice()));
+        let away = ray(Point3::new(0.0, 50.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(bvh.nodes_visited(&mut rng, &away, 0.001, f64::INFINITY), 1);
+
+        let through = ray(Point3::new(-50.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let visited = bvh.nodes_visited(&mut rng, &through, 0.001, f64::INFINITY);
+        assert!(visited > 1 && visited < 2 * shapes.len());
+    }
 }
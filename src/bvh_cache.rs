@@ -0,0 +1,337 @@
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use rand::{RngCore, thread_rng};
+
+use crate::bounding_box::BBox;
+use crate::bvh::{BVHBuilder, BVHNode, FlatBVH, LinearNode, MAX_DEPTH};
+use crate::geometry::{HitRecord, Hittable, Triangle};
+use crate::point3::Point3;
+use crate::ray::Ray;
+
+const MAGIC: &[u8; 8] = b"RTBVH\0\0\0";
+const VERSION: u32 = 1;
+
+/// What to do with the BVH cache files stored next to OBJ meshes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BVHCache {
+    /// Loads the cache when it matches the mesh and builder, and writes it otherwise.
+    Enabled,
+    /// Always builds the BVH and overwrites the cache.
+    Rebuild,
+    /// Neither reads nor writes the cache.
+    Disabled,
+}
+
+/// Builds the BVH of the triangles read from `obj_filename`, or loads it from
+/// `<obj_filename>.bvh`. The cache only holds the tree layout and the order of the triangles,
+/// so it stays valid as long as the OBJ file and the builder settings don't change. Failing to
+/// write the cache only adds to `warnings`.
+pub fn mesh_bvh(
+    obj_filename: &str,
+    triangles: Vec<Triangle>,
+    builder: BVHBuilder,
+    cache: BVHCache,
+    warnings: &mut Vec<String>,
+) -> FlatBVH<Triangle> {
+    if cache == BVHCache::Disabled {
+        return build(triangles, builder).0;
+    }
+
+    let path = cache_path(obj_filename);
+    let key = match fs::read(obj_filename) {
+        Ok(contents) => CacheKey { file_hash: fnv1a(&contents), builder, triangle_count: triangles.len() },
+        Err(_) => return build(triangles, builder).0
+    };
+
+    if cache == BVHCache::Enabled {
+        if let Ok((nodes, order)) = read_cache(&path, &key) {
+            let primitives = order.iter().map(|&index| triangles[index as usize].clone()).collect();
+            return FlatBVH::from_parts(nodes, primitives);
+        }
+    }
+
+    let (bvh, order) = build(triangles, builder);
+    if let Err(e) = write_cache(&path, &key, bvh.nodes(), &order) {
+        warnings.push(format!("could not write BVH cache {}: {}", path.display(), e));
+    }
+    bvh
+}
+
+fn cache_path(obj_filename: &str) -> PathBuf {
+    let mut path = PathBuf::from(obj_filename).into_os_string();
+    path.push(".bvh");
+    PathBuf::from(path)
+}
+
+/// Builds the BVH, also returning the index in `triangles` of each of its primitives.
+fn build(triangles: Vec<Triangle>, builder: BVHBuilder) -> (FlatBVH<Triangle>, Vec<u32>) {
+    let mut indexed = triangles.into_iter()
+        .enumerate()
+        .map(|(index, triangle)| Indexed { index: index as u32, shape: triangle })
+        .collect::<Vec<_>>();
+    let bvh = FlatBVH::new(BVHNode::build(&mut thread_rng(), builder, indexed.as_mut_slice()));
+    let order = bvh.primitives().iter().map(|primitive| primitive.index).collect();
+    (bvh.map(|primitive| primitive.shape), order)
+}
+
+/// A shape tagged with its position in the list the BVH is built from.
+#[derive(Clone)]
+struct Indexed<T> {
+    index: u32,
+    shape: T,
+}
+
+impl<T: Hittable> Hittable for Indexed<T> {
+    fn hit_by(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        self.shape.hit_by(rng, ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self) -> BBox {
+        self.shape.bounding_box()
+    }
+}
+
+struct CacheKey {
+    file_hash: u64,
+    builder: BVHBuilder,
+    triangle_count: usize,
+}
+
+impl CacheKey {
+    fn to_bytes(&self) -> Vec<u8> {
+        let (kind, buckets, max_leaf_size) = match self.builder {
+            BVHBuilder::Median => (0u32, 0, 0),
+            BVHBuilder::Sah { buckets, max_leaf_size } => (1, buckets as u32, max_leaf_size as u32),
+        };
+
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&self.file_hash.to_le_bytes());
+        bytes.extend_from_slice(&kind.to_le_bytes());
+        bytes.extend_from_slice(&buckets.to_le_bytes());
+        bytes.extend_from_slice(&max_leaf_size.to_le_bytes());
+        bytes.extend_from_slice(&(self.triangle_count as u32).to_le_bytes());
+        bytes
+    }
+}
+
+/// 64-bit FNV-1a hash.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+fn write_cache(path: &Path, key: &CacheKey, nodes: &[LinearNode], order: &[u32]) -> io::Result<()> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend(key.to_bytes());
+
+    bytes.extend_from_slice(&(nodes.len() as u32).to_le_bytes());
+    for node in nodes {
+        for i in 0..3 {
+            bytes.extend_from_slice(&node.bbox.min[i].to_le_bytes());
+        }
+        for i in 0..3 {
+            bytes.extend_from_slice(&node.bbox.max[i].to_le_bytes());
+        }
+        bytes.extend_from_slice(&node.offset.to_le_bytes());
+        bytes.extend_from_slice(&node.count.to_le_bytes());
+        bytes.push(node.axis);
+    }
+
+    bytes.extend_from_slice(&(order.len() as u32).to_le_bytes());
+    for index in order {
+        bytes.extend_from_slice(&index.to_le_bytes());
+    }
+
+    fs::write(path, bytes)
+}
+
+/// Reads the nodes and triangle order of a cache, failing if it doesn't match `key`, if the
+/// tree refers to nodes or triangles that don't exist, or if it's too deep to traverse.
+fn read_cache(path: &Path, key: &CacheKey) -> io::Result<(Vec<LinearNode>, Vec<u32>)> {
+    let contents = fs::read(path)?;
+    let mut input = contents.as_slice();
+
+    let mut header = [0u8; 8];
+    input.read_exact(&mut header)?;
+    if &header != MAGIC || read_u32(&mut input)? != VERSION {
+        return Err(invalid("not a BVH cache of this version"));
+    }
+
+    let mut stored_key = vec![0u8; key.to_bytes().len()];
+    input.read_exact(&mut stored_key)?;
+    if stored_key != key.to_bytes() {
+        return Err(invalid("cache is for another file or builder"));
+    }
+
+    let node_count = read_u32(&mut input)? as usize;
+    let mut nodes = Vec::with_capacity(node_count.min(input.len()));
+    for _ in 0..node_count {
+        let min = Point3::new(read_f64(&mut input)?, read_f64(&mut input)?, read_f64(&mut input)?);
+        let max = Point3::new(read_f64(&mut input)?, read_f64(&mut input)?, read_f64(&mut input)?);
+        let offset = read_u32(&mut input)?;
+        let count = read_u32(&mut input)?;
+        let mut axis = [0u8; 1];
+        input.read_exact(&mut axis)?;
+        nodes.push(LinearNode { bbox: BBox { min, max }, offset, count, axis: axis[0] });
+    }
+
+    let order_count = read_u32(&mut input)? as usize;
+    let mut order = Vec::with_capacity(order_count.min(input.len()));
+    for _ in 0..order_count {
+        order.push(read_u32(&mut input)?);
+    }
+
+    // Children must come after their parent, which also rules out cycles.
+    let valid_node = |(index, node): (usize, &LinearNode)| if node.count > 0 {
+        node.offset as usize + node.count as usize <= order.len()
+    } else {
+        (index + 2..nodes.len()).contains(&(node.offset as usize)) && node.axis < 3
+    };
+    if nodes.is_empty() || !nodes.iter().enumerate().all(valid_node)
+        || order.iter().any(|&index| index as usize >= key.triangle_count) {
+        return Err(invalid("corrupted BVH cache"));
+    }
+    if depth(&nodes) > MAX_DEPTH {
+        return Err(invalid("BVH cache is too deep"));
+    }
+
+    Ok((nodes, order))
+}
+
+/// Largest number of internal nodes from the root to a leaf, for nodes whose children all
+/// come after them.
+fn depth(nodes: &[LinearNode]) -> usize {
+    let mut depths = vec![0; nodes.len()];
+    for (index, node) in nodes.iter().enumerate() {
+        if node.count == 0 {
+            for child in [index + 1, node.offset as usize].iter() {
+                depths[*child] = depths[*child].max(depths[index] + 1);
+            }
+        }
+    }
+    depths.into_iter().max().unwrap_or(0)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_f64(input: &mut impl Read) -> io::Result<f64> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::material::Material;
+    use crate::test_util::temp_path;
+
+    use super::*;
+
+    /// Tree where every internal node has a leaf as its first child and the rest of the tree
+    /// as its second, `internal_count` levels deep.
+    fn chain(internal_count: usize) -> Vec<LinearNode> {
+        let bbox = BBox { min: Point3::new(0.0, 0.0, 0.0), max: Point3::new(1.0, 1.0, 1.0) };
+        let leaf = LinearNode { bbox, offset: 0, count: 1, axis: 0 };
+        let mut nodes = vec![];
+        for i in 0..internal_count {
+            nodes.push(LinearNode { bbox, offset: 2 * i as u32 + 2, count: 0, axis: 0 });
+            nodes.push(leaf);
+        }
+        nodes.push(leaf);
+        nodes
+    }
+
+    fn read_chain(name: &str, internal_count: usize) -> io::Result<(Vec<LinearNode>, Vec<u32>)> {
+        let path = temp_path(&format!("{}.bvh", name));
+        let key = CacheKey { file_hash: 1, builder: BVHBuilder::default(), triangle_count: 1 };
+        write_cache(&path, &key, &chain(internal_count), &[0]).unwrap();
+        let result = read_cache(&path, &key);
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn reads_written_cache() {
+        let (nodes, order) = read_chain("cache_round_trip", 3).unwrap();
+        assert_eq!(nodes.len(), 7);
+        assert_eq!(depth(&nodes), 3);
+        assert_eq!(order, vec![0]);
+    }
+
+    #[test]
+    fn rejects_caches_too_deep_to_traverse() {
+        assert!(read_chain("cache_max_depth", MAX_DEPTH).is_ok());
+        let error = read_chain("cache_too_deep", MAX_DEPTH + 1).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn random_triangles(count: usize) -> Vec<Triangle> {
+        let mut rng = SmallRng::seed_from_u64(17);
+        let mut point = || Point3::new(rng.gen_range(-5.0..5.0), rng.gen_range(-5.0..5.0), rng.gen_range(-5.0..5.0));
+        (0..count).map(|_| Triangle::new([point(), point(), point()], Material::BlackBody)).collect()
+    }
+
+    fn primitive_boxes(bvh: &FlatBVH<Triangle>) -> Vec<(f64, f64, f64)> {
+        bvh.primitives().iter()
+            .map(|triangle| {
+                let min = triangle.bounding_box().min;
+                (min[0], min[1], min[2])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mesh_bvhs_are_reloaded_from_the_cache() {
+        let directory = temp_path("mesh_cache");
+        fs::create_dir_all(&directory).unwrap();
+        let obj = directory.join("mesh.obj").to_str().unwrap().to_string();
+        fs::write(&obj, "# only hashed\n").unwrap();
+        let mut warnings = vec![];
+
+        // The median builder picks random axes, so only a cached tree comes out the same.
+        let built = mesh_bvh(&obj, random_triangles(200), BVHBuilder::Median, BVHCache::Enabled, &mut warnings);
+        assert!(cache_path(&obj).exists());
+        let loaded = mesh_bvh(&obj, random_triangles(200), BVHBuilder::Median, BVHCache::Enabled, &mut warnings);
+        assert_eq!(primitive_boxes(&loaded), primitive_boxes(&built));
+        assert_eq!(loaded.nodes().len(), built.nodes().len());
+
+        fs::write(cache_path(&obj), "garbage").unwrap();
+        let rebuilt = mesh_bvh(&obj, random_triangles(200), BVHBuilder::Median, BVHCache::Enabled, &mut warnings);
+        assert_eq!(rebuilt.primitives().len(), 200);
+        let key = CacheKey { file_hash: fnv1a(&fs::read(&obj).unwrap()), builder: BVHBuilder::Median, triangle_count: 200 };
+        assert!(read_cache(&cache_path(&obj), &key).is_ok());
+
+        fs::remove_dir_all(&directory).unwrap();
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn failing_to_write_the_cache_is_a_warning() {
+        let directory = temp_path("unwritable_cache");
+        fs::create_dir_all(&directory).unwrap();
+        let obj = directory.join("mesh.obj").to_str().unwrap().to_string();
+        fs::write(&obj, "# only hashed\n").unwrap();
+        fs::create_dir_all(cache_path(&obj)).unwrap();
+
+        let mut warnings = vec![];
+        let bvh = mesh_bvh(&obj, random_triangles(10), BVHBuilder::default(), BVHCache::Rebuild, &mut warnings);
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(bvh.primitives().len(), 10);
+        assert_eq!(warnings.len(), 1);
+    }
+}
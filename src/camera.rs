@@ -1,6 +1,6 @@
 use crate::vec3::Vec3;
 use crate::ray::Ray;
-use rand::RngCore;
+use rand::{Rng, RngCore};
 use crate::point3::Point3;
 
 pub struct Camera {
@@ -11,6 +11,8 @@ pub struct Camera {
     u: Vec3,
     v: Vec3,
     lens_radius: f64,
+    shutter_open: f64,
+    shutter_close: f64,
 }
 
 impl Camera {
@@ -47,9 +49,16 @@ impl Camera {
             u,
             v,
             lens_radius,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
         };
     }
 
+    /// Spreads the rays over the times from `open` to `close`, blurring moving objects.
+    pub fn with_shutter(self, open: f64, close: f64) -> Camera {
+        Camera { shutter_open: open, shutter_close: close, ..self }
+    }
+
     pub fn get_ray(&self, rng: &mut dyn RngCore, s: f64, t: f64) -> Ray {
         let rd = self.lens_radius * Vec3::random_in_unit_disk(rng);
         let offset = self.u * rd[0] + self.v * rd[1];
@@ -57,6 +66,7 @@ impl Camera {
         return Ray {
             orig,
             dir: self.lower_left_corner + s * self.horizontal + t * self.vertical - orig,
+            time: self.shutter_open + rng.gen::<f64>() * (self.shutter_close - self.shutter_open),
         };
     }
 }
@@ -33,6 +33,14 @@ impl Color {
     pub fn random(rng: &mut dyn RngCore) -> Color {
         return Color::new(rng.gen(), rng.gen(), rng.gen());
     }
+
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
+    pub fn is_black(&self) -> bool {
+        self.r == 0.0 && self.g == 0.0 && self.b == 0.0
+    }
 }
 
 impl_op_ex!(+ |lhs: &Color, rhs: &Color| -> Color { Color::new(lhs.r + rhs.r, lhs.g + rhs.g, lhs.b + rhs.b) });
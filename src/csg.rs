@@ -0,0 +1,196 @@
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::bounding_box::BBox;
+use crate::geometry::{ArcHittable, HitRecord, Hittable};
+use crate::point3::Point3;
+use crate::ray::Ray;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    /// The left shape minus the right one.
+    Difference,
+}
+
+impl CsgOperation {
+    fn contains(&self, in_left: bool, in_right: bool) -> bool {
+        match self {
+            CsgOperation::Union => in_left || in_right,
+            CsgOperation::Intersection => in_left && in_right,
+            CsgOperation::Difference => in_left && !in_right,
+        }
+    }
+}
+
+/// Combination of two closed shapes. Both are walked along the ray, entering or leaving them
+/// at each of their hits, and the surfaces where the ray enters or leaves the combination are
+/// kept. Whether the ray starts inside a shape is told by the side of its first hit.
+///
+/// CSG shapes aren't sampled as lights.
+pub struct Csg {
+    operation: CsgOperation,
+    left: ArcHittable,
+    right: ArcHittable,
+}
+
+impl Csg {
+    pub fn new(operation: CsgOperation, left: ArcHittable, right: ArcHittable) -> Csg {
+        Csg { operation, left, right }
+    }
+}
+
+impl Hittable for Csg {
+    fn hit_by(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        self.hit_all(rng, ray, t_min, t_max).into_iter().next()
+    }
+
+    fn hit_all(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Vec<HitRecord<'_>> {
+        // Hits past `t_max` are still needed to know whether the ray starts inside.
+        let left_hits = self.left.hit_all(rng, ray, t_min, f64::INFINITY);
+        let right_hits = self.right.hit_all(rng, ray, t_min, f64::INFINITY);
+        let mut in_left = match left_hits.first() {
+            Some(rec) => !rec.front_face,
+            None => false
+        };
+        let mut in_right = match right_hits.first() {
+            Some(rec) => !rec.front_face,
+            None => false
+        };
+        let mut inside = self.operation.contains(in_left, in_right);
+
+        let mut hits = vec![];
+        let (mut left_hits, mut right_hits) = (left_hits.into_iter().peekable(), right_hits.into_iter().peekable());
+        loop {
+            let from_left = match (left_hits.peek(), right_hits.peek()) {
+                (Some(left), Some(right)) => left.t <= right.t,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break
+            };
+            let mut rec = if from_left { left_hits.next() } else { right_hits.next() }.unwrap();
+            if rec.t >= t_max {
+                break;
+            }
+
+            if from_left {
+                in_left = rec.front_face;
+            } else {
+                in_right = rec.front_face;
+            }
+            let now_inside = self.operation.contains(in_left, in_right);
+            if now_inside != inside {
+                // Entering the subtracted shape is leaving the difference. The normal already
+                // faces the ray, only the side it came from changes.
+                rec.front_face = now_inside;
+                hits.push(rec);
+                inside = now_inside;
+            }
+        }
+        hits
+    }
+
+    fn bounding_box(&self) -> BBox {
+        let left = self.left.bounding_box();
+        let right = self.right.bounding_box();
+        match self.operation {
+            CsgOperation::Union => BBox::surrounding_box(left, right),
+            CsgOperation::Intersection => BBox {
+                min: Point3::new(left.min[0].max(right.min[0]), left.min[1].max(right.min[1]), left.min[2].max(right.min[2])),
+                max: Point3::new(left.max[0].min(right.max[0]), left.max[1].min(right.max[1]), left.max[2].min(right.max[2])),
+            },
+            CsgOperation::Difference => left,
+        }
+    }
+
+    fn is_bounded(&self) -> bool {
+        match self.operation {
+            CsgOperation::Union => self.left.is_bounded() && self.right.is_bounded(),
+            CsgOperation::Intersection => self.left.is_bounded() || self.right.is_bounded(),
+            CsgOperation::Difference => self.left.is_bounded(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::SeedableRng;
+    use rand::rngs::SmallRng;
+
+    use crate::geometry::Sphere;
+    use crate::material::Material;
+    use crate::test_util::ray;
+    use crate::vec3::Vec3;
+
+    use super::*;
+
+    fn sphere(x: f64, radius: f64) -> ArcHittable {
+        Arc::new(Sphere { center: Point3::new(x, 0.0, 0.0), radius, material: Material::BlackBody })
+    }
+
+    /// Unit spheres overlapping between x = -0.5 and x = 0.5.
+    fn lens(operation: CsgOperation) -> Csg {
+        Csg::new(operation, sphere(-0.5, 1.0), sphere(0.5, 1.0))
+    }
+
+    /// Distances and sides of the hits of a ray along the x axis from `x`.
+    fn hits(csg: &Csg, x: f64, t_max: f64) -> Vec<(f64, bool)> {
+        let rng = &mut SmallRng::seed_from_u64(22);
+        let ray = ray(Point3::new(x, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let hits = csg.hit_all(rng, &ray, 0.001, t_max).iter()
+            .map(|rec| ((rec.t * 1e9).round() / 1e9, rec.front_face))
+            .collect::<Vec<_>>();
+        assert_eq!(csg.hit_by(rng, &ray, 0.001, t_max).map(|rec| rec.t), hits.first().map(|hit| hit.0));
+        hits
+    }
+
+    #[test]
+    fn operations_keep_the_surfaces_of_the_combination() {
+        let (enter, leave) = (true, false);
+        assert_eq!(hits(&lens(CsgOperation::Union), -5.0, f64::INFINITY), vec![(3.5, enter), (6.5, leave)]);
+        assert_eq!(hits(&lens(CsgOperation::Intersection), -5.0, f64::INFINITY), vec![(4.5, enter), (5.5, leave)]);
+        // Entering the right sphere leaves the difference.
+        assert_eq!(hits(&lens(CsgOperation::Difference), -5.0, f64::INFINITY), vec![(3.5, enter), (4.5, leave)]);
+    }
+
+    #[test]
+    fn rays_can_start_inside() {
+        let leave = false;
+        assert_eq!(hits(&lens(CsgOperation::Union), 0.0, f64::INFINITY), vec![(1.5, leave)]);
+        assert_eq!(hits(&lens(CsgOperation::Intersection), 0.0, f64::INFINITY), vec![(0.5, leave)]);
+        assert_eq!(hits(&lens(CsgOperation::Difference), 0.0, f64::INFINITY), vec![]);
+        assert_eq!(hits(&lens(CsgOperation::Difference), -1.0, f64::INFINITY), vec![(0.5, leave)]);
+    }
+
+    #[test]
+    fn hits_stop_at_t_max() {
+        assert_eq!(hits(&lens(CsgOperation::Union), -5.0, 5.0), vec![(3.5, true)]);
+        // Hits past `t_max` still tell that the ray starts inside.
+        assert_eq!(hits(&lens(CsgOperation::Intersection), 0.0, 0.4), vec![]);
+        assert_eq!(hits(&lens(CsgOperation::Intersection), -5.0, 4.0), vec![]);
+    }
+
+    #[test]
+    fn combinations_nest() {
+        let hollow = Csg::new(CsgOperation::Difference, Arc::new(lens(CsgOperation::Union)), sphere(0.0, 0.3));
+        assert_eq!(hits(&hollow, -5.0, f64::INFINITY), vec![(3.5, true), (4.7, false), (5.3, true), (6.5, false)]);
+        assert_eq!(hits(&hollow, 0.0, f64::INFINITY), vec![(0.3, true), (1.5, false)]);
+
+        let miss = Ray { orig: Point3::new(-5.0, 2.0, 0.0), dir: Vec3::new(1.0, 0.0, 0.0), time: 0.0 };
+        assert!(hollow.hit_by(&mut SmallRng::seed_from_u64(22), &miss, 0.001, f64::INFINITY).is_none());
+    }
+
+    #[test]
+    fn bounding_boxes_follow_the_operation() {
+        let union = lens(CsgOperation::Union).bounding_box();
+        assert_eq!((union.min[0], union.max[0]), (-1.5, 1.5));
+        let intersection = lens(CsgOperation::Intersection).bounding_box();
+        assert_eq!((intersection.min[0], intersection.max[0]), (-0.5, 0.5));
+        assert_eq!((intersection.min[1], intersection.max[1]), (-1.0, 1.0));
+        let difference = lens(CsgOperation::Difference).bounding_box();
+        assert_eq!((difference.min[0], difference.max[0]), (-1.5, 0.5));
+    }
+}
@@ -0,0 +1,354 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::f64::consts::PI;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use image::codecs::hdr::HdrDecoder;
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::color::Color;
+use crate::sampling::Distribution2D;
+use crate::vec3::Vec3;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnvironmentSpec {
+    filename: String,
+    #[serde(default)]
+    rotation_deg: f64,
+    #[serde(default = "default_intensity")]
+    intensity: f64,
+    #[serde(default = "default_importance_sampling")]
+    importance_sampling: bool,
+}
+
+fn default_intensity() -> f64 {
+    1.0
+}
+
+fn default_importance_sampling() -> bool {
+    true
+}
+
+/// Lat-long (equirectangular) environment map with +Y up. The left edge of the image
+/// faces +X and the horizontal coordinate increases towards +Z.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "EnvironmentSpec", into = "EnvironmentSpec")]
+pub struct EnvironmentMap {
+    spec: EnvironmentSpec,
+    width: usize,
+    height: usize,
+    pixels: Arc<Vec<Color>>,
+    distribution: Arc<Distribution2D>,
+}
+
+impl EnvironmentMap {
+    pub fn load(spec: EnvironmentSpec) -> Result<EnvironmentMap, Box<dyn Error>> {
+        let (width, height, pixels) = read_environment_image(&spec.filename)?;
+
+        let mut luminance = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let sin_theta = (PI * (y as f64 + 0.5) / height as f64).sin();
+            for x in 0..width {
+                luminance.push(pixels[y * width + x].luminance() * sin_theta);
+            }
+        }
+
+        Ok(EnvironmentMap {
+            spec,
+            width,
+            height,
+            pixels: Arc::new(pixels),
+            distribution: Arc::new(Distribution2D::new(&luminance, width, height)),
+        })
+    }
+
+    pub fn importance_sampling(&self) -> bool {
+        self.spec.importance_sampling
+    }
+
+    pub fn radiance(&self, dir: Vec3) -> Color {
+        let (u, v) = self.direction_to_uv(dir);
+        let x = ((u * self.width as f64) as usize).min(self.width - 1);
+        let y = ((v * self.height as f64) as usize).min(self.height - 1);
+        self.pixels[y * self.width + x] * self.spec.intensity
+    }
+
+    /// Samples a direction proportionally to the luminance of the map, returning it with
+    /// its solid angle density.
+    pub fn sample(&self, rng: &mut dyn RngCore) -> Option<(Vec3, f64)> {
+        let ((u, v), map_pdf) = self.distribution.sample_continuous(rng.gen(), rng.gen());
+        let sin_theta = (PI * v).sin();
+        if map_pdf == 0.0 || sin_theta == 0.0 {
+            return None;
+        }
+
+        Some((self.uv_to_direction(u, v), map_pdf / (2.0 * PI * PI * sin_theta)))
+    }
+
+    pub fn pdf(&self, dir: Vec3) -> f64 {
+        let (u, v) = self.direction_to_uv(dir);
+        let sin_theta = (PI * v).sin();
+        if sin_theta == 0.0 {
+            return 0.0;
+        }
+
+        self.distribution.pdf(u, v) / (2.0 * PI * PI * sin_theta)
+    }
+
+    fn direction_to_uv(&self, dir: Vec3) -> (f64, f64) {
+        let local = rotate_y(dir.normalize(), -self.spec.rotation_deg.to_radians());
+        let theta = local[1].clamp(-1.0, 1.0).acos();
+        let mut phi = local[2].atan2(local[0]);
+        if phi < 0.0 {
+            phi += 2.0 * PI;
+        }
+
+        (phi / (2.0 * PI), theta / PI)
+    }
+
+    fn uv_to_direction(&self, u: f64, v: f64) -> Vec3 {
+        let phi = 2.0 * PI * u;
+        let theta = PI * v;
+        let local = Vec3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+        rotate_y(local, self.spec.rotation_deg.to_radians())
+    }
+}
+
+fn rotate_y(v: Vec3, angle: f64) -> Vec3 {
+    let (sin, cos) = angle.sin_cos();
+    Vec3::new(cos * v[0] - sin * v[2], v[1], sin * v[0] + cos * v[2])
+}
+
+impl TryFrom<EnvironmentSpec> for EnvironmentMap {
+    type Error = Box<dyn Error>;
+
+    fn try_from(spec: EnvironmentSpec) -> Result<Self, Self::Error> {
+        EnvironmentMap::load(spec)
+    }
+}
+
+impl From<EnvironmentMap> for EnvironmentSpec {
+    fn from(map: EnvironmentMap) -> Self {
+        map.spec
+    }
+}
+
+impl fmt::Debug for EnvironmentMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EnvironmentMap")
+            .field("spec", &self.spec)
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .finish()
+    }
+}
+
+fn read_environment_image(filename: &str) -> Result<(usize, usize, Vec<Color>), Box<dyn Error>> {
+    let extension = Path::new(filename)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("hdr") => read_hdr(filename),
+        Some("pfm") => read_pfm(filename),
+        _ => Err(format!("unsupported environment map format: {}", filename).into())
+    }
+}
+
+fn read_hdr(filename: &str) -> Result<(usize, usize, Vec<Color>), Box<dyn Error>> {
+    let decoder = HdrDecoder::new(BufReader::new(File::open(filename)?))?;
+    let metadata = decoder.metadata();
+    let pixels = decoder.read_image_hdr()?
+        .iter()
+        .map(|p| Color::new(p[0] as f64, p[1] as f64, p[2] as f64))
+        .collect();
+    Ok((metadata.width as usize, metadata.height as usize, pixels))
+}
+
+fn read_pfm(filename: &str) -> Result<(usize, usize, Vec<Color>), Box<dyn Error>> {
+    let bytes = std::fs::read(filename)?;
+
+    // The header is four whitespace-separated tokens followed by a single whitespace byte.
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while tokens.len() < 4 {
+        while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        let start = pos;
+        while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if start == pos {
+            return Err(format!("truncated PFM header: {}", filename).into());
+        }
+        tokens.push(std::str::from_utf8(&bytes[start..pos])?);
+    }
+    pos += 1;
+
+    let channels = match tokens[0] {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(format!("not a PFM file: {}", filename).into())
+    };
+    let width: usize = tokens[1].parse()?;
+    let height: usize = tokens[2].parse()?;
+    let scale: f64 = tokens[3].parse()?;
+    let little_endian = scale < 0.0;
+
+    let data = bytes.get(pos..).unwrap_or(&[]);
+    if data.len() < width * height * channels * 4 {
+        return Err(format!("truncated PFM data: {}", filename).into());
+    }
+
+    let read_float = |i: usize| {
+        let mut b = [0u8; 4];
+        b.copy_from_slice(&data[4 * i..4 * i + 4]);
+        let value = if little_endian { f32::from_le_bytes(b) } else { f32::from_be_bytes(b) };
+        value as f64
+    };
+
+    // PFM scanlines are stored bottom to top.
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let i = (y * width + x) * channels;
+            pixels.push(if channels == 3 {
+                Color::new(read_float(i), read_float(i + 1), read_float(i + 2))
+            } else {
+                let value = read_float(i);
+                Color::new(value, value, value)
+            });
+        }
+    }
+
+    Ok((width, height, pixels))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::codecs::hdr::HdrEncoder;
+    use image::Rgb;
+    use rand::SeedableRng;
+    use rand::rngs::SmallRng;
+
+    use crate::test_util::{assert_close, assert_vec_close, temp_path};
+
+    use super::*;
+
+    fn pfm(header: &str, values: &[f32], little_endian: bool) -> Vec<u8> {
+        let mut bytes = header.as_bytes().to_vec();
+        for value in values.iter() {
+            bytes.extend_from_slice(&if little_endian { value.to_le_bytes() } else { value.to_be_bytes() });
+        }
+        bytes
+    }
+
+    /// Reads `contents` as an environment image named `name`.
+    fn read_image(name: &str, contents: &[u8]) -> Result<(usize, usize, Vec<Color>), Box<dyn Error>> {
+        let path = temp_path(name);
+        std::fs::write(&path, contents).unwrap();
+        let result = read_environment_image(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    fn rgb(color: Color) -> (f64, f64, f64) {
+        (color.r, color.g, color.b)
+    }
+
+    #[test]
+    fn pfm_scanlines_are_read_bottom_to_top() {
+        let values = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 11.0, 12.0];
+        let (width, height, pixels) = read_image("color.pfm", &pfm("PF\n2 2\n-1.0\n", &values, true)).unwrap();
+        assert_eq!((width, height), (2, 2));
+        assert_eq!(rgb(pixels[0]), (7.0, 8.0, 9.0));
+        assert_eq!(rgb(pixels[1]), (10.0, 11.0, 12.0));
+        assert_eq!(rgb(pixels[2]), (1.0, 2.0, 3.0));
+
+        // A positive scale means big-endian data.
+        let (_, _, pixels) = read_image("gray.pfm", &pfm("Pf 3 1 1.0\n", &[0.5, 1.5, 2.5], false)).unwrap();
+        assert_eq!(pixels.iter().map(|&p| rgb(p)).collect::<Vec<_>>(), vec![(0.5, 0.5, 0.5), (1.5, 1.5, 1.5), (2.5, 2.5, 2.5)]);
+    }
+
+    #[test]
+    fn malformed_pfm_is_rejected() {
+        assert!(read_image("magic.pfm", &pfm("P6\n1 1\n-1.0\n", &[0.0; 3], true)).is_err());
+        assert!(read_image("header.pfm", b"PF\n1 1\n").is_err());
+        assert!(read_image("data.pfm", &pfm("PF\n2 1\n-1.0\n", &[0.0; 5], true)).is_err());
+        assert!(read_image("size.pfm", &pfm("PF\n-2 1\n-1.0\n", &[0.0; 6], true)).is_err());
+        assert!(read_image("image.exr", b"").is_err());
+    }
+
+    #[test]
+    fn rgbe_pixels_are_read() {
+        let path = temp_path("rgbe.hdr");
+        let pixels = [Rgb([0.5f32, 1.0, 2.0]), Rgb([4.0, 0.0, 0.25])];
+        HdrEncoder::new(File::create(&path).unwrap()).encode(&pixels, 2, 1).unwrap();
+        let result = read_environment_image(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        let (width, height, pixels) = result.unwrap();
+        assert_eq!((width, height), (2, 1));
+        assert_eq!(rgb(pixels[0]), (0.5, 1.0, 2.0));
+        assert_eq!(rgb(pixels[1]), (4.0, 0.0, 0.25));
+    }
+
+    /// A 4x2 map, brightest in its top right pixel, rotated by `rotation_deg`.
+    fn environment(rotation_deg: f64) -> EnvironmentMap {
+        let mut values = vec![];
+        for i in 0..8 {
+            let value = i as f32 + 1.0;
+            values.extend_from_slice(&[value, value, value]);
+        }
+        let path = temp_path(&format!("environment_{}.pfm", rotation_deg));
+        std::fs::write(&path, pfm("PF\n4 2\n-1.0\n", &values, true)).unwrap();
+        let spec = EnvironmentSpec {
+            filename: path.to_str().unwrap().to_string(),
+            rotation_deg,
+            intensity: 2.0,
+            importance_sampling: true,
+        };
+        let map = EnvironmentMap::load(spec);
+        std::fs::remove_file(&path).unwrap();
+        map.unwrap()
+    }
+
+    #[test]
+    fn directions_map_to_pixels() {
+        let map = environment(0.0);
+        // +Y is the top row, the left edge faces +X and u increases towards +Z.
+        assert_eq!(map.radiance(Vec3::new(1.0, 0.1, 0.1)).r, 2.0 * 5.0);
+        assert_eq!(map.radiance(Vec3::new(0.0, 0.1, 1.0)).r, 2.0 * 6.0);
+        assert_eq!(map.radiance(Vec3::new(0.1, -0.1, -1.0)).r, 2.0 * 4.0);
+
+        let rotated = environment(90.0);
+        assert_eq!(rotated.radiance(Vec3::new(0.1, 0.1, 1.0)).r, map.radiance(Vec3::new(1.0, 0.1, -0.1)).r);
+        for &(u, v) in [(0.1, 0.2), (0.6, 0.7), (0.9, 0.4)].iter() {
+            let (u2, v2) = rotated.direction_to_uv(rotated.uv_to_direction(u, v));
+            assert_close(u2, u, 1e-12);
+            assert_close(v2, v, 1e-12);
+        }
+    }
+
+    #[test]
+    fn sampled_directions_match_their_pdf() {
+        let rng = &mut SmallRng::seed_from_u64(3);
+        let map = environment(30.0);
+        for _ in 0..1000 {
+            let (dir, pdf) = map.sample(rng).unwrap();
+            assert_close(dir.length(), 1.0, 1e-12);
+            assert_close(map.pdf(dir), pdf, 1e-9 * pdf);
+        }
+
+        // Brighter pixels are sampled more often.
+        assert!(map.pdf(map.uv_to_direction(0.9, 0.25)) > map.pdf(map.uv_to_direction(0.1, 0.25)));
+        assert_vec_close(map.uv_to_direction(0.5, 0.0), Vec3::new(0.0, 1.0, 0.0), 1e-12);
+    }
+}
@@ -1,45 +1,154 @@
+use std::f64::consts::PI;
 use std::sync::Arc;
 
-use rand::thread_rng;
+use rand::{Rng, RngCore, thread_rng};
 
 use crate::bounding_box::BBox;
-use crate::bvh::BVHNode;
+use crate::bvh::{BVHBuilder, BVHNode, FlatBVH};
 use crate::material::Material;
 use crate::point3::Point3;
 use crate::ray::Ray;
+use crate::sampling::Distribution1D;
 use crate::vec3::Vec3;
 
-pub struct HitRecord {
+pub struct HitRecord<'a> {
     pub point: Point3,
     pub normal: Vec3,
     pub t: f64,
+    pub u: f64,
+    pub v: f64,
     pub front_face: bool,
-    pub material: Material,
+    /// Unit vector along increasing `u`, perpendicular to `normal`.
+    pub tangent: Vec3,
+    /// Completes the right-handed frame `tangent`, `bitangent`, `normal`.
+    pub bitangent: Vec3,
+    pub material: &'a Material,
 }
 
-impl HitRecord {
+impl<'a> HitRecord<'a> {
     pub fn create(
         ray: &Ray,
         point: Point3,
         outward_normal: Vec3,
         t: f64,
-        material: Material,
-    ) -> HitRecord {
+        (u, v): (f64, f64),
+        dpdu: Vec3,
+        material: &'a Material,
+    ) -> HitRecord<'a> {
         let front_face = ray.dir.dot(outward_normal) < 0.0;
         let normal = if front_face { outward_normal } else { -outward_normal };
+        let (tangent, bitangent) = tangent_frame(normal, dpdu);
         return HitRecord {
             point,
             t,
+            u,
+            v,
             normal,
             front_face,
+            tangent,
+            bitangent,
             material,
         };
     }
+
+    /// Replaces the geometric normal with an interpolated shading normal, keeping the side
+    /// of the surface the ray arrived from and the direction of the tangent.
+    pub fn with_shading_normal(mut self, shading_normal: Vec3) -> HitRecord<'a> {
+        let normal = if self.normal.dot(shading_normal) < 0.0 { -shading_normal } else { shading_normal };
+        let (tangent, bitangent) = tangent_frame(normal, self.tangent);
+        self.normal = normal;
+        self.tangent = tangent;
+        self.bitangent = bitangent;
+        self
+    }
+}
+
+/// Orthonormalizes the surface derivative `dpdu` against `normal`, falling back to an
+/// arbitrary frame where the parameterization degenerates (e.g. at the poles of a sphere).
+pub fn tangent_frame(normal: Vec3, dpdu: Vec3) -> (Vec3, Vec3) {
+    let tangent = dpdu - normal.dot(dpdu) * normal;
+    if tangent.length2() < 1e-16 {
+        return normal.orthonormal_basis();
+    }
+
+    let tangent = tangent.normalize();
+    (tangent, normal.cross(tangent))
+}
+
+/// A point sampled on the surface of a shape, with its density with respect to surface area.
+pub struct SurfaceSample {
+    pub point: Point3,
+    pub normal: Vec3,
+    pub pdf: f64,
 }
 
 pub trait Hittable {
-    fn hit_by(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord>;
+    /// `rng` is the sampler of the current pixel, for shapes like participating media whose
+    /// hits are random, so that renders with a fixed seed stay reproducible.
+    fn hit_by(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>>;
     fn bounding_box(&self) -> BBox;
+
+    /// Every intersection of `ray` with the surface between `t_min` and `t_max`, nearest
+    /// first. CSG follows these to know where the ray enters and leaves closed shapes.
+    fn hit_all(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Vec<HitRecord<'_>> {
+        let mut hits = vec![];
+        let mut t = t_min;
+        while let Some(rec) = self.hit_by(rng, ray, t, t_max) {
+            t = rec.t + 1e-4;
+            hits.push(rec);
+        }
+        hits
+    }
+
+    /// Whether `bounding_box` is finite. Unbounded shapes are kept out of BVHs.
+    fn is_bounded(&self) -> bool {
+        true
+    }
+
+    /// Fraction of the light travelling along `ray` between `t_min` and `t_max` that gets
+    /// through the shape. Surfaces are opaque, participating media let part of it through.
+    fn transmittance(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> f64 {
+        if self.hit_by(rng, ray, t_min, t_max).is_some() { 0.0 } else { 1.0 }
+    }
+
+    /// Surface area of the shape, zero if it can't be sampled as a light.
+    fn area(&self) -> f64 {
+        0.0
+    }
+
+    /// Whether any part of the shape emits light, making it a candidate area light.
+    fn is_emissive(&self) -> bool {
+        false
+    }
+
+    fn sample_surface(&self, _rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        None
+    }
+
+    /// Solid angle density of reaching this shape in direction `dir` from `origin` at `time`
+    /// when sampling its surface uniformly by area.
+    fn pdf_value(&self, rng: &mut dyn RngCore, origin: Point3, dir: Vec3, time: f64) -> f64 {
+        let area = self.area();
+        if area <= 0.0 {
+            return 0.0;
+        }
+
+        match self.hit_by(rng, &Ray { orig: origin, dir, time }, 0.001, f64::INFINITY) {
+            Some(rec) => area_to_solid_angle_pdf(1.0 / area, origin, rec.point, rec.normal),
+            None => 0.0
+        }
+    }
+}
+
+pub fn area_to_solid_angle_pdf(pdf: f64, origin: Point3, point: Point3, normal: Vec3) -> f64 {
+    let to_point = point - origin;
+    let distance2 = to_point.length2();
+    let cosine = normal.dot(to_point).abs() / distance2.sqrt();
+    if cosine < 1e-8 {
+        return 0.0;
+    }
+
+    pdf * distance2 / cosine
 }
 
 pub struct Sphere {
@@ -48,24 +157,47 @@ pub struct Sphere {
     pub material: Material,
 }
 
+impl Sphere {
+    fn record(&self, ray: &Ray, t: f64) -> HitRecord<'_> {
+        let point = ray.at(t);
+        let normal = (point - self.center) / self.radius;
+        HitRecord::create(
+            ray,
+            point,
+            normal,
+            t,
+            sphere_uv(normal),
+            Vec3::new(normal[2], 0.0, -normal[0]),
+            &self.material,
+        )
+    }
+}
+
 impl Hittable for Sphere {
-    fn hit_by(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn hit_by(&self, _rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
         let orig_to_center = ray.orig - self.center;
         let a = ray.dir.length2();
         let half_b = ray.dir.dot(orig_to_center);
         let c = orig_to_center.length2() - self.radius * self.radius;
 
-        solve_quadratic(a, half_b, c, t_min, t_max).map(|t| {
-            let point = ray.at(t);
-            let normal = (point - self.center) / self.radius;
-            HitRecord::create(
-                ray,
-                point,
-                normal,
-                t,
-                self.material,
-            )
-        })
+        solve_quadratic(a, half_b, c, t_min, t_max).map(|t| self.record(ray, t))
+    }
+
+    fn hit_all(&self, _rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Vec<HitRecord<'_>> {
+        let orig_to_center = ray.orig - self.center;
+        let a = ray.dir.length2();
+        let half_b = ray.dir.dot(orig_to_center);
+        let c = orig_to_center.length2() - self.radius * self.radius;
+        let discr = half_b * half_b - a * c;
+        if discr < 0.0 {
+            return vec![];
+        }
+
+        let sqrt_discr = discr.sqrt();
+        [(-half_b - sqrt_discr) / a, (-half_b + sqrt_discr) / a].iter()
+            .filter(|&&t| t_min < t && t < t_max)
+            .map(|&t| self.record(ray, t))
+            .collect()
     }
 
     fn bounding_box(&self) -> BBox {
@@ -75,6 +207,30 @@ impl Hittable for Sphere {
             max: self.center + Vec3::new(abs_radius, abs_radius, abs_radius),
         }
     }
+
+    fn area(&self) -> f64 {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        let point = self.center + self.radius.abs() * Vec3::random_unit_vector(rng);
+        Some(SurfaceSample {
+            point,
+            normal: (point - self.center) / self.radius,
+            pdf: 1.0 / self.area(),
+        })
+    }
+}
+
+/// Maps a unit vector to spherical coordinates, with `v` going from the -Y pole to the +Y pole.
+fn sphere_uv(p: Vec3) -> (f64, f64) {
+    let theta = (-p[1]).clamp(-1.0, 1.0).acos();
+    let phi = (-p[2]).atan2(p[0]) + PI;
+    (phi / (2.0 * PI), theta / PI)
 }
 
 #[inline]
@@ -108,7 +264,7 @@ pub struct Plane {
 }
 
 impl Hittable for Plane {
-    fn hit_by(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn hit_by(&self, _rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
         let dir_dot_normal = ray.dir.dot(self.normal);
         if dir_dot_normal.abs() < 1e-8 {
             return None;
@@ -117,12 +273,17 @@ impl Hittable for Plane {
         let orig_to_center = self.center - ray.orig;
         let t = orig_to_center.dot(self.normal) / dir_dot_normal;
         if t_min < t && t < t_max {
+            let point = ray.at(t);
+            let (tangent, bitangent) = self.normal.normalize().orthonormal_basis();
+            let uv = ((point - self.center).dot(tangent), (point - self.center).dot(bitangent));
             return Some(HitRecord::create(
                 ray,
-                ray.at(t),
+                point,
                 self.normal,
                 t,
-                self.material,
+                uv,
+                tangent,
+                &self.material,
             ));
         }
 
@@ -130,57 +291,117 @@ impl Hittable for Plane {
     }
 
     fn bounding_box(&self) -> BBox {
-        BBox {
-            min: Point3::new(std::f64::NEG_INFINITY, std::f64::NEG_INFINITY, std::f64::NEG_INFINITY),
-            max: Point3::new(std::f64::INFINITY, std::f64::INFINITY, std::f64::INFINITY),
-        }
+        BBox::infinite()
+    }
+
+    fn is_bounded(&self) -> bool {
+        false
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct Triangle {
     pub vertices: [Point3; 3],
+    /// Per-vertex texture coordinates, the barycentric coordinates are used when absent.
+    pub uvs: Option<[(f64, f64); 3]>,
+    /// Per-vertex shading normals, the triangle is shaded flat when absent.
+    pub normals: Option<[Vec3; 3]>,
     pub material: Material,
 }
 
-impl Hittable for Triangle {
-    fn hit_by(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+const BARYCENTRIC_UVS: [(f64, f64); 3] = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)];
+
+impl Triangle {
+    pub fn new(vertices: [Point3; 3], material: Material) -> Triangle {
+        Triangle { vertices, uvs: None, normals: None, material }
+    }
+
+    /// Interpolates the texture coordinates at barycentric `(b1, b2)` and returns them with
+    /// the derivative of the position along `u`.
+    fn surface_parameterization(&self, b1: f64, b2: f64) -> ((f64, f64), Vec3) {
+        let uvs = self.uvs.as_ref().unwrap_or(&BARYCENTRIC_UVS);
+        let b0 = 1.0 - b1 - b2;
+        let uv = (
+            b0 * uvs[0].0 + b1 * uvs[1].0 + b2 * uvs[2].0,
+            b0 * uvs[0].1 + b1 * uvs[1].1 + b2 * uvs[2].1,
+        );
+
         let vertices = &self.vertices;
-        let edge1 = vertices[1] - vertices[0];
-        let edge2 = vertices[2] - vertices[0];
-        let h = ray.dir.cross(edge2);
-        let a = edge1.dot(h);
-        if a.abs() < 1e-8 {
-            return None;
-        }
+        let (du02, dv02) = (uvs[0].0 - uvs[2].0, uvs[0].1 - uvs[2].1);
+        let (du12, dv12) = (uvs[1].0 - uvs[2].0, uvs[1].1 - uvs[2].1);
+        let determinant = du02 * dv12 - dv02 * du12;
+        let dpdu = if determinant.abs() < 1e-12 {
+            vertices[1] - vertices[0]
+        } else {
+            (dv12 * (vertices[0] - vertices[2]) - dv02 * (vertices[1] - vertices[2])) / determinant
+        };
 
-        let f = 1.0 / a;
-        let s = ray.orig - vertices[0];
-        let u = f * s.dot(h);
-        if u < 0.0 || u > 1.0 {
-            return None;
-        }
+        (uv, dpdu)
+    }
+}
 
-        let q = s.cross(edge1);
-        let v = f * ray.dir.dot(q);
-        if v < 0.0 || u + v > 1.0 {
-            return None;
-        }
+/// Möller-Trumbore intersection of `ray` with a triangle, giving the distance along the ray and
+/// the barycentric coordinates of the second and third vertices.
+pub fn intersect_triangle(vertices: &[Point3; 3], ray: &Ray, t_min: f64, t_max: f64) -> Option<(f64, f64, f64)> {
+    let edge1 = vertices[1] - vertices[0];
+    let edge2 = vertices[2] - vertices[0];
+    let h = ray.dir.cross(edge2);
+    let a = edge1.dot(h);
+    if a.abs() < 1e-8 {
+        return None;
+    }
 
-        let t = f * edge2.dot(q);
-        if t < t_min || t > t_max {
-            return None;
-        }
+    let f = 1.0 / a;
+    let s = ray.orig - vertices[0];
+    let u = f * s.dot(h);
+    if u < 0.0 || u > 1.0 {
+        return None;
+    }
+
+    let q = s.cross(edge1);
+    let v = f * ray.dir.dot(q);
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = f * edge2.dot(q);
+    if t < t_min || t > t_max {
+        return None;
+    }
+
+    Some((t, u, v))
+}
 
+impl Hittable for Triangle {
+    fn hit_by(&self, _rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let (t, u, v) = intersect_triangle(&self.vertices, ray, t_min, t_max)?;
+        let vertices = &self.vertices;
+        let edge1 = vertices[1] - vertices[0];
+        let edge2 = vertices[2] - vertices[0];
         let outward_normal = edge1.cross(edge2).normalize();
         let point = ray.at(t);
-        Some(HitRecord::create(
+        let (uv, dpdu) = self.surface_parameterization(u, v);
+        let rec = HitRecord::create(
             ray,
             point,
             outward_normal,
             t,
-            self.material,
-        ))
+            uv,
+            dpdu,
+            &self.material,
+        );
+
+        match self.normals {
+            Some(normals) => {
+                let shading_normal = (1.0 - u - v) * normals[0] + u * normals[1] + v * normals[2];
+                if shading_normal.near_zero() {
+                    Some(rec)
+                } else {
+                    Some(rec.with_shading_normal(shading_normal.normalize()))
+                }
+            }
+            None => Some(rec)
+        }
     }
 
     fn bounding_box(&self) -> BBox {
@@ -199,41 +420,155 @@ impl Hittable for Triangle {
             max: Point3::new(max_x, max_y, max_z),
         }
     }
+
+    fn area(&self) -> f64 {
+        let vertices = &self.vertices;
+        0.5 * (vertices[1] - vertices[0]).cross(vertices[2] - vertices[0]).length()
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        let vertices = &self.vertices;
+        let edge1 = vertices[1] - vertices[0];
+        let edge2 = vertices[2] - vertices[0];
+        let normal = edge1.cross(edge2);
+        let area = 0.5 * normal.length();
+        if area <= 0.0 {
+            return None;
+        }
+
+        let sqrt_r1 = rng.gen::<f64>().sqrt();
+        let r2 = rng.gen::<f64>();
+        Some(SurfaceSample {
+            point: vertices[0] + sqrt_r1 * (1.0 - r2) * edge1 + sqrt_r1 * r2 * edge2,
+            normal: normal.normalize(),
+            pdf: 1.0 / area,
+        })
+    }
 }
 
 pub struct TriangleMesh {
-    triangles: BVHNode<Triangle>,
+    triangles: FlatBVH<Triangle>,
+    area: f64,
+    is_emissive: bool,
+    /// Area-weighted distribution over the BVH primitives, only built for emissive meshes
+    /// since those are the ones sampled as lights.
+    distribution: Option<Distribution1D>,
 }
 
 impl TriangleMesh {
     pub fn new(vertices: &Vec<Point3>, vertices_index: &Vec<usize>, material: Material) -> TriangleMesh {
+        TriangleMesh::with_uvs(vertices, None, vertices_index, material)
+    }
+
+    /// Builds a mesh whose texture coordinates are interpolated from `uvs`, indexed like `vertices`.
+    pub fn with_uvs(
+        vertices: &[Point3],
+        uvs: Option<&[(f64, f64)]>,
+        vertices_index: &[usize],
+        material: Material,
+    ) -> TriangleMesh {
         let mut triangles: Vec<Triangle> = vec![];
-        for i in 0..vertices_index.len() / 3 {
+        for face in vertices_index.chunks_exact(3) {
             triangles.push(Triangle {
-                vertices: [
-                    vertices[vertices_index[3 * i]],
-                    vertices[vertices_index[3 * i + 1]],
-                    vertices[vertices_index[3 * i + 2]]
-                ],
-                material,
+                vertices: [vertices[face[0]], vertices[face[1]], vertices[face[2]]],
+                uvs: uvs.map(|uvs| [uvs[face[0]], uvs[face[1]], uvs[face[2]]]),
+                normals: None,
+                material: material.clone(),
             });
         }
 
-        let bvh = BVHNode::from_shapes(&mut thread_rng(), triangles.as_mut_slice());
-        TriangleMesh { triangles: bvh }
+        TriangleMesh::from_triangles(triangles, BVHBuilder::default())
+    }
+
+    pub fn from_triangles(mut triangles: Vec<Triangle>, bvh_builder: BVHBuilder) -> TriangleMesh {
+        let bvh = BVHNode::build(&mut thread_rng(), bvh_builder, triangles.as_mut_slice());
+        TriangleMesh::from_bvh(FlatBVH::new(bvh))
+    }
+
+    pub fn from_bvh(triangles: FlatBVH<Triangle>) -> TriangleMesh {
+        let area = triangles.primitives().iter().map(|t| t.area()).sum();
+        let is_emissive = triangles.primitives().iter().any(|t| t.is_emissive());
+        let distribution = if is_emissive {
+            Some(Distribution1D::new(triangles.primitives().iter().map(|t| t.area()).collect()))
+        } else {
+            None
+        };
+        TriangleMesh { triangles, area, is_emissive, distribution }
     }
 }
 
 impl Hittable for TriangleMesh {
-    fn hit_by(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        self.triangles.hit_by(ray, t_min, t_max)
+    fn hit_by(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        self.triangles.hit_by(rng, ray, t_min, t_max)
     }
 
     fn bounding_box(&self) -> BBox {
         self.triangles.bounding_box()
     }
+
+    fn area(&self) -> f64 {
+        self.area
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.is_emissive
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        let distribution = self.distribution.as_ref()?;
+        let (index, probability) = distribution.sample_discrete(rng.gen());
+        self.triangles.primitives()[index].sample_surface(rng).map(|sample| SurfaceSample {
+            pdf: sample.pdf * probability,
+            ..sample
+        })
+    }
 }
 
+/// Generates a vertex normal for every corner listed in `vertices_index` by averaging the
+/// area-weighted normals of the faces sharing that vertex. Faces meeting at more than
+/// `max_angle_deg` don't contribute to each other, which keeps hard edges sharp.
+pub fn smooth_normals(vertices: &[Point3], vertices_index: &[usize], max_angle_deg: f64) -> Vec<Vec3> {
+    let face_normals = vertices_index.chunks_exact(3)
+        .map(|face| (vertices[face[1]] - vertices[face[0]]).cross(vertices[face[2]] - vertices[face[0]]))
+        .collect::<Vec<_>>();
+
+    let mut adjacent_faces = vec![vec![]; vertices.len()];
+    for (corner, &vertex) in vertices_index.iter().enumerate().take(3 * face_normals.len()) {
+        adjacent_faces[vertex].push(corner / 3);
+    }
+
+    let min_cosine = max_angle_deg.to_radians().cos();
+    let mut normals = Vec::with_capacity(vertices_index.len());
+    for (corner, &vertex) in vertices_index.iter().enumerate().take(3 * face_normals.len()) {
+        let face = corner / 3;
+        let face_normal = face_normals[face];
+        if face_normal.length2() == 0.0 {
+            normals.push(face_normal);
+            continue;
+        }
+
+        let unit_face_normal = face_normal.normalize();
+        let mut normal = face_normal;
+        for &other in adjacent_faces[vertex].iter() {
+            let other_normal = face_normals[other];
+            if other == face || other_normal.length2() == 0.0 {
+                continue;
+            }
+
+            if unit_face_normal.dot(other_normal.normalize()) >= min_cosine {
+                normal += other_normal;
+            }
+        }
+
+        normals.push(normal.normalize());
+    }
+
+    normals
+}
 
 pub struct Parallelepiped {
     triangles: Vec<Triangle>,
@@ -248,76 +583,52 @@ impl Parallelepiped {
 
         Parallelepiped {
             triangles: vec![
-                Triangle {
-                    vertices: [a, b, c],
-                    material,
-                },
-                Triangle {
-                    vertices: [a, d, b],
-                    material,
-                },
-                Triangle {
-                    vertices: [a, c, d],
-                    material,
-                },
-                Triangle {
-                    vertices: [d, c, v1],
-                    material,
-                },
-                Triangle {
-                    vertices: [c, b, v2],
-                    material,
-                },
-                Triangle {
-                    vertices: [b, d, v3],
-                    material,
-                },
-                Triangle {
-                    vertices: [d, v1, v4],
-                    material,
-                },
-                Triangle {
-                    vertices: [d, v4, v3],
-                    material,
-                },
-                Triangle {
-                    vertices: [c, v4, v1],
-                    material,
-                },
-                Triangle {
-                    vertices: [c, v2, v4],
-                    material,
-                },
-                Triangle {
-                    vertices: [b, v4, v2],
-                    material,
-                },
-                Triangle {
-                    vertices: [b, v3, v4],
-                    material,
-                },
+                Triangle::new([a, b, c], material.clone()),
+                Triangle::new([a, d, b], material.clone()),
+                Triangle::new([a, c, d], material.clone()),
+                Triangle::new([d, c, v1], material.clone()),
+                Triangle::new([c, b, v2], material.clone()),
+                Triangle::new([b, d, v3], material.clone()),
+                Triangle::new([d, v1, v4], material.clone()),
+                Triangle::new([d, v4, v3], material.clone()),
+                Triangle::new([c, v4, v1], material.clone()),
+                Triangle::new([c, v2, v4], material.clone()),
+                Triangle::new([b, v4, v2], material.clone()),
+                Triangle::new([b, v3, v4], material),
             ]
         }
     }
 }
 
 impl Hittable for Parallelepiped {
-    fn hit_by(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        self.triangles.hit_by(ray, t_min, t_max)
+    fn hit_by(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        self.triangles.hit_by(rng, ray, t_min, t_max)
     }
 
     fn bounding_box(&self) -> BBox {
         self.triangles.bounding_box()
     }
+
+    fn area(&self) -> f64 {
+        self.triangles.area()
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.triangles.is_emissive()
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        self.triangles.sample_surface(rng)
+    }
 }
 
 impl<T: Hittable> Hittable for Vec<T> {
-    fn hit_by(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn hit_by(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
         let mut closest_t = t_max;
         let mut closest_found: Option<HitRecord> = None;
 
         self.iter()
-            .map(|obj| obj.hit_by(ray, t_min, t_max))
+            .map(|obj| obj.hit_by(rng, ray, t_min, t_max))
             .filter_map(|obj| obj)
             .for_each(|rec| {
                 let curr_t = rec.t;
@@ -333,7 +644,7 @@ impl<T: Hittable> Hittable for Vec<T> {
     fn bounding_box(&self) -> BBox {
         let mut iter = self.iter();
         let first_bbox = match iter.next() {
-            None => panic!("No bounding box for an empty set"),
+            None => return BBox::empty(),
             Some(shape) => shape.bounding_box()
         };
 
@@ -344,26 +655,253 @@ impl<T: Hittable> Hittable for Vec<T> {
                 |accum, bbox| BBox::surrounding_box(accum, bbox),
             )
     }
+
+    fn is_bounded(&self) -> bool {
+        self.iter().all(|h| h.is_bounded())
+    }
+
+    fn transmittance(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> f64 {
+        let mut transmittance = 1.0;
+        for h in self.iter() {
+            transmittance *= h.transmittance(rng, ray, t_min, t_max);
+            if transmittance <= 0.0 {
+                return 0.0;
+            }
+        }
+        transmittance
+    }
+
+    fn area(&self) -> f64 {
+        self.iter().map(|h| h.area()).sum()
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.iter().any(|h| h.is_emissive())
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        let total_area = self.area();
+        if total_area <= 0.0 {
+            return None;
+        }
+
+        let mut threshold = rng.gen::<f64>() * total_area;
+        let shape = self.iter()
+            .find(|h| {
+                threshold -= h.area();
+                threshold < 0.0
+            })
+            .or_else(|| self.iter().rev().find(|h| h.area() > 0.0))?;
+
+        shape.sample_surface(rng).map(|sample| SurfaceSample {
+            pdf: sample.pdf * shape.area() / total_area,
+            ..sample
+        })
+    }
+
+    /// Density of the shape hit first in direction `dir`, weighted by the probability of
+    /// `sample_surface` picking it.
+    fn pdf_value(&self, rng: &mut dyn RngCore, origin: Point3, dir: Vec3, time: f64) -> f64 {
+        let total_area = self.area();
+        if total_area <= 0.0 {
+            return 0.0;
+        }
+
+        let ray = Ray { orig: origin, dir, time };
+        let closest = self.iter()
+            .filter_map(|h| h.hit_by(rng, &ray, 0.001, f64::INFINITY).map(|rec| (rec.t, h)))
+            .min_by(|(t1, _), (t2, _)| t1.partial_cmp(t2).unwrap());
+        match closest {
+            Some((_, shape)) => shape.pdf_value(rng, origin, dir, time) * shape.area() / total_area,
+            None => 0.0
+        }
+    }
 }
 
 impl<T: Hittable + ?Sized> Hittable for Arc<T> {
-    fn hit_by(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        T::hit_by(self, ray, t_min, t_max)
+    fn hit_by(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        T::hit_by(self, rng, ray, t_min, t_max)
     }
 
     fn bounding_box(&self) -> BBox {
         T::bounding_box(self)
     }
+
+    fn hit_all(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Vec<HitRecord<'_>> {
+        T::hit_all(self, rng, ray, t_min, t_max)
+    }
+
+    fn is_bounded(&self) -> bool {
+        T::is_bounded(self)
+    }
+
+    fn transmittance(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> f64 {
+        T::transmittance(self, rng, ray, t_min, t_max)
+    }
+
+    fn area(&self) -> f64 {
+        T::area(self)
+    }
+
+    fn is_emissive(&self) -> bool {
+        T::is_emissive(self)
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        T::sample_surface(self, rng)
+    }
+
+    fn pdf_value(&self, rng: &mut dyn RngCore, origin: Point3, dir: Vec3, time: f64) -> f64 {
+        T::pdf_value(self, rng, origin, dir, time)
+    }
 }
 
 impl<T: Hittable> Hittable for &T {
-    fn hit_by(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        T::hit_by(self, ray, t_min, t_max)
+    fn hit_by(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        T::hit_by(self, rng, ray, t_min, t_max)
     }
 
     fn bounding_box(&self) -> BBox {
         T::bounding_box(self)
     }
+
+    fn hit_all(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Vec<HitRecord<'_>> {
+        T::hit_all(self, rng, ray, t_min, t_max)
+    }
+
+    fn is_bounded(&self) -> bool {
+        T::is_bounded(self)
+    }
+
+    fn transmittance(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> f64 {
+        T::transmittance(self, rng, ray, t_min, t_max)
+    }
+
+    fn area(&self) -> f64 {
+        T::area(self)
+    }
+
+    fn is_emissive(&self) -> bool {
+        T::is_emissive(self)
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        T::sample_surface(self, rng)
+    }
+
+    fn pdf_value(&self, rng: &mut dyn RngCore, origin: Point3, dir: Vec3, time: f64) -> f64 {
+        T::pdf_value(self, rng, origin, dir, time)
+    }
 }
 
-pub type ArcHittable = Arc<dyn Hittable + Send + Sync>;
\ No newline at end of file
+pub type ArcHittable = Arc<dyn Hittable + Send + Sync>;
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    use crate::test_util::{assert_close, assert_vec_close, ray};
+
+    use super::*;
+
+    fn assert_frame(rec: &HitRecord) {
+        assert_close(rec.tangent.length(), 1.0, 1e-9);
+        assert_close(rec.tangent.dot(rec.normal), 0.0, 1e-9);
+        assert_vec_close(rec.bitangent, rec.normal.cross(rec.tangent), 1e-9);
+    }
+
+    #[test]
+    fn sphere_tangent_points_along_increasing_u() {
+        let rng = &mut SmallRng::seed_from_u64(0);
+        let sphere = Sphere { center: Point3::new(0.0, 0.0, 0.0), radius: 1.0, material: Material::BlackBody };
+        let rec = sphere.hit_by(rng, &ray(Point3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f64::INFINITY).unwrap();
+        assert_close(rec.u, 0.25, 1e-9);
+        assert_close(rec.v, 0.5, 1e-9);
+        assert_frame(&rec);
+        assert_vec_close(rec.tangent, Vec3::new(1.0, 0.0, 0.0), 1e-9);
+
+        let nudged = Point3::new(0.0, 0.0, 5.0) + 0.01 * rec.tangent;
+        let next = sphere.hit_by(rng, &ray(nudged, Vec3::new(0.0, 0.0, -1.0)), 0.001, f64::INFINITY).unwrap();
+        assert!(next.u > rec.u);
+        assert_close(next.v, rec.v, 1e-9);
+    }
+
+    #[test]
+    fn sphere_poles_still_get_a_frame() {
+        let rng = &mut SmallRng::seed_from_u64(0);
+        let sphere = Sphere { center: Point3::new(0.0, 0.0, 0.0), radius: 1.0, material: Material::BlackBody };
+        let rec = sphere.hit_by(rng, &ray(Point3::new(0.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0)), 0.001, f64::INFINITY).unwrap();
+        assert_close(rec.v, 1.0, 1e-9);
+        assert_frame(&rec);
+    }
+
+    #[test]
+    fn plane_frames_are_orthonormal() {
+        let rng = &mut SmallRng::seed_from_u64(0);
+        let plane = Plane { center: Point3::new(0.0, -1.0, 0.0), normal: Vec3::new(0.0, 1.0, 1.0).normalize(), material: Material::BlackBody };
+        let rec = plane.hit_by(rng, &ray(Point3::new(0.3, 2.0, 0.1), Vec3::new(0.0, -1.0, 0.0)), 0.001, f64::INFINITY).unwrap();
+        assert_frame(&rec);
+    }
+
+    #[test]
+    fn triangle_uvs_are_interpolated_and_orient_the_tangent() {
+        let rng = &mut SmallRng::seed_from_u64(0);
+        let vertices = [Point3::new(0.0, 0.0, 0.0), Point3::new(2.0, 0.0, 0.0), Point3::new(0.0, 1.0, 0.0)];
+        let ray = ray(Point3::new(0.5, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0));
+
+        let plain = Triangle::new(vertices, Material::BlackBody);
+        let rec = plain.hit_by(rng, &ray, 0.001, f64::INFINITY).unwrap();
+        assert_close(rec.u, 0.25, 1e-9);
+        assert_close(rec.v, 0.25, 1e-9);
+        assert_vec_close(rec.tangent, Vec3::new(1.0, 0.0, 0.0), 1e-9);
+
+        // u decreases towards the third vertex and v increases towards the last two.
+        let mapped = Triangle { uvs: Some([(0.5, 0.5), (0.5, 1.0), (0.0, 0.5)]), ..plain };
+        let rec = mapped.hit_by(rng, &ray, 0.001, f64::INFINITY).unwrap();
+        assert_close(rec.u, 0.375, 1e-9);
+        assert_close(rec.v, 0.625, 1e-9);
+        assert_vec_close(rec.tangent, Vec3::new(0.0, -1.0, 0.0), 1e-9);
+        assert_frame(&rec);
+    }
+
+    #[test]
+    fn smooth_normals_only_blend_faces_within_the_angle() {
+        let normals_for_fold = |height: f64| {
+            let vertices = [
+                Point3::new(0.0, 0.0, 0.0),
+                Point3::new(1.0, 0.0, 0.0),
+                Point3::new(0.0, 1.0, 0.0),
+                Point3::new(-1.0, 0.0, height),
+            ];
+            smooth_normals(&vertices, &[0, 1, 2, 0, 2, 3], 60.0)
+        };
+
+        let shallow = normals_for_fold(0.2);
+        assert_vec_close(shallow[0], Vec3::new(0.2, 0.0, 2.0).normalize(), 1e-9);
+        assert_vec_close(shallow[1], Vec3::new(0.0, 0.0, 1.0), 1e-9);
+        assert_vec_close(shallow[3], shallow[0], 1e-9);
+
+        let sharp = normals_for_fold(3.0);
+        assert_vec_close(sharp[0], Vec3::new(0.0, 0.0, 1.0), 1e-9);
+        assert_vec_close(sharp[3], Vec3::new(3.0, 0.0, 1.0).normalize(), 1e-9);
+    }
+
+    #[test]
+    fn shading_normals_are_interpolated_on_the_side_of_the_ray() {
+        let rng = &mut SmallRng::seed_from_u64(0);
+        let triangle = Triangle {
+            normals: Some([Vec3::new(0.0, 0.0, 1.0), Vec3::new(1.0, 0.0, 1.0).normalize(), Vec3::new(0.0, 0.0, 1.0)]),
+            ..Triangle::new([Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(0.0, 1.0, 0.0)], Material::BlackBody)
+        };
+        let expected = (0.5 * Vec3::new(0.0, 0.0, 1.0) + 0.5 * Vec3::new(1.0, 0.0, 1.0).normalize()).normalize();
+
+        let from_above = triangle.hit_by(rng, &ray(Point3::new(0.5, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f64::INFINITY).unwrap();
+        assert_vec_close(from_above.normal, expected, 1e-9);
+        assert_frame(&from_above);
+
+        let from_below = triangle.hit_by(rng, &ray(Point3::new(0.5, 0.0, -1.0), Vec3::new(0.0, 0.0, 1.0)), 0.001, f64::INFINITY).unwrap();
+        assert_vec_close(from_below.normal, -expected, 1e-9);
+        assert!(!from_below.front_face);
+    }
+}
@@ -0,0 +1,115 @@
+use rand::{RngCore, thread_rng};
+
+use crate::bounding_box::BBox;
+use crate::bvh::{BVHBuilder, SceneBVH};
+use crate::geometry::{ArcHittable, HitRecord, Hittable, SurfaceSample};
+use crate::point3::Point3;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+/// Child shapes under their own BVH, so that a whole sub-assembly is a single entry of the
+/// scene's top-level BVH. Emissive children are kept aside to be sampled as one light.
+pub struct Group {
+    bvh: SceneBVH<ArcHittable>,
+    emitters: Vec<ArcHittable>,
+}
+
+impl Group {
+    /// Panics if `children` is empty.
+    pub fn new(children: Vec<ArcHittable>, bvh_builder: BVHBuilder) -> Group {
+        assert!(!children.is_empty(), "a group needs at least one object");
+        let emitters = children.iter()
+            .filter(|child| child.is_emissive() && child.area() > 0.0)
+            .cloned()
+            .collect();
+        let bvh = SceneBVH::build(&mut thread_rng(), bvh_builder, &children);
+        Group { bvh, emitters }
+    }
+}
+
+impl Hittable for Group {
+    fn hit_by(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        self.bvh.hit_by(rng, ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self) -> BBox {
+        self.bvh.bounding_box()
+    }
+
+    fn is_bounded(&self) -> bool {
+        self.bvh.is_bounded()
+    }
+
+    fn transmittance(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> f64 {
+        self.bvh.transmittance(rng, ray, t_min, t_max)
+    }
+
+    /// Area of the emissive children only, since they're all that gets sampled.
+    fn area(&self) -> f64 {
+        self.emitters.area()
+    }
+
+    fn is_emissive(&self) -> bool {
+        !self.emitters.is_empty()
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        self.emitters.sample_surface(rng)
+    }
+
+    fn pdf_value(&self, rng: &mut dyn RngCore, origin: Point3, dir: Vec3, time: f64) -> f64 {
+        self.emitters.pdf_value(rng, origin, dir, time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+    use std::sync::Arc;
+
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    use crate::geometry::Sphere;
+    use crate::light::Light;
+    use crate::test_util::{assert_close, ray};
+
+    use super::*;
+
+    fn sphere(x: f64, material: &str) -> ArcHittable {
+        Arc::new(Sphere { center: Point3::new(x, 0.0, -5.0), radius: 1.0, material: serde_yaml::from_str(material).unwrap() })
+    }
+
+    #[test]
+    fn hits_the_nearest_child() {
+        let rng = &mut SmallRng::seed_from_u64(0);
+        let group = Group::new(vec![sphere(0.0, "type: BlackBody"), sphere(3.0, "type: BlackBody")], BVHBuilder::default());
+        let rec = group.hit_by(rng, &ray(Point3::new(3.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f64::INFINITY).unwrap();
+        assert_close(rec.point[0], 3.0, 1e-9);
+        assert!(group.hit_by(rng, &ray(Point3::new(1.5, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f64::INFINITY).is_none());
+    }
+
+    #[test]
+    fn only_emissive_children_are_sampled() {
+        let rng = &mut SmallRng::seed_from_u64(12);
+        let group = Group::new(vec![
+            sphere(0.0, "type: BlackBody"),
+            sphere(3.0, "type: DiffuseLight\nemit: [1.0, 1.0, 1.0]"),
+        ], BVHBuilder::default());
+        assert!(group.is_emissive());
+        assert_close(group.area(), 4.0 * PI, 1e-9);
+
+        let origin = Point3::new(3.0, 0.0, 0.0);
+        let light = Light::Area(Arc::new(group));
+        for _ in 0..100 {
+            if let Some(sample) = light.sample(rng, origin, 0.0) {
+                assert!(sample.dir[0].abs() < 0.25);
+                assert_close(sample.pdf, light.pdf(rng, origin, sample.dir, 0.0), 1e-6 * sample.pdf);
+            }
+        }
+
+        let dark = Group::new(vec![sphere(0.0, "type: BlackBody")], BVHBuilder::default());
+        assert!(!dark.is_emissive());
+        assert_eq!(dark.area(), 0.0);
+    }
+}
@@ -0,0 +1,265 @@
+use std::error::Error;
+
+use rand::RngCore;
+
+use crate::bounding_box::BBox;
+use crate::geometry::{HitRecord, Hittable, intersect_triangle};
+use crate::material::Material;
+use crate::point3::Point3;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+/// Terrain with one vertex per pixel of a grayscale image, black at the height of `origin`
+/// and white `height_scale` above it. The image spans `size[0]` along X from left to right
+/// and `size[1]` along Z from top to bottom.
+///
+/// Rays walk the grid cell by cell and only test the two triangles of the cells whose
+/// height range they cross, so no triangle is stored. Normals are interpolated from
+/// per-vertex normals found by central differences.
+pub struct Heightfield {
+    origin: Point3,
+    /// Size of a cell along X and Z.
+    cell_size: (f64, f64),
+    /// Number of vertices along X and Z.
+    resolution: (usize, usize),
+    heights: Vec<f64>,
+    normals: Vec<Vec3>,
+    bbox: BBox,
+    material: Material,
+}
+
+impl Heightfield {
+    /// Reads the heights from an image, using 16 bits per pixel when the file has them.
+    pub fn load(filename: &str, origin: Point3, size: (f64, f64), height_scale: f64, material: Material) -> Result<Heightfield, Box<dyn Error>> {
+        let image = image::open(filename)?.to_luma16();
+        let (width, height) = image.dimensions();
+        if width < 2 || height < 2 {
+            return Err(format!("heightfield {} must be at least 2x2 pixels", filename).into());
+        }
+
+        let heights = image.pixels().map(|pixel| height_scale * pixel[0] as f64 / 65535.0).collect();
+        Ok(Heightfield::new(origin, size, (width as usize, height as usize), heights, material))
+    }
+
+    /// `heights` are relative to `origin`, row by row along X.
+    pub fn new(origin: Point3, size: (f64, f64), resolution: (usize, usize), heights: Vec<f64>, material: Material) -> Heightfield {
+        let (nx, nz) = resolution;
+        assert!(nx >= 2 && nz >= 2 && heights.len() == nx * nz, "invalid heightfield resolution");
+        let cell_size = (size.0 / (nx - 1) as f64, size.1 / (nz - 1) as f64);
+
+        let height = |i: usize, j: usize| heights[j * nx + i];
+        let normals = (0..nz)
+            .flat_map(|j| (0..nx).map(move |i| (i, j)))
+            .map(|(i, j)| {
+                let (left, right) = (i.saturating_sub(1), (i + 1).min(nx - 1));
+                let (back, front) = (j.saturating_sub(1), (j + 1).min(nz - 1));
+                let dx = (height(right, j) - height(left, j)) / ((right - left) as f64 * cell_size.0);
+                let dz = (height(i, front) - height(i, back)) / ((front - back) as f64 * cell_size.1);
+                Vec3::new(-dx, 1.0, -dz).normalize()
+            })
+            .collect();
+
+        let (min_height, max_height) = heights.iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &h| (min.min(h), max.max(h)));
+        let bbox = BBox {
+            min: origin + Vec3::new(0.0, min_height, 0.0),
+            max: origin + Vec3::new(size.0, max_height, size.1),
+        };
+
+        Heightfield { origin, cell_size, resolution, heights, normals, bbox, material }
+    }
+
+    fn index(&self, i: usize, j: usize) -> usize {
+        j * self.resolution.0 + i
+    }
+
+    fn vertex(&self, i: usize, j: usize) -> Point3 {
+        self.origin + Vec3::new(i as f64 * self.cell_size.0, self.heights[self.index(i, j)], j as f64 * self.cell_size.1)
+    }
+
+    /// Nearest hit with the two triangles of cell `(i, j)`.
+    fn hit_cell(&self, ray: &Ray, i: usize, j: usize, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let corners = [(i, j), (i, j + 1), (i + 1, j), (i + 1, j + 1)];
+        let mut closest_t = t_max;
+        let mut closest = None;
+        // Both triangles are wound to face +Y.
+        for triangle in [[corners[0], corners[1], corners[2]], [corners[3], corners[2], corners[1]]].iter() {
+            let vertices = [
+                self.vertex(triangle[0].0, triangle[0].1),
+                self.vertex(triangle[1].0, triangle[1].1),
+                self.vertex(triangle[2].0, triangle[2].1),
+            ];
+            if let Some((t, b1, b2)) = intersect_triangle(&vertices, ray, t_min, closest_t) {
+                closest_t = t;
+                closest = Some((vertices, *triangle, b1, b2));
+            }
+        }
+
+        let (vertices, triangle, b1, b2) = closest?;
+        let point = ray.at(closest_t);
+        let normal = (vertices[1] - vertices[0]).cross(vertices[2] - vertices[0]).normalize();
+        let size = (self.bbox.max[0] - self.bbox.min[0], self.bbox.max[2] - self.bbox.min[2]);
+        let uv = ((point[0] - self.origin[0]) / size.0, (point[2] - self.origin[2]) / size.1);
+        let rec = HitRecord::create(ray, point, normal, closest_t, uv, Vec3::new(1.0, 0.0, 0.0), &self.material);
+
+        let normals = triangle.iter().map(|&(i, j)| self.normals[self.index(i, j)]).collect::<Vec<_>>();
+        let shading_normal = (1.0 - b1 - b2) * normals[0] + b1 * normals[1] + b2 * normals[2];
+        Some(rec.with_shading_normal(shading_normal.normalize()))
+    }
+
+    fn cell_height_range(&self, i: usize, j: usize) -> (f64, f64) {
+        let heights = [
+            self.heights[self.index(i, j)],
+            self.heights[self.index(i + 1, j)],
+            self.heights[self.index(i, j + 1)],
+            self.heights[self.index(i + 1, j + 1)],
+        ];
+        let min = heights.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = heights.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        (self.origin[1] + min, self.origin[1] + max)
+    }
+}
+
+impl Hittable for Heightfield {
+    /// Walks the cells crossed by the ray in order with a 2D DDA over the XZ grid.
+    fn hit_by(&self, _rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let (t_enter, t_exit) = self.bbox.clip(ray, t_min, t_max)?;
+        let (nx, nz) = (self.resolution.0 - 1, self.resolution.1 - 1);
+        let start = ray.at(t_enter);
+        let cell_coordinate = |p: f64, origin: f64, size: f64, count: usize| {
+            (((p - origin) / size).floor().max(0.0) as usize).min(count - 1)
+        };
+        let mut cell = [
+            cell_coordinate(start[0], self.origin[0], self.cell_size.0, nx),
+            cell_coordinate(start[2], self.origin[2], self.cell_size.1, nz),
+        ];
+
+        // Distance along the ray to the next cell boundary on each axis, and between two.
+        let axes = [(0, self.cell_size.0, nx), (2, self.cell_size.1, nz)];
+        let mut t_next = [f64::INFINITY; 2];
+        let mut t_delta = [f64::INFINITY; 2];
+        let mut step = [0isize; 2];
+        for (k, &(axis, size, _)) in axes.iter().enumerate() {
+            let dir = ray.dir[axis];
+            if dir.abs() < 1e-12 {
+                continue;
+            }
+            let boundary = if dir > 0.0 { cell[k] + 1 } else { cell[k] };
+            t_next[k] = (self.origin[axis] + boundary as f64 * size - ray.orig[axis]) / dir;
+            t_delta[k] = size / dir.abs();
+            step[k] = if dir > 0.0 { 1 } else { -1 };
+        }
+
+        let mut t = t_enter;
+        loop {
+            let t_cell_exit = t_next[0].min(t_next[1]).min(t_exit);
+            let (y_enter, y_exit) = (ray.at(t)[1], ray.at(t_cell_exit)[1]);
+            let (min, max) = self.cell_height_range(cell[0], cell[1]);
+            if y_enter.min(y_exit) <= max && y_enter.max(y_exit) >= min {
+                if let Some(rec) = self.hit_cell(ray, cell[0], cell[1], t_min, t_max) {
+                    return Some(rec);
+                }
+            }
+
+            if t_cell_exit >= t_exit {
+                return None;
+            }
+
+            let k = if t_next[0] < t_next[1] { 0 } else { 1 };
+            let next = cell[k] as isize + step[k];
+            if next < 0 || next as usize >= axes[k].2 {
+                return None;
+            }
+            cell[k] = next as usize;
+            t = t_next[k];
+            t_next[k] += t_delta[k];
+        }
+    }
+
+    fn bounding_box(&self) -> BBox {
+        self.bbox
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::SmallRng;
+
+    use crate::test_util::{ray, temp_path};
+
+    use super::*;
+
+    /// Nearest hit over the triangles of every cell.
+    fn brute_force_hit(field: &Heightfield, ray: &Ray) -> Option<f64> {
+        let (nx, nz) = field.resolution;
+        (0..nz - 1)
+            .flat_map(|j| (0..nx - 1).map(move |i| (i, j)))
+            .filter_map(|(i, j)| field.hit_cell(ray, i, j, 0.001, f64::INFINITY).map(|rec| rec.t))
+            .reduce(f64::min)
+    }
+
+    #[test]
+    fn flat_field_is_hit_from_above() {
+        let rng = &mut SmallRng::seed_from_u64(24);
+        let field = Heightfield::new(Point3::new(1.0, 0.5, 1.0), (4.0, 2.0), (5, 3), vec![0.0; 15], Material::BlackBody);
+
+        let rec = field.hit_by(rng, &ray(Point3::new(2.0, 5.5, 2.5), Vec3::new(0.0, -1.0, 0.0)), 0.001, f64::INFINITY).unwrap();
+        assert!((rec.t - 5.0).abs() < 1e-9);
+        assert!(rec.front_face);
+        assert!((rec.normal - Vec3::new(0.0, 1.0, 0.0)).length() < 1e-9);
+        assert!((rec.u - 0.25).abs() < 1e-9 && (rec.v - 0.75).abs() < 1e-9);
+
+        let slanted = field.hit_by(rng, &ray(Point3::new(0.0, 1.5, 0.0), Vec3::new(2.0, -1.0, 1.5)), 0.001, f64::INFINITY).unwrap();
+        assert!((slanted.t - 1.0).abs() < 1e-9);
+
+        assert!(field.hit_by(rng, &ray(Point3::new(6.0, 5.5, 2.5), Vec3::new(0.0, -1.0, 0.0)), 0.001, f64::INFINITY).is_none());
+        assert!(field.hit_by(rng, &ray(Point3::new(2.0, 5.5, 2.5), Vec3::new(0.0, -1.0, 0.0)), 0.001, 4.0).is_none());
+        assert!(field.hit_by(rng, &ray(Point3::new(2.0, 5.5, 2.5), Vec3::new(1.0, 0.0, 0.0)), 0.001, f64::INFINITY).is_none());
+    }
+
+    #[test]
+    fn grid_walk_matches_brute_force() {
+        let rng = &mut SmallRng::seed_from_u64(24);
+        let (nx, nz) = (17, 11);
+        let heights = (0..nx * nz).map(|_| rng.gen_range(0.0..2.0)).collect();
+        let field = Heightfield::new(Point3::new(-4.0, -1.0, -3.0), (8.0, 6.0), (nx, nz), heights, Material::BlackBody);
+
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let orig = Point3::new(rng.gen_range(-8.0..8.0), rng.gen_range(-2.0..4.0), rng.gen_range(-8.0..8.0));
+            let target = Point3::new(rng.gen_range(-4.0..4.0), rng.gen_range(-1.0..1.0), rng.gen_range(-3.0..3.0));
+            let ray = ray(orig, target - orig);
+            let expected = brute_force_hit(&field, &ray);
+            let actual = field.hit_by(rng, &ray, 0.001, f64::INFINITY).map(|rec| rec.t);
+            match (actual, expected) {
+                (Some(actual), Some(expected)) => assert!((actual - expected).abs() < 1e-9, "{} isn't {}", actual, expected),
+                _ => assert_eq!(actual, expected),
+            }
+            hits += actual.is_some() as usize;
+        }
+        assert!(hits > 500);
+
+        // Axis-aligned rays walk along a single row or column of cells.
+        for &dir in [Vec3::new(1.0, -0.5, 0.0), Vec3::new(0.0, -0.5, -1.0), Vec3::new(0.0, -1.0, 0.0)].iter() {
+            let ray = ray(Point3::new(0.3, -1.0, 0.2) - 20.0 * dir, dir);
+            let expected = brute_force_hit(&field, &ray);
+            assert!(expected.is_some());
+            let actual = field.hit_by(rng, &ray, 0.001, f64::INFINITY).map(|rec| rec.t);
+            assert!((actual.unwrap() - expected.unwrap()).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn heights_are_loaded_from_images() {
+        let path = temp_path("heightfield.png");
+        image::ImageBuffer::<image::Luma<u16>, _>::from_raw(2, 2, vec![0, 65535, 32768, 0]).unwrap().save(&path).unwrap();
+        let field = Heightfield::load(path.to_str().unwrap(), Point3::new(0.0, 0.0, 0.0), (1.0, 1.0), 2.0, Material::BlackBody);
+        std::fs::remove_file(&path).unwrap();
+
+        let field = field.unwrap();
+        assert_eq!(field.heights[1], 2.0);
+        assert!((field.heights[2] - 1.0).abs() < 1e-4);
+        assert_eq!(field.bounding_box().max[1], 2.0);
+    }
+}
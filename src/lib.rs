@@ -11,4 +11,22 @@ pub mod point3;
 pub mod scene;
 pub mod bounding_box;
 pub mod bvh;
-pub mod render;
\ No newline at end of file
+pub mod render;
+pub mod background;
+pub mod environment;
+pub mod sampling;
+pub mod light;
+pub mod texture;
+pub mod mtl;
+pub mod transform;
+pub mod group;
+pub mod bvh_cache;
+pub mod motion;
+pub mod medium;
+pub mod shapes;
+pub mod csg;
+pub mod sdf;
+pub mod heightfield;
+pub mod mesh_formats;
+#[cfg(test)]
+mod test_util;
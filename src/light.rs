@@ -0,0 +1,336 @@
+use std::f64::consts::PI;
+
+use rand::{Rng, RngCore};
+
+use crate::color::Color;
+use crate::environment::EnvironmentMap;
+use crate::geometry::{area_to_solid_angle_pdf, ArcHittable, Hittable};
+use crate::point3::Point3;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+/// Light sources that can be sampled explicitly from a shading point.
+pub enum Light {
+    Area(ArcHittable),
+    Environment(EnvironmentMap),
+    Point {
+        position: Point3,
+        intensity: Color,
+    },
+    Spot {
+        position: Point3,
+        direction: Vec3,
+        intensity: Color,
+        cos_inner: f64,
+        cos_outer: f64,
+        falloff: f64,
+    },
+    /// Light arriving from a cone of directions around `-direction`. With a zero angular
+    /// radius it's a delta light and `radiance` holds the irradiance it delivers instead.
+    Directional {
+        direction: Vec3,
+        radiance: Color,
+        cos_max: f64,
+    },
+}
+
+pub struct LightSample {
+    /// Unit direction from the shading point towards the light.
+    pub dir: Vec3,
+    pub distance: f64,
+    pub radiance: Color,
+    /// Solid angle density of the sampled direction, one for delta lights.
+    pub pdf: f64,
+    /// Delta lights can't be hit by scattered rays, so they aren't weighted with MIS.
+    pub is_delta: bool,
+}
+
+impl Light {
+    pub fn directional(direction: Vec3, irradiance: Color, angular_radius_deg: f64) -> Light {
+        let angular_radius = angular_radius_deg.to_radians();
+        let radiance = if angular_radius > 0.0 {
+            // Radiance of a uniform disk producing `irradiance` at normal incidence.
+            irradiance / (PI * angular_radius.sin().powi(2))
+        } else {
+            irradiance
+        };
+
+        Light::Directional {
+            direction: direction.normalize(),
+            radiance,
+            cos_max: angular_radius.cos(),
+        }
+    }
+
+    /// Samples a direction towards the light from `origin`, as seen by rays at `time`.
+    pub fn sample(&self, rng: &mut dyn RngCore, origin: Point3, time: f64) -> Option<LightSample> {
+        match self {
+            Light::Area(shape) => {
+                let surface_sample = shape.sample_surface(rng)?;
+                let dir = (surface_sample.point - origin).normalize();
+
+                // The sampled point only contributes if it's the first point of the light
+                // seen from the origin, which also gives us the material to take emission from.
+                let rec = shape.hit_by(rng, &Ray { orig: origin, dir, time }, 0.001, f64::INFINITY)?;
+                let distance = (surface_sample.point - origin).length();
+                if rec.t < distance * (1.0 - 1e-6) {
+                    return None;
+                }
+
+                let pdf = area_to_solid_angle_pdf(
+                    surface_sample.pdf,
+                    origin,
+                    surface_sample.point,
+                    surface_sample.normal,
+                );
+
+                Some(LightSample {
+                    dir,
+                    distance: rec.t,
+                    radiance: rec.material.emitted(&rec),
+                    pdf,
+                    is_delta: false,
+                })
+            }
+
+            Light::Environment(map) => {
+                let (dir, pdf) = map.sample(rng)?;
+                Some(LightSample {
+                    dir,
+                    distance: f64::INFINITY,
+                    radiance: map.radiance(dir),
+                    pdf,
+                    is_delta: false,
+                })
+            }
+
+            Light::Point { position, intensity } => {
+                let to_light = *position - origin;
+                let distance = to_light.length();
+                Some(LightSample {
+                    dir: to_light / distance,
+                    distance,
+                    radiance: *intensity / (distance * distance),
+                    pdf: 1.0,
+                    is_delta: true,
+                })
+            }
+
+            Light::Spot { position, direction, intensity, cos_inner, cos_outer, falloff } => {
+                let to_light = *position - origin;
+                let distance = to_light.length();
+                let dir = to_light / distance;
+
+                let cos_theta = -dir.dot(*direction);
+                let attenuation = if cos_theta >= *cos_inner {
+                    1.0
+                } else if cos_theta <= *cos_outer {
+                    return None;
+                } else {
+                    ((cos_theta - cos_outer) / (cos_inner - cos_outer)).powf(*falloff)
+                };
+
+                Some(LightSample {
+                    dir,
+                    distance,
+                    radiance: *intensity * attenuation / (distance * distance),
+                    pdf: 1.0,
+                    is_delta: true,
+                })
+            }
+
+            Light::Directional { direction, radiance, cos_max } => {
+                let axis = -*direction;
+                if *cos_max >= 1.0 {
+                    return Some(LightSample {
+                        dir: axis,
+                        distance: f64::INFINITY,
+                        radiance: *radiance,
+                        pdf: 1.0,
+                        is_delta: true,
+                    });
+                }
+
+                let cos_theta = 1.0 - rng.gen::<f64>() * (1.0 - cos_max);
+                let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+                let phi = 2.0 * PI * rng.gen::<f64>();
+                let (t, b) = axis.orthonormal_basis();
+                let dir = cos_theta * axis + sin_theta * (phi.cos() * t + phi.sin() * b);
+
+                Some(LightSample {
+                    dir,
+                    distance: f64::INFINITY,
+                    radiance: *radiance,
+                    pdf: 1.0 / (2.0 * PI * (1.0 - cos_max)),
+                    is_delta: false,
+                })
+            }
+        }
+    }
+
+    /// Solid angle density of sampling `dir` from `origin` at `time`. Area lights only account
+    /// for rays that hit them, lights at infinity only for rays that escape the scene.
+    pub fn pdf(&self, rng: &mut dyn RngCore, origin: Point3, dir: Vec3, time: f64) -> f64 {
+        match self {
+            Light::Area(shape) => shape.pdf_value(rng, origin, dir, time),
+            Light::Environment(map) => map.pdf(dir),
+            Light::Directional { direction, cos_max, .. } if in_cone(dir, *direction, *cos_max) =>
+                1.0 / (2.0 * PI * (1.0 - cos_max)),
+            _ => 0.0
+        }
+    }
+
+    /// Radiance carried by a ray escaping the scene in direction `dir` that isn't already
+    /// part of the background.
+    pub fn escaped_radiance(&self, dir: Vec3) -> Color {
+        match self {
+            Light::Directional { direction, radiance, cos_max } if in_cone(dir, *direction, *cos_max) =>
+                *radiance,
+            _ => Color::new(0.0, 0.0, 0.0)
+        }
+    }
+
+    /// Whether `ray` first reaches this light at distance `t`, so that a surface found there
+    /// is the light and not something in front of or behind it.
+    pub fn is_hit_at(&self, rng: &mut dyn RngCore, ray: &Ray, t: f64) -> bool {
+        match self {
+            Light::Area(shape) => match shape.hit_by(rng, ray, 0.001, f64::INFINITY) {
+                Some(rec) => (rec.t - t).abs() <= 1e-6 * t.max(1.0),
+                None => false
+            },
+            _ => false
+        }
+    }
+}
+
+/// Whether `dir` points into the finite cone a directional light arrives from.
+fn in_cone(dir: Vec3, direction: Vec3, cos_max: f64) -> bool {
+    cos_max < 1.0 && -dir.normalize().dot(direction) >= cos_max
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+    use std::sync::Arc;
+
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    use crate::color::Color;
+    use crate::geometry::{Sphere, TriangleMesh};
+    use crate::material::Material;
+    use crate::test_util::assert_close;
+
+    use super::*;
+
+    fn emitter() -> Material {
+        serde_yaml::from_str("type: DiffuseLight\nemit: [1.0, 1.0, 1.0]").unwrap()
+    }
+
+    #[test]
+    fn sphere_samples_match_their_pdf_and_cover_its_solid_angle() {
+        let rng = &mut SmallRng::seed_from_u64(4);
+        let light = Light::Area(Arc::new(Sphere { center: Point3::new(0.0, 0.0, -3.0), radius: 1.0, material: emitter() }));
+        let origin = Point3::new(0.0, 0.0, 0.0);
+
+        let n = 100_000;
+        let mut solid_angle = 0.0;
+        for _ in 0..n {
+            if let Some(sample) = light.sample(rng, origin, 0.0) {
+                assert_close(sample.pdf, light.pdf(rng, origin, sample.dir, 0.0), 1e-6 * sample.pdf);
+                assert_eq!(sample.radiance.r, 1.0);
+                solid_angle += 1.0 / sample.pdf / n as f64;
+            }
+        }
+
+        let expected = 2.0 * PI * (1.0 - (1.0 - 1.0 / 9.0f64).sqrt());
+        assert_close(solid_angle, expected, 0.02 * expected);
+    }
+
+    #[test]
+    fn mesh_samples_match_the_uniform_area_pdf() {
+        let rng = &mut SmallRng::seed_from_u64(5);
+        let vertices = vec![
+            Point3::new(-1.0, -1.0, -1.5),
+            Point3::new(3.0, -1.0, -3.5),
+            Point3::new(3.0, 1.0, -3.5),
+            Point3::new(0.0, 1.0, -2.0),
+        ];
+        // Two triangles of different areas, so the mesh has to pick them by area.
+        let mesh = TriangleMesh::new(&vertices, &vec![0, 1, 2, 0, 2, 3], emitter());
+        let light = Light::Area(Arc::new(mesh));
+        let origin = Point3::new(0.0, 0.0, 0.0);
+
+        for _ in 0..1000 {
+            let sample = light.sample(rng, origin, 0.0).unwrap();
+            assert_close(sample.pdf, light.pdf(rng, origin, sample.dir, 0.0), 1e-6 * sample.pdf);
+        }
+    }
+
+    #[test]
+    fn area_lights_have_no_density_where_they_are_missed() {
+        let rng = &mut SmallRng::seed_from_u64(0);
+        let light = Light::Area(Arc::new(Sphere { center: Point3::new(0.0, 0.0, -3.0), radius: 1.0, material: emitter() }));
+        assert_eq!(light.pdf(rng, Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0), 0.0), 0.0);
+    }
+
+    #[test]
+    fn point_lights_fall_off_with_the_squared_distance() {
+        let rng = &mut SmallRng::seed_from_u64(6);
+        let light = Light::Point { position: Point3::new(0.0, 2.0, 0.0), intensity: Color::new(8.0, 8.0, 8.0) };
+        let sample = light.sample(rng, Point3::new(0.0, 0.0, 0.0), 0.0).unwrap();
+        assert_eq!((sample.dir[1], sample.distance, sample.radiance.r), (1.0, 2.0, 2.0));
+        assert!(sample.is_delta);
+        assert_eq!(light.pdf(rng, Point3::new(0.0, 0.0, 0.0), sample.dir, 0.0), 0.0);
+    }
+
+    #[test]
+    fn spot_lights_fade_between_their_cone_angles() {
+        let rng = &mut SmallRng::seed_from_u64(7);
+        let light = Light::Spot {
+            position: Point3::new(0.0, 1.0, 0.0),
+            direction: Vec3::new(0.0, -1.0, 0.0),
+            intensity: Color::new(1.0, 1.0, 1.0),
+            cos_inner: 0.9,
+            cos_outer: 0.5,
+            falloff: 1.0,
+        };
+        let radiance_at = |rng: &mut SmallRng, cos_theta: f64| {
+            let x = (1.0 - cos_theta * cos_theta).sqrt() / cos_theta;
+            let origin = Point3::new(x, 0.0, 0.0);
+            let distance2 = x * x + 1.0;
+            light.sample(rng, origin, 0.0).map(|sample| sample.radiance.r * distance2)
+        };
+
+        assert_close(radiance_at(rng, 0.95).unwrap(), 1.0, 1e-9);
+        assert_close(radiance_at(rng, 0.7).unwrap(), 0.5, 1e-9);
+        assert!(radiance_at(rng, 0.4).is_none());
+    }
+
+    #[test]
+    fn directional_lights_deliver_their_irradiance() {
+        let rng = &mut SmallRng::seed_from_u64(8);
+        let origin = Point3::new(0.0, 0.0, 0.0);
+        let irradiance = Color::new(3.0, 3.0, 3.0);
+        let down = Vec3::new(0.0, -1.0, 0.0);
+
+        let delta = Light::directional(down, irradiance, 0.0);
+        let sample = delta.sample(rng, origin, 0.0).unwrap();
+        assert!(sample.is_delta);
+        assert_eq!((sample.dir[1], sample.radiance.r), (1.0, 3.0));
+
+        let sun = Light::directional(down, irradiance, 5.0);
+        let n = 10_000;
+        let mut received = 0.0;
+        for _ in 0..n {
+            let sample = sun.sample(rng, origin, 0.0).unwrap();
+            assert!(!sample.is_delta);
+            assert_close(sample.pdf, sun.pdf(rng, origin, sample.dir, 0.0), 1e-9 * sample.pdf);
+            assert_close(sample.radiance.r, sun.escaped_radiance(sample.dir).r, 1e-9);
+            received += sample.radiance.r * sample.dir[1] / sample.pdf / n as f64;
+        }
+
+        assert_close(received, 3.0, 0.01);
+        assert_eq!(sun.pdf(rng, origin, Vec3::new(1.0, 0.0, 0.0), 0.0), 0.0);
+    }
+}
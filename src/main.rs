@@ -1,7 +1,10 @@
 use std::error::Error;
+use std::time::Instant;
 
 use clap::Clap;
 
+use rust_ray_tracer::bvh::{BVHBuilder, DEFAULT_SAH_BUCKETS, DEFAULT_SAH_MAX_LEAF_SIZE};
+use rust_ray_tracer::bvh_cache::BVHCache;
 use rust_ray_tracer::render::render_scene;
 use rust_ray_tracer::scene;
 
@@ -15,11 +18,78 @@ struct Opts {
 
     #[clap(long)]
     no_bvh: bool,
+
+    /// Overrides the BVH builder of the scene file
+    #[clap(long, possible_values = &["median", "sah"])]
+    bvh: Option<String>,
+
+    /// Number of buckets of the SAH builder
+    #[clap(long)]
+    bvh_buckets: Option<usize>,
+
+    /// Maximum number of shapes in a leaf of the SAH builder
+    #[clap(long)]
+    bvh_leaf_size: Option<usize>,
+
+    /// Neither reads nor writes the BVH caches stored next to OBJ files
+    #[clap(long)]
+    no_bvh_cache: bool,
+
+    /// Rebuilds the BVH caches stored next to OBJ files
+    #[clap(long)]
+    rebuild_bvh_cache: bool,
+
+    /// Prints how long loading, BVH construction and rendering took
+    #[clap(long)]
+    timings: bool,
+}
+
+impl Opts {
+    fn bvh_builder(&self) -> Option<BVHBuilder> {
+        let sah = || BVHBuilder::Sah {
+            buckets: self.bvh_buckets.unwrap_or(DEFAULT_SAH_BUCKETS),
+            max_leaf_size: self.bvh_leaf_size.unwrap_or(DEFAULT_SAH_MAX_LEAF_SIZE),
+        };
+
+        match self.bvh.as_deref() {
+            Some("median") => Some(BVHBuilder::Median),
+            Some(_) => Some(sah()),
+            None if self.bvh_buckets.is_some() || self.bvh_leaf_size.is_some() => Some(sah()),
+            None => None
+        }
+    }
+
+    fn bvh_cache(&self) -> BVHCache {
+        if self.no_bvh_cache {
+            BVHCache::Disabled
+        } else if self.rebuild_bvh_cache {
+            BVHCache::Rebuild
+        } else {
+            BVHCache::Enabled
+        }
+    }
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let opts: Opts = Opts::parse();
-    let scene = scene::read_scene(&opts.scene_file)?;
-    render_scene(&scene, &opts.output_file, opts.parallel, !opts.no_bvh);
+    let start = Instant::now();
+    let scene = scene::read_scene(&opts.scene_file, opts.bvh_builder(), opts.bvh_cache())?;
+    let load = start.elapsed();
+    for warning in scene.warnings.iter() {
+        eprintln!("warning: {}", warning);
+    }
+    let timings = render_scene(&scene, &opts.output_file, opts.parallel, !opts.no_bvh);
+
+    if opts.timings {
+        let report = [
+            ("scene loading", load - scene.bvh_build_time),
+            ("object BVH build", scene.bvh_build_time),
+            ("top-level BVH build", timings.bvh_build),
+            ("render", timings.render),
+        ];
+        for (step, duration) in report.iter() {
+            eprintln!("{:<20} {:>10.3?}", step, duration);
+        }
+    }
     Ok(())
 }
@@ -3,27 +3,49 @@ use crate::geometry::HitRecord;
 use crate::vec3::Vec3;
 use rand::{RngCore, Rng};
 use crate::color::Color;
+use crate::texture::Texture;
 use serde::{Serialize, Deserialize};
+use std::f64::consts::PI;
 
 pub struct ScatteringRecord {
     pub ray: Ray,
     pub attenuation: Color,
+    pub is_specular: bool,
+    /// Solid angle density of the scattered direction, unused for specular scattering.
+    pub pdf: f64,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Material {
-    Lambertian { albedo: Color },
+    Lambertian { albedo: Texture },
     Metal {
-        albedo: Color,
+        albedo: Texture,
         fuzz: f64,
     },
     Dielectric {
         index_of_refraction: f64,
     },
+    /// Phase function of a participating medium scattering equally in every direction.
+    Isotropic { albedo: Texture },
+    /// Phase function favoring forward scattering for positive `g` and backward scattering
+    /// for negative `g`, in (-1, 1).
+    HenyeyGreenstein {
+        albedo: Texture,
+        g: f64,
+    },
+    DiffuseLight {
+        emit: Color,
+        #[serde(default = "default_intensity")]
+        intensity: f64,
+    },
     BlackBody,
 }
 
+fn default_intensity() -> f64 {
+    1.0
+}
+
 impl Material {
     pub fn scatter(&self,
                    rng: &mut dyn RngCore,
@@ -31,18 +53,75 @@ impl Material {
                    hit_record: &HitRecord
     ) -> Option<ScatteringRecord> {
         match *self {
-            Material::Lambertian { albedo } =>
-                Material::scatter_lambertian(rng, hit_record, albedo),
-            Material::Metal { albedo, fuzz } =>
-                Material::scatter_metal(rng, ray_in, hit_record, albedo, fuzz),
+            Material::Lambertian { ref albedo } =>
+                Material::scatter_lambertian(rng, ray_in, hit_record, albedo.value(hit_record.u, hit_record.v, hit_record.point)),
+            Material::Metal { ref albedo, fuzz } =>
+                Material::scatter_metal(rng, ray_in, hit_record, albedo.value(hit_record.u, hit_record.v, hit_record.point), fuzz),
             Material::Dielectric { index_of_refraction } =>
                 Material::scatter_dielectric(rng, ray_in, hit_record, index_of_refraction),
+            Material::Isotropic { ref albedo } =>
+                Material::scatter_phase(rng, ray_in, hit_record, albedo.value(hit_record.u, hit_record.v, hit_record.point), 0.0),
+            Material::HenyeyGreenstein { ref albedo, g } =>
+                Material::scatter_phase(rng, ray_in, hit_record, albedo.value(hit_record.u, hit_record.v, hit_record.point), g),
+            Material::DiffuseLight { .. } => None,
             Material::BlackBody => None
         }
     }
 
+    /// Evaluates the BSDF times the cosine term for light arriving from `dir`.
+    /// Specular materials return black since they can't be sampled explicitly.
+    pub fn eval(&self, ray_in: &Ray, hit_record: &HitRecord, dir: Vec3) -> Color {
+        // Phase functions have no cosine term and scatter to both sides of the normal.
+        if let Material::Isotropic { ref albedo } | Material::HenyeyGreenstein { ref albedo, .. } = *self {
+            let albedo = albedo.value(hit_record.u, hit_record.v, hit_record.point);
+            return albedo * self.scattering_pdf(ray_in, hit_record, dir);
+        }
+
+        if hit_record.normal.dot(dir) <= 0.0 {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+
+        match *self {
+            Material::Lambertian { ref albedo } | Material::Metal { ref albedo, .. } => {
+                let albedo = albedo.value(hit_record.u, hit_record.v, hit_record.point);
+                albedo * self.scattering_pdf(ray_in, hit_record, dir)
+            }
+            _ => Color::new(0.0, 0.0, 0.0)
+        }
+    }
+
+    /// Solid angle density of `scatter` producing direction `dir`.
+    pub fn scattering_pdf(&self, ray_in: &Ray, hit_record: &HitRecord, dir: Vec3) -> f64 {
+        match *self {
+            Material::Lambertian { .. } => {
+                let cosine = hit_record.normal.dot(dir.normalize());
+                if cosine <= 0.0 { 0.0 } else { cosine / PI }
+            }
+            Material::Metal { fuzz, .. } if fuzz > 0.0 => {
+                let reflected = ray_in.dir.reflect(hit_record.normal);
+                let length = reflected.length();
+                fuzzy_reflection_pdf(reflected.dot(dir.normalize()) / length, fuzz / length)
+            }
+            Material::Isotropic { .. } => henyey_greenstein(0.0, 0.0),
+            Material::HenyeyGreenstein { g, .. } => henyey_greenstein(ray_in.dir.normalize().dot(dir.normalize()), g),
+            _ => 0.0
+        }
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        matches!(self, Material::DiffuseLight { .. })
+    }
+
+    pub fn emitted(&self, _hit_record: &HitRecord) -> Color {
+        match *self {
+            Material::DiffuseLight { emit, intensity } => emit * intensity,
+            _ => Color::new(0.0, 0.0, 0.0)
+        }
+    }
+
     fn scatter_lambertian(
         rng: &mut dyn RngCore,
+        ray_in: &Ray,
         hit_record: &HitRecord,
         albedo: Color
     ) -> Option<ScatteringRecord> {
@@ -52,10 +131,13 @@ impl Material {
             target = hit_record.normal;
         }
 
-        let scattered_ray = Ray { orig: hit_record.point, dir: target };
+        let pdf = hit_record.normal.dot(target.normalize()).max(0.0) / PI;
+        let scattered_ray = Ray { orig: hit_record.point, dir: target, time: ray_in.time };
         return Some(ScatteringRecord {
             ray: scattered_ray,
             attenuation: albedo,
+            is_specular: false,
+            pdf,
         });
     }
 
@@ -66,11 +148,22 @@ impl Material {
         albedo: Color,
         fuzz: f64
     ) -> Option<ScatteringRecord> {
-        let dir = ray_in.dir.reflect(hit_record.normal) + fuzz * Vec3::random_in_unit_sphere(rng);
-        let scattered_ray = Ray { orig: hit_record.point, dir };
+        let reflected = ray_in.dir.reflect(hit_record.normal);
+        let dir = reflected + fuzz * Vec3::random_in_unit_sphere(rng);
+        // Fuzz pushing the reflection below the surface absorbs it, as `eval` has no
+        // reflectance there.
+        if dir.dot(hit_record.normal) <= 0.0 {
+            return None;
+        }
+
+        // The incoming direction isn't normalized, and the fuzz is relative to its length.
+        let length = reflected.length();
+        let scattered_ray = Ray { orig: hit_record.point, dir, time: ray_in.time };
         return Some(ScatteringRecord {
             ray: scattered_ray,
             attenuation: albedo,
+            is_specular: fuzz <= 0.0,
+            pdf: fuzzy_reflection_pdf(reflected.dot(dir.normalize()) / length, fuzz / length),
         });
     }
 
@@ -101,12 +194,71 @@ impl Material {
             Vec3::refract(unit_direction, hit_record.normal, refraction_ratio)
         };
 
-        let scattered_ray = Ray { orig: hit_record.point, dir };
+        let scattered_ray = Ray { orig: hit_record.point, dir, time: ray_in.time };
         return Some(ScatteringRecord {
             ray: scattered_ray,
             attenuation: Color::new(1.0, 1.0, 1.0),
+            is_specular: true,
+            pdf: 0.0,
         });
     }
+
+    fn scatter_phase(
+        rng: &mut dyn RngCore,
+        ray_in: &Ray,
+        hit_record: &HitRecord,
+        albedo: Color,
+        g: f64
+    ) -> Option<ScatteringRecord> {
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * rng.gen::<f64>()
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * rng.gen::<f64>());
+            ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * rng.gen::<f64>();
+
+        let forward = ray_in.dir.normalize();
+        let (u, v) = forward.orthonormal_basis();
+        let dir = sin_theta * phi.cos() * u + sin_theta * phi.sin() * v + cos_theta * forward;
+        Some(ScatteringRecord {
+            ray: Ray { orig: hit_record.point, dir, time: ray_in.time },
+            attenuation: albedo,
+            is_specular: false,
+            pdf: henyey_greenstein(cos_theta, g),
+        })
+    }
+}
+
+/// Henyey-Greenstein phase function, for the angle `acos(cos_theta)` between the direction
+/// of propagation and the scattered direction. Uniform over the sphere when `g` is zero.
+fn henyey_greenstein(cos_theta: f64, g: f64) -> f64 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * PI * denom * denom.sqrt())
+}
+
+/// Density of the direction of `r + fuzz * u`, where `r` is the unit mirror direction and
+/// `u` is uniform in the unit ball, at a direction making angle `acos(cos_theta)` with `r`.
+/// It integrates `t^2` along the chord of the fuzz ball that the direction passes through.
+fn fuzzy_reflection_pdf(cos_theta: f64, fuzz: f64) -> f64 {
+    if fuzz <= 0.0 {
+        return 0.0;
+    }
+
+    let discr = fuzz * fuzz - (1.0 - cos_theta * cos_theta);
+    if discr < 0.0 {
+        return 0.0;
+    }
+
+    let sqrt_discr = discr.sqrt();
+    let t_far = cos_theta + sqrt_discr;
+    let t_near = (cos_theta - sqrt_discr).max(0.0);
+    if t_far <= 0.0 {
+        return 0.0;
+    }
+
+    (t_far.powi(3) - t_near.powi(3)) / (4.0 * PI * fuzz.powi(3))
 }
 
 fn reflectance(cosine: f64, ref_idx: f64) -> f64 {
@@ -114,3 +266,88 @@ fn reflectance(cosine: f64, ref_idx: f64) -> f64 {
     let r0 = ((1.0 - ref_idx) / (1.0 + ref_idx)).powi(2);
     return r0 + (1.0 - r0) * (1.0 - cosine).powi(5);
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    use crate::point3::Point3;
+    use crate::test_util::{assert_close, ray};
+
+    use super::*;
+
+    fn hit<'a>(ray_in: &Ray, material: &'a Material) -> HitRecord<'a> {
+        HitRecord::create(ray_in, Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 1.0, (0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), material)
+    }
+
+    fn gray(value: f64) -> Texture {
+        Texture::Constant(Color::new(value, value, value))
+    }
+
+    /// Integral of `fuzzy_reflection_pdf` over the directions within `acos(cos_min)` of the
+    /// mirror direction.
+    fn fuzzy_reflection_probability(cos_min: f64, fuzz: f64) -> f64 {
+        let steps = 100_000;
+        let dc = (1.0 - cos_min) / steps as f64;
+        (0..steps)
+            .map(|i| 2.0 * PI * fuzzy_reflection_pdf(cos_min + (i as f64 + 0.5) * dc, fuzz) * dc)
+            .sum()
+    }
+
+    #[test]
+    fn fuzzy_reflection_pdf_integrates_to_one() {
+        for &fuzz in &[0.1, 0.5, 0.9] {
+            assert_close(fuzzy_reflection_probability(-1.0, fuzz), 1.0, 1e-3);
+        }
+    }
+
+    #[test]
+    fn scattered_directions_have_the_pdf_of_scattering_pdf() {
+        let rng = &mut SmallRng::seed_from_u64(6);
+        // Not normalized, as camera rays aren't.
+        let ray_in = ray(Point3::new(-1.0, 1.0, 0.0), Vec3::new(1.5, -2.0, 0.5));
+        let materials = [
+            Material::Lambertian { albedo: gray(0.5) },
+            Material::Metal { albedo: gray(0.5), fuzz: 0.3 },
+        ];
+
+        for material in &materials {
+            let rec = hit(&ray_in, material);
+            for _ in 0..1000 {
+                if let Some(scatter_rec) = material.scatter(rng, &ray_in, &rec) {
+                    let pdf = material.scattering_pdf(&ray_in, &rec, scatter_rec.ray.dir);
+                    assert_close(scatter_rec.pdf, pdf, 1e-9 * pdf);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fuzzy_metal_samples_follow_their_pdf() {
+        let rng = &mut SmallRng::seed_from_u64(7);
+        let fuzz = 0.5;
+        let ray_in = ray(Point3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let material = Material::Metal { albedo: gray(0.5), fuzz };
+        let rec = hit(&ray_in, &material);
+
+        let n = 100_000;
+        let cos_min = 0.95;
+        let within = (0..n)
+            .filter_map(|_| material.scatter(rng, &ray_in, &rec))
+            .filter(|scatter_rec| scatter_rec.ray.dir.normalize()[1] >= cos_min)
+            .count();
+        let expected = fuzzy_reflection_probability(cos_min, fuzz);
+        assert_close(within as f64 / n as f64, expected, 0.01);
+    }
+
+    #[test]
+    fn specular_materials_have_no_reflectance_to_sample() {
+        let ray_in = ray(Point3::new(-1.0, 1.0, 0.0), Vec3::new(1.0, -1.0, 0.0));
+        let mirror = Material::Metal { albedo: gray(1.0), fuzz: 0.0 };
+        let rec = hit(&ray_in, &mirror);
+        let mirrored = Vec3::new(1.0, 1.0, 0.0);
+        assert!(mirror.eval(&ray_in, &rec, mirrored).is_black());
+        assert_eq!(mirror.scattering_pdf(&ray_in, &rec, mirrored), 0.0);
+    }
+}
@@ -0,0 +1,439 @@
+use std::error::Error;
+use std::fs;
+
+use rand::{Rng, RngCore};
+
+use crate::bounding_box::BBox;
+use crate::geometry::{HitRecord, Hittable};
+use crate::material::Material;
+use crate::point3::Point3;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+/// Participating medium of uniform density filling a closed `boundary`. Rays scatter at a
+/// distance drawn from the exponential distribution of free flights, and the returned
+/// record carries `phase_function`, which should be `Isotropic` or `HenyeyGreenstein`.
+pub struct ConstantMedium<T: Hittable> {
+    boundary: T,
+    density: f64,
+    phase_function: Material,
+}
+
+impl<T: Hittable> ConstantMedium<T> {
+    pub fn new(boundary: T, density: f64, phase_function: Material) -> ConstantMedium<T> {
+        ConstantMedium { boundary, density, phase_function }
+    }
+
+    /// Walks the boundary crossings from `t_min`, calling `visit` with every interval spent
+    /// inside until it returns something. Whether the ray starts inside is told by the side
+    /// of the first crossing, so the boundary doesn't need to be convex. `visit` gets `rng` back
+    /// along with the interval.
+    fn find_inside<R>(
+        &self,
+        rng: &mut dyn RngCore,
+        ray: &Ray,
+        t_min: f64,
+        t_max: f64,
+        mut visit: impl FnMut(&mut dyn RngCore, f64, f64) -> Option<R>,
+    ) -> Option<R> {
+        let mut t = t_min;
+        let mut crossing = self.boundary.hit_by(rng, ray, t_min, f64::INFINITY);
+        let mut inside = match crossing {
+            Some(ref rec) => !rec.front_face,
+            None => false
+        };
+        loop {
+            let end = crossing.as_ref().map_or(f64::INFINITY, |rec| rec.t).min(t_max);
+            if inside {
+                if let Some(result) = visit(rng, t, end) {
+                    return Some(result);
+                }
+            }
+
+            match crossing {
+                Some(rec) if rec.t < t_max => {
+                    t = rec.t;
+                    inside = rec.front_face;
+                    crossing = self.boundary.hit_by(rng, ray, t + 1e-4, f64::INFINITY);
+                }
+                _ => return None
+            }
+        }
+    }
+}
+
+impl<T: Hittable> Hittable for ConstantMedium<T> {
+    fn hit_by(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        if self.density <= 0.0 {
+            return None;
+        }
+
+        let ray_length = ray.dir.length();
+        let t_hit = self.find_inside(rng, ray, t_min, t_max, |rng, start, end| {
+            let t_hit = start + free_flight(rng, self.density) / ray_length;
+            if t_hit < end { Some(t_hit) } else { None }
+        })?;
+        Some(medium_record(ray, t_hit, &self.phase_function))
+    }
+
+    fn bounding_box(&self) -> BBox {
+        self.boundary.bounding_box()
+    }
+
+    fn is_bounded(&self) -> bool {
+        self.boundary.is_bounded()
+    }
+
+    fn transmittance(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> f64 {
+        let mut length = 0.0;
+        self.find_inside::<()>(rng, ray, t_min, t_max, |_, start, end| {
+            length += end - start;
+            None
+        });
+        (-self.density * length * ray.dir.length()).exp()
+    }
+}
+
+/// Densities on a regular grid spanning `bounds`, with x varying fastest. Values sit at the
+/// centers of the cells and are interpolated trilinearly in between.
+pub struct VoxelGrid {
+    pub dims: [usize; 3],
+    pub bounds: BBox,
+    pub densities: Vec<f64>,
+}
+
+impl VoxelGrid {
+    /// Reads a grid made of a text header followed by the densities:
+    ///
+    /// ```text
+    /// # comment
+    /// dims 64 64 64
+    /// bounds -1 -1 -1 1 1 1
+    /// data ascii
+    /// 0.0 0.12 ...
+    /// ```
+    ///
+    /// With `data raw` instead, the line is followed by little-endian 32-bit floats.
+    pub fn load(filename: &str) -> Result<VoxelGrid, Box<dyn Error>> {
+        let contents = fs::read(filename)?;
+        let mut dims = None;
+        let mut bounds = None;
+        let mut rest = contents.as_slice();
+        while !rest.is_empty() {
+            let line_end = rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len());
+            let line = std::str::from_utf8(&rest[..line_end])?.trim();
+            rest = &rest[(line_end + 1).min(rest.len())..];
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let args = line.split_whitespace().collect::<Vec<_>>();
+            match args.as_slice() {
+                ["dims", x, y, z] => dims = Some([x.parse()?, y.parse()?, z.parse()?]),
+                ["bounds", x0, y0, z0, x1, y1, z1] => bounds = Some(BBox {
+                    min: Point3::new(x0.parse()?, y0.parse()?, z0.parse()?),
+                    max: Point3::new(x1.parse()?, y1.parse()?, z1.parse()?),
+                }),
+                ["data", format] => {
+                    let dims: [usize; 3] = dims.ok_or("dims must come before data")?;
+                    let bounds = bounds.ok_or("bounds must come before data")?;
+                    let densities = match *format {
+                        "ascii" => std::str::from_utf8(rest)?
+                            .split_whitespace()
+                            .map(|value| value.parse::<f64>())
+                            .collect::<Result<Vec<_>, _>>()?,
+                        "raw" => rest.chunks_exact(4)
+                            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64)
+                            .collect(),
+                        _ => return Err(format!("unknown voxel data format {}", format).into())
+                    };
+
+                    let count = dims[0] * dims[1] * dims[2];
+                    if count == 0 || densities.len() != count {
+                        return Err(format!("expected {} densities, got {}", count, densities.len()).into());
+                    }
+                    return Ok(VoxelGrid { dims, bounds, densities });
+                }
+                _ => return Err(format!("invalid line in voxel grid: {}", line).into())
+            }
+        }
+
+        Err("voxel grid has no data".into())
+    }
+
+    pub fn max_density(&self) -> f64 {
+        self.densities.iter().cloned().fold(0.0, f64::max)
+    }
+
+    /// Trilinearly interpolated density, zero outside of the bounds.
+    pub fn density(&self, p: Point3) -> f64 {
+        let mut base = [0usize; 3];
+        let mut frac = [0.0; 3];
+        for i in 0..3 {
+            let x = (p[i] - self.bounds.min[i]) / (self.bounds.max[i] - self.bounds.min[i]);
+            if !(0.0..=1.0).contains(&x) {
+                return 0.0;
+            }
+
+            let coord = (x * self.dims[i] as f64 - 0.5).clamp(0.0, (self.dims[i] - 1) as f64);
+            base[i] = coord as usize;
+            frac[i] = coord - base[i] as f64;
+        }
+
+        let mut density = 0.0;
+        for corner in 0..8 {
+            let mut weight = 1.0;
+            let mut index = [0usize; 3];
+            for i in 0..3 {
+                let upper = (corner >> i) & 1 == 1;
+                index[i] = if upper { (base[i] + 1).min(self.dims[i] - 1) } else { base[i] };
+                weight *= if upper { frac[i] } else { 1.0 - frac[i] };
+            }
+            if weight > 0.0 {
+                density += weight * self.densities[index[0] + self.dims[0] * (index[1] + self.dims[1] * index[2])];
+            }
+        }
+        density
+    }
+}
+
+/// Heterogeneous medium with the densities of a `VoxelGrid` times `density_scale`. Scattering
+/// distances come from delta tracking and transmittance from ratio tracking, both against
+/// the maximum density of the grid.
+pub struct GridMedium {
+    grid: VoxelGrid,
+    density_scale: f64,
+    max_density: f64,
+    phase_function: Material,
+}
+
+impl GridMedium {
+    pub fn new(grid: VoxelGrid, density_scale: f64, phase_function: Material) -> GridMedium {
+        let max_density = grid.max_density() * density_scale;
+        GridMedium { grid, density_scale, max_density, phase_function }
+    }
+
+    fn density(&self, p: Point3) -> f64 {
+        self.grid.density(p) * self.density_scale
+    }
+}
+
+impl Hittable for GridMedium {
+    /// Delta tracking: tentative collisions are drawn as in a medium of the maximum density,
+    /// and are real with probability density / maximum density.
+    fn hit_by(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        if self.max_density <= 0.0 {
+            return None;
+        }
+
+        let (mut t, end) = self.grid.bounds.clip(ray, t_min, t_max)?;
+        let ray_length = ray.dir.length();
+        loop {
+            t += free_flight(rng, self.max_density) / ray_length;
+            if t >= end {
+                return None;
+            }
+            if rng.gen::<f64>() * self.max_density < self.density(ray.at(t)) {
+                return Some(medium_record(ray, t, &self.phase_function));
+            }
+        }
+    }
+
+    fn bounding_box(&self) -> BBox {
+        self.grid.bounds
+    }
+
+    /// Ratio tracking: the product over tentative collisions of the probability of each being
+    /// a null collision.
+    fn transmittance(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> f64 {
+        let (mut t, end) = match self.grid.bounds.clip(ray, t_min, t_max) {
+            Some(segment) if self.max_density > 0.0 => segment,
+            _ => return 1.0
+        };
+
+        let ray_length = ray.dir.length();
+        let mut transmittance = 1.0;
+        loop {
+            t += free_flight(rng, self.max_density) / ray_length;
+            if t >= end {
+                return transmittance;
+            }
+            transmittance *= 1.0 - self.density(ray.at(t)) / self.max_density;
+            if transmittance <= 0.0 {
+                return 0.0;
+            }
+        }
+    }
+}
+
+/// Distance to the next collision in a medium of the given density.
+fn free_flight(rng: &mut dyn RngCore, density: f64) -> f64 {
+    -(1.0 - rng.gen::<f64>()).ln() / density
+}
+
+fn medium_record<'a>(ray: &Ray, t: f64, phase_function: &'a Material) -> HitRecord<'a> {
+    // Phase functions don't depend on the normal, any will do.
+    let normal = -ray.dir.normalize();
+    HitRecord::create(ray, ray.at(t), normal, t, (0.0, 0.0), Vec3::new(0.0, 0.0, 0.0), phase_function)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::SmallRng;
+
+    use crate::color::Color;
+    use crate::geometry::Sphere;
+    use crate::test_util::{assert_close, ray, temp_path};
+    use crate::texture::Texture;
+
+    use super::*;
+
+    fn isotropic() -> Material {
+        Material::Isotropic { albedo: Texture::Constant(Color::new(1.0, 1.0, 1.0)) }
+    }
+
+    fn fog_ball(density: f64) -> ConstantMedium<Sphere> {
+        let boundary = Sphere { center: Point3::new(0.0, 0.0, 0.0), radius: 1.0, material: Material::BlackBody };
+        ConstantMedium::new(boundary, density, isotropic())
+    }
+
+    #[test]
+    fn constant_medium_transmittance_follows_beer_lambert() {
+        let rng = &mut SmallRng::seed_from_u64(19);
+        let medium = fog_ball(0.7);
+        let through = ray(Point3::new(-5.0, 0.0, 0.0), Vec3::new(2.0, 0.0, 0.0));
+        assert_close(medium.transmittance(rng, &through, 0.0, f64::INFINITY), (-0.7 * 2.0f64).exp(), 1e-9);
+
+        // Starting inside, and stopping halfway to the boundary.
+        let inside = ray(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        assert_close(medium.transmittance(rng, &inside, 0.0, f64::INFINITY), (-0.7f64).exp(), 1e-9);
+        assert_close(medium.transmittance(rng, &inside, 0.0, 0.5), (-0.35f64).exp(), 1e-9);
+
+        let miss = ray(Point3::new(-5.0, 2.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(medium.transmittance(rng, &miss, 0.0, f64::INFINITY), 1.0);
+    }
+
+    #[test]
+    fn constant_medium_scatters_as_often_as_light_is_absorbed() {
+        let rng = &mut SmallRng::seed_from_u64(19);
+        let medium = fog_ball(0.7);
+        let through = ray(Point3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let count = 20000;
+        let hits = (0..count)
+            .filter_map(|_| medium.hit_by(rng, &through, 0.0, f64::INFINITY))
+            .inspect(|rec| assert!(rec.t >= 4.0 && rec.t <= 6.0))
+            .count();
+        assert_close(hits as f64 / count as f64, 1.0 - (-0.7 * 2.0f64).exp(), 0.01);
+    }
+
+    #[test]
+    fn constant_medium_hits_are_reproducible() {
+        let medium = fog_ball(0.7);
+        let through = ray(Point3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let hits = |seed| {
+            let rng = &mut SmallRng::seed_from_u64(seed);
+            (0..100).map(|_| medium.hit_by(rng, &through, 0.0, f64::INFINITY).map(|rec| rec.t)).collect::<Vec<_>>()
+        };
+        assert_eq!(hits(19), hits(19));
+    }
+
+    /// Two cells along x, so the density ramps from 0.5 to 1 between their centers.
+    fn ramp() -> VoxelGrid {
+        VoxelGrid {
+            dims: [2, 1, 1],
+            bounds: BBox { min: Point3::new(-1.0, -1.0, -1.0), max: Point3::new(1.0, 1.0, 1.0) },
+            densities: vec![0.5, 1.0],
+        }
+    }
+
+    fn temp_grid(name: &str, contents: &[u8]) -> String {
+        let path = temp_path(name);
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn grid_density_is_interpolated_between_cell_centers() {
+        let grid = ramp();
+        assert_close(grid.density(Point3::new(-0.9, 0.0, 0.0)), 0.5, 1e-12);
+        assert_close(grid.density(Point3::new(0.0, 0.3, -0.7)), 0.75, 1e-12);
+        assert_close(grid.density(Point3::new(0.25, 0.0, 0.0)), 0.875, 1e-12);
+        assert_close(grid.density(Point3::new(0.9, 0.0, 0.0)), 1.0, 1e-12);
+        assert_eq!(grid.density(Point3::new(1.5, 0.0, 0.0)), 0.0);
+        assert_eq!(grid.max_density(), 1.0);
+    }
+
+    #[test]
+    fn grids_load_from_ascii_and_raw_data() {
+        let ascii = temp_grid("grid_ascii", b"# ramp\ndims 2 1 1\nbounds -1 -1 -1 1 1 1\ndata ascii\n0.5 1.0\n");
+        let grid = VoxelGrid::load(&ascii).unwrap();
+        fs::remove_file(&ascii).unwrap();
+        assert_eq!(grid.dims, [2, 1, 1]);
+        assert_eq!(grid.densities, vec![0.5, 1.0]);
+
+        let mut contents = b"dims 2 1 1\nbounds -1 -1 -1 1 1 1\ndata raw\n".to_vec();
+        contents.extend_from_slice(&0.5f32.to_le_bytes());
+        contents.extend_from_slice(&1.0f32.to_le_bytes());
+        let raw = temp_grid("grid_raw", &contents);
+        let grid = VoxelGrid::load(&raw).unwrap();
+        fs::remove_file(&raw).unwrap();
+        assert_eq!(grid.densities, vec![0.5, 1.0]);
+        assert_eq!(grid.bounds.max[0], 1.0);
+    }
+
+    #[test]
+    fn malformed_grids_are_rejected() {
+        let cases: [(&str, &[u8]); 4] = [
+            ("grid_count", b"dims 2 1 1\nbounds -1 -1 -1 1 1 1\ndata ascii\n0.5\n"),
+            ("grid_order", b"bounds -1 -1 -1 1 1 1\ndata ascii\n0.5 1.0\n"),
+            ("grid_format", b"dims 2 1 1\nbounds -1 -1 -1 1 1 1\ndata hex\n"),
+            ("grid_no_data", b"dims 2 1 1\nbounds -1 -1 -1 1 1 1\n"),
+        ];
+        for (name, contents) in cases.iter() {
+            let path = temp_grid(name, contents);
+            let result = VoxelGrid::load(&path);
+            fs::remove_file(&path).unwrap();
+            assert!(result.is_err(), "{} was accepted", name);
+        }
+    }
+
+    #[test]
+    fn grid_medium_matches_optical_depth() {
+        let rng = &mut SmallRng::seed_from_u64(20);
+        let medium = GridMedium::new(ramp(), 1.0, isotropic());
+        // The density integrates to 1.5 along the x axis through the bounds.
+        let through = ray(Point3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let expected = (-1.5f64).exp();
+
+        let count = 20000;
+        let mean = (0..count).map(|_| medium.transmittance(rng, &through, 0.0, f64::INFINITY)).sum::<f64>() / count as f64;
+        assert_close(mean, expected, 0.01);
+
+        let hits = (0..count)
+            .filter_map(|_| medium.hit_by(rng, &through, 0.0, f64::INFINITY))
+            .inspect(|rec| assert!(rec.t >= 4.0 && rec.t <= 6.0))
+            .count();
+        assert_close(hits as f64 / count as f64, 1.0 - expected, 0.01);
+
+        let miss = ray(Point3::new(-5.0, 2.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(medium.transmittance(rng, &miss, 0.0, f64::INFINITY), 1.0);
+        assert!(medium.hit_by(rng, &miss, 0.0, f64::INFINITY).is_none());
+    }
+
+    #[test]
+    fn grid_medium_is_reproducible() {
+        let medium = GridMedium::new(ramp(), 1.0, isotropic());
+        let through = ray(Point3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let samples = |seed| {
+            let rng = &mut SmallRng::seed_from_u64(seed);
+            (0..100)
+                .map(|_| (
+                    medium.hit_by(rng, &through, 0.0, f64::INFINITY).map(|rec| rec.t),
+                    medium.transmittance(rng, &through, 0.0, f64::INFINITY),
+                ))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(samples(20), samples(20));
+    }
+}
@@ -0,0 +1,523 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+
+use crate::color::Color;
+use crate::geometry::{smooth_normals, Triangle};
+use crate::material::Material;
+use crate::point3::Point3;
+use crate::texture::Texture;
+use crate::vec3::Vec3;
+
+/// Loads the triangles of an ASCII or binary PLY file, fanning out polygons into triangles.
+/// Vertex normals, texture coordinates (`u`/`v`, `s`/`t` or `texture_u`/`texture_v`) and
+/// colors are used when present. Colors replace the albedo of a `Lambertian` material with
+/// the average color of each face. Without normals, they're generated as for OBJ files.
+pub fn read_ply(filename: &str, material: &Material, smoothing_angle_deg: f64) -> Result<Vec<Triangle>, Box<dyn Error>> {
+    let contents = fs::read(filename)?;
+    let (header, body) = split_ply_header(&contents)?;
+    let mut values = match header.format.as_str() {
+        "ascii" => PlyValues::Ascii(std::str::from_utf8(body)?.split_ascii_whitespace()),
+        "binary_little_endian" => PlyValues::Binary { data: body, big_endian: false },
+        "binary_big_endian" => PlyValues::Binary { data: body, big_endian: true },
+        format => return Err(format!("unknown PLY format {}", format).into())
+    };
+
+    let mut positions = vec![];
+    let mut normals = vec![];
+    let mut uvs = vec![];
+    let mut colors = vec![];
+    let mut corners = vec![];
+    let mut face_count = 0;
+    for element in header.elements.iter() {
+        let index_of = |names: &[&str]| element.properties.iter().position(|p| names.contains(&p.name.as_str()));
+        let position_index = [index_of(&["x"]), index_of(&["y"]), index_of(&["z"])];
+        let normal_index = [index_of(&["nx"]), index_of(&["ny"]), index_of(&["nz"])];
+        let uv_index = [index_of(&["u", "s", "texture_u"]), index_of(&["v", "t", "texture_v"])];
+        let color_index = [index_of(&["red"]), index_of(&["green"]), index_of(&["blue"])];
+        let indices_index = index_of(&["vertex_indices", "vertex_index"]);
+
+        let mut scalars = vec![0.0; element.properties.len()];
+        for _ in 0..element.count {
+            let mut list = vec![];
+            for (i, property) in element.properties.iter().enumerate() {
+                match property.list_count {
+                    None => scalars[i] = values.read(property.ty)?,
+                    Some(count_ty) => {
+                        let count = values.read(count_ty)? as usize;
+                        let items = (0..count).map(|_| values.read(property.ty)).collect::<Result<Vec<_>, _>>()?;
+                        if Some(i) == indices_index {
+                            list = items;
+                        }
+                    }
+                }
+            }
+
+            match element.name.as_str() {
+                "vertex" => {
+                    let [x, y, z] = position_index;
+                    match (x, y, z) {
+                        (Some(x), Some(y), Some(z)) => positions.push(Point3::new(scalars[x], scalars[y], scalars[z])),
+                        _ => return Err("PLY vertices have no x, y and z".into())
+                    }
+                    if let [Some(x), Some(y), Some(z)] = normal_index {
+                        normals.push(Vec3::new(scalars[x], scalars[y], scalars[z]).normalize());
+                    }
+                    if let [Some(u), Some(v)] = uv_index {
+                        uvs.push((scalars[u], scalars[v]));
+                    }
+                    if let [Some(r), Some(g), Some(b)] = color_index {
+                        let channel = |i: usize| {
+                            let ty = element.properties[i].ty;
+                            let value = if ty.is_integer() { scalars[i] / ty.max_value() } else { scalars[i] };
+                            // Colors are stored gamma encoded, like images.
+                            value * value
+                        };
+                        colors.push(Color::new(channel(r), channel(g), channel(b)));
+                    }
+                }
+                "face" => {
+                    for i in 1..list.len().saturating_sub(1) {
+                        corners.push(list[0] as usize);
+                        corners.push(list[i] as usize);
+                        corners.push(list[i + 1] as usize);
+                        face_count += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if corners.iter().any(|&corner| corner >= positions.len()) {
+        return Err("PLY face refers to a missing vertex".into());
+    }
+
+    let normals = if normals.len() == positions.len() { Some(normals.as_slice()) } else { None };
+    let uvs = if uvs.len() == positions.len() { Some(uvs.as_slice()) } else { None };
+    let face_material = |face: usize| match material {
+        Material::Lambertian { .. } if colors.len() == positions.len() => {
+            let face_corners = &corners[3 * face..3 * face + 3];
+            let color = face_corners.iter()
+                .fold(Color::new(0.0, 0.0, 0.0), |sum, &corner| sum + colors[corner]) / 3.0;
+            Material::Lambertian { albedo: Texture::Constant(color) }
+        }
+        _ => material.clone()
+    };
+    let face_materials = (0..face_count).map(face_material).collect::<Vec<_>>();
+    Ok(indexed_triangles(&positions, &corners, normals, uvs, face_materials, smoothing_angle_deg))
+}
+
+/// Loads the triangles of a binary or ASCII STL file. STL repeats the vertices of every
+/// facet, so vertices at the same position are merged before normals are generated.
+pub fn read_stl(filename: &str, material: &Material, smoothing_angle_deg: f64) -> Result<Vec<Triangle>, Box<dyn Error>> {
+    let contents = fs::read(filename)?;
+    let facet_count = contents.get(80..84).map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize);
+    // ASCII files start with "solid", but so do the headers of some binary files.
+    let is_binary = match facet_count {
+        Some(count) => contents.len() == 84 + 50 * count,
+        None => false
+    };
+
+    let mut vertices = vec![];
+    if is_binary {
+        for facet in contents[84..].chunks_exact(50) {
+            // Skips the facet normal, which is implied by the winding.
+            for vertex in facet[12..48].chunks_exact(12) {
+                let coordinate = |i: usize| f32::from_le_bytes([vertex[i], vertex[i + 1], vertex[i + 2], vertex[i + 3]]) as f64;
+                vertices.push(Point3::new(coordinate(0), coordinate(4), coordinate(8)));
+            }
+        }
+    } else {
+        let mut tokens = std::str::from_utf8(&contents)?.split_ascii_whitespace();
+        while let Some(token) = tokens.next() {
+            if token == "vertex" {
+                let mut coordinate = || -> Result<f64, Box<dyn Error>> {
+                    Ok(tokens.next().ok_or("truncated STL vertex")?.parse()?)
+                };
+                vertices.push(Point3::new(coordinate()?, coordinate()?, coordinate()?));
+            }
+        }
+        if vertices.len() % 3 != 0 {
+            return Err("STL facets must have 3 vertices".into());
+        }
+    }
+
+    let mut positions = vec![];
+    let mut position_indices = HashMap::new();
+    let corners = vertices.iter()
+        .map(|vertex| {
+            let key = [vertex[0].to_bits(), vertex[1].to_bits(), vertex[2].to_bits()];
+            *position_indices.entry(key).or_insert_with(|| {
+                positions.push(*vertex);
+                positions.len() - 1
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let face_materials = vec![material.clone(); corners.len() / 3];
+    Ok(indexed_triangles(&positions, &corners, None, None, face_materials, smoothing_angle_deg))
+}
+
+/// Builds one triangle per three `corners` indexing into `positions`, with per-vertex
+/// `normals` and `uvs` when given. Missing normals are generated unless `smoothing_angle_deg`
+/// is 0.
+fn indexed_triangles(
+    positions: &[Point3],
+    corners: &[usize],
+    normals: Option<&[Vec3]>,
+    uvs: Option<&[(f64, f64)]>,
+    face_materials: Vec<Material>,
+    smoothing_angle_deg: f64,
+) -> Vec<Triangle> {
+    let generated_normals = if normals.is_none() && smoothing_angle_deg > 0.0 {
+        Some(smooth_normals(positions, corners, smoothing_angle_deg))
+    } else {
+        None
+    };
+
+    corners.chunks_exact(3).zip(face_materials)
+        .enumerate()
+        .map(|(i, (face, material))| Triangle {
+            vertices: [positions[face[0]], positions[face[1]], positions[face[2]]],
+            uvs: uvs.map(|uvs| [uvs[face[0]], uvs[face[1]], uvs[face[2]]]),
+            normals: match (normals, generated_normals.as_ref()) {
+                (Some(normals), _) => Some([normals[face[0]], normals[face[1]], normals[face[2]]]),
+                (None, Some(normals)) => Some([normals[3 * i], normals[3 * i + 1], normals[3 * i + 2]]),
+                (None, None) => None
+            },
+            material,
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum PlyType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl PlyType {
+    fn parse(name: &str) -> Result<PlyType, Box<dyn Error>> {
+        Ok(match name {
+            "char" | "int8" => PlyType::I8,
+            "uchar" | "uint8" => PlyType::U8,
+            "short" | "int16" => PlyType::I16,
+            "ushort" | "uint16" => PlyType::U16,
+            "int" | "int32" => PlyType::I32,
+            "uint" | "uint32" => PlyType::U32,
+            "float" | "float32" => PlyType::F32,
+            "double" | "float64" => PlyType::F64,
+            _ => return Err(format!("unknown PLY type {}", name).into())
+        })
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            PlyType::I8 | PlyType::U8 => 1,
+            PlyType::I16 | PlyType::U16 => 2,
+            PlyType::I32 | PlyType::U32 | PlyType::F32 => 4,
+            PlyType::F64 => 8,
+        }
+    }
+
+    fn is_integer(&self) -> bool {
+        !matches!(self, PlyType::F32 | PlyType::F64)
+    }
+
+    fn max_value(&self) -> f64 {
+        match self {
+            PlyType::I8 => i8::MAX as f64,
+            PlyType::U8 => u8::MAX as f64,
+            PlyType::I16 => i16::MAX as f64,
+            PlyType::U16 => u16::MAX as f64,
+            PlyType::I32 => i32::MAX as f64,
+            PlyType::U32 => u32::MAX as f64,
+            PlyType::F32 | PlyType::F64 => 1.0,
+        }
+    }
+}
+
+struct PlyProperty {
+    name: String,
+    /// Type of the items, which are preceded by their count for list properties.
+    ty: PlyType,
+    list_count: Option<PlyType>,
+}
+
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>,
+}
+
+struct PlyHeader {
+    format: String,
+    elements: Vec<PlyElement>,
+}
+
+/// Parses the header, returning it with the data that follows `end_header`.
+fn split_ply_header(contents: &[u8]) -> Result<(PlyHeader, &[u8]), Box<dyn Error>> {
+    let mut header = PlyHeader { format: String::new(), elements: vec![] };
+    let mut rest = contents;
+    let mut first_line = true;
+    loop {
+        let line_end = rest.iter().position(|&b| b == b'\n').ok_or("PLY header has no end_header")?;
+        let line = std::str::from_utf8(&rest[..line_end])?.trim();
+        rest = &rest[line_end + 1..];
+        if first_line {
+            if line != "ply" {
+                return Err("not a PLY file".into());
+            }
+            first_line = false;
+            continue;
+        }
+
+        let args = line.split_whitespace().collect::<Vec<_>>();
+        match args.as_slice() {
+            ["format", format, _] => header.format = format.to_string(),
+            ["element", name, count] => header.elements.push(PlyElement {
+                name: name.to_string(),
+                count: count.parse()?,
+                properties: vec![],
+            }),
+            ["property", "list", count_ty, ty, name] => header.elements.last_mut().ok_or("PLY property outside of an element")?
+                .properties.push(PlyProperty { name: name.to_string(), ty: PlyType::parse(ty)?, list_count: Some(PlyType::parse(count_ty)?) }),
+            ["property", ty, name] => header.elements.last_mut().ok_or("PLY property outside of an element")?
+                .properties.push(PlyProperty { name: name.to_string(), ty: PlyType::parse(ty)?, list_count: None }),
+            ["end_header"] => return Ok((header, rest)),
+            _ => {}
+        }
+    }
+}
+
+/// Values of the body of a PLY file, read one at a time whatever the format.
+enum PlyValues<'a> {
+    Ascii(std::str::SplitAsciiWhitespace<'a>),
+    Binary { data: &'a [u8], big_endian: bool },
+}
+
+impl PlyValues<'_> {
+    fn read(&mut self, ty: PlyType) -> Result<f64, Box<dyn Error>> {
+        match self {
+            PlyValues::Ascii(tokens) => Ok(tokens.next().ok_or("truncated PLY data")?.parse()?),
+            PlyValues::Binary { data, big_endian } => {
+                if data.len() < ty.size() {
+                    return Err("truncated PLY data".into());
+                }
+                let mut bytes = [0u8; 8];
+                bytes[..ty.size()].copy_from_slice(&data[..ty.size()]);
+                if *big_endian {
+                    bytes[..ty.size()].reverse();
+                }
+                *data = &data[ty.size()..];
+
+                let [b0, b1, b2, b3, ..] = bytes;
+                Ok(match ty {
+                    PlyType::I8 => b0 as i8 as f64,
+                    PlyType::U8 => b0 as f64,
+                    PlyType::I16 => i16::from_le_bytes([b0, b1]) as f64,
+                    PlyType::U16 => u16::from_le_bytes([b0, b1]) as f64,
+                    PlyType::I32 => i32::from_le_bytes([b0, b1, b2, b3]) as f64,
+                    PlyType::U32 => u32::from_le_bytes([b0, b1, b2, b3]) as f64,
+                    PlyType::F32 => f32::from_le_bytes([b0, b1, b2, b3]) as f64,
+                    PlyType::F64 => f64::from_le_bytes(bytes),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::temp_path;
+
+    use super::*;
+
+    const SQUARE: [[f64; 3]; 4] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]];
+
+    /// Reads `contents` from a temporary file with `read`.
+    fn read_temp<T>(
+        name: &str,
+        contents: &[u8],
+        read: impl Fn(&str) -> Result<T, Box<dyn Error>>,
+    ) -> Result<T, Box<dyn Error>> {
+        let path = temp_path(name);
+        fs::write(&path, contents).unwrap();
+        let result = read(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    fn ply(name: &str, contents: &[u8], smoothing_angle_deg: f64) -> Result<Vec<Triangle>, Box<dyn Error>> {
+        let material = Material::Lambertian { albedo: Texture::Constant(Color::new(0.5, 0.5, 0.5)) };
+        read_temp(name, contents, |filename| read_ply(filename, &material, smoothing_angle_deg))
+    }
+
+    fn stl(name: &str, contents: &[u8], smoothing_angle_deg: f64) -> Result<Vec<Triangle>, Box<dyn Error>> {
+        read_temp(name, contents, |filename| read_stl(filename, &Material::BlackBody, smoothing_angle_deg))
+    }
+
+    /// Checks that the triangles fan out the unit square from its first corner.
+    fn assert_square(triangles: &[Triangle]) {
+        assert_eq!(triangles.len(), 2);
+        for (triangle, corners) in triangles.iter().zip([[0, 1, 2], [0, 2, 3]].iter()) {
+            for (vertex, &corner) in triangle.vertices.iter().zip(corners.iter()) {
+                assert_eq!([vertex[0], vertex[1], vertex[2]], SQUARE[corner]);
+            }
+        }
+    }
+
+    #[test]
+    fn ascii_ply_is_read() {
+        let contents = b"ply\n\
+            format ascii 1.0\n\
+            comment a unit square\n\
+            element vertex 4\n\
+            property float x\nproperty float y\nproperty float z\n\
+            property float nx\nproperty float ny\nproperty float nz\n\
+            property float s\nproperty float t\n\
+            element face 1\n\
+            property list uchar int vertex_indices\n\
+            end_header\n\
+            0 0 0 0 0 2 0 0\n1 0 0 0 0 2 1 0\n1 1 0 0 0 2 1 1\n0 1 0 0 0 2 0 1\n\
+            4 0 1 2 3\n";
+        let triangles = ply("ascii_ply", contents, 0.0).unwrap();
+        assert_square(&triangles);
+        assert_eq!(triangles[1].uvs, Some([(0.0, 0.0), (1.0, 1.0), (0.0, 1.0)]));
+        let normal = triangles[0].normals.unwrap()[1];
+        assert_eq!([normal[0], normal[1], normal[2]], [0.0, 0.0, 1.0]);
+    }
+
+    /// The unit square with red vertices at y = 0 and green ones at y = 1, in binary.
+    fn binary_ply(big_endian: bool) -> Vec<u8> {
+        let format = if big_endian { "binary_big_endian" } else { "binary_little_endian" };
+        let mut contents = format!(
+            "ply\nformat {} 1.0\nelement vertex 4\n\
+             property float x\nproperty float y\nproperty float z\n\
+             property uchar red\nproperty uchar green\nproperty uchar blue\n\
+             element face 1\nproperty list uchar int vertex_indices\nend_header\n",
+            format,
+        ).into_bytes();
+        let float = |x: f32| if big_endian { x.to_be_bytes() } else { x.to_le_bytes() };
+        let int = |i: i32| if big_endian { i.to_be_bytes() } else { i.to_le_bytes() };
+        for vertex in SQUARE.iter() {
+            for &coordinate in vertex.iter() {
+                contents.extend_from_slice(&float(coordinate as f32));
+            }
+            contents.extend_from_slice(if vertex[1] == 0.0 { &[255, 0, 0] } else { &[0, 255, 0] });
+        }
+        contents.push(4);
+        for i in 0..4 {
+            contents.extend_from_slice(&int(i));
+        }
+        contents
+    }
+
+    #[test]
+    fn binary_ply_is_read_in_both_byte_orders() {
+        for &big_endian in [false, true].iter() {
+            let triangles = ply("binary_ply", &binary_ply(big_endian), 0.0).unwrap();
+            assert_square(&triangles);
+            assert!(triangles[0].normals.is_none() && triangles[0].uvs.is_none());
+
+            // Faces take the average of their vertex colors.
+            match triangles[0].material {
+                Material::Lambertian { albedo: Texture::Constant(color) } => {
+                    assert!((color.r - 2.0 / 3.0).abs() < 1e-12 && (color.g - 1.0 / 3.0).abs() < 1e-12 && color.b == 0.0);
+                }
+                ref material => panic!("unexpected material {:?}", material),
+            }
+        }
+    }
+
+    #[test]
+    fn ply_normals_are_generated_when_smoothing() {
+        let triangles = ply("smoothed_ply", &binary_ply(false), 30.0).unwrap();
+        let normal = triangles[0].normals.unwrap()[0];
+        assert!((normal[2].abs() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn malformed_ply_is_rejected() {
+        let header = "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\nproperty float z\n\
+                      element face 1\nproperty list uchar int vertex_indices\nend_header\n";
+        let cases = [
+            ("ply_magic", "solid square\nend_header\n".to_string()),
+            ("ply_no_end", "ply\nformat ascii 1.0\nelement vertex 3\n".to_string()),
+            ("ply_format", "ply\nformat utf8 1.0\nend_header\n".to_string()),
+            ("ply_type", "ply\nformat ascii 1.0\nelement vertex 1\nproperty quad x\nend_header\n".to_string()),
+            ("ply_truncated", format!("{}0 0 0\n1 0 0\n", header)),
+            ("ply_index", format!("{}0 0 0\n1 0 0\n1 1 0\n3 0 1 3\n", header)),
+            ("ply_no_position", "ply\nformat ascii 1.0\nelement vertex 1\nproperty float x\nend_header\n0\n".to_string()),
+        ];
+        for (name, contents) in cases.iter() {
+            assert!(ply(name, contents.as_bytes(), 0.0).is_err(), "{} was accepted", name);
+        }
+
+        let mut truncated = binary_ply(false);
+        truncated.truncate(truncated.len() - 2);
+        assert!(ply("ply_truncated_binary", &truncated, 0.0).is_err());
+    }
+
+    fn ascii_stl(facets: &[[usize; 3]]) -> String {
+        let mut contents = "solid square\n".to_string();
+        for facet in facets.iter() {
+            contents += "facet normal 0 0 1\nouter loop\n";
+            for &corner in facet.iter() {
+                let [x, y, z] = SQUARE[corner];
+                contents += &format!("vertex {} {} {}\n", x, y, z);
+            }
+            contents += "endloop\nendfacet\n";
+        }
+        contents + "endsolid square\n"
+    }
+
+    #[test]
+    fn ascii_stl_is_read() {
+        let triangles = stl("ascii_stl", ascii_stl(&[[0, 1, 2], [0, 2, 3]]).as_bytes(), 0.0).unwrap();
+        assert_square(&triangles);
+        assert!(triangles[0].normals.is_none());
+
+        // Shared corners are merged, which lets normals be smoothed across facets.
+        let smoothed = stl("smoothed_stl", ascii_stl(&[[0, 1, 2], [0, 2, 3]]).as_bytes(), 30.0).unwrap();
+        let normal = smoothed[1].normals.unwrap()[0];
+        assert!((normal[2] - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn binary_stl_is_read() {
+        // The header may start with "solid" too, the size tells the formats apart.
+        let mut contents = b"solid binary".to_vec();
+        contents.resize(80, 0);
+        contents.extend_from_slice(&2u32.to_le_bytes());
+        for facet in [[0, 1, 2], [0, 2, 3]].iter() {
+            contents.extend_from_slice(&[0; 12]);
+            for &corner in facet.iter() {
+                for &coordinate in SQUARE[corner].iter() {
+                    contents.extend_from_slice(&(coordinate as f32).to_le_bytes());
+                }
+            }
+            contents.extend_from_slice(&[0; 2]);
+        }
+        assert_square(&stl("binary_stl", &contents, 0.0).unwrap());
+    }
+
+    #[test]
+    fn malformed_stl_is_rejected() {
+        let missing_vertex = ascii_stl(&[[0, 1, 2]]).replace("vertex 1 1 0\n", "");
+        assert!(stl("stl_missing_vertex", missing_vertex.as_bytes(), 0.0).is_err());
+
+        let truncated = "solid square\nfacet normal 0 0 1\nouter loop\nvertex 0 0\n";
+        assert!(stl("stl_truncated", truncated.as_bytes(), 0.0).is_err());
+
+        let not_a_number = ascii_stl(&[[0, 1, 2]]).replace("vertex 1 0 0", "vertex 1 zero 0");
+        assert!(stl("stl_number", not_a_number.as_bytes(), 0.0).is_err());
+    }
+}
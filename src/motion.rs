@@ -0,0 +1,219 @@
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::bounding_box::BBox;
+use crate::geometry::{HitRecord, Hittable};
+use crate::ray::Ray;
+use crate::transform::{compose, hit_transformed, Matrix4, object_ray, transform_bbox, TransformSpec};
+use crate::vec3::Vec3;
+
+/// Number of instants per keyframe interval at which the bounds of a moving object are taken.
+const BBOX_STEPS: usize = 32;
+
+/// Placement of a moving object at `time`, listed like the transforms of `Transform`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Keyframe {
+    pub time: f64,
+    pub transform: Vec<TransformSpec>,
+}
+
+/// Places `object` with transforms interpolated between keyframes at the time of each ray.
+/// Before the first keyframe and after the last one the object stays still.
+///
+/// Moving objects aren't sampled as lights, since where they are depends on the time of
+/// the ray. Emissive ones still light the scene through the rays that hit them.
+pub struct Moving<T: Hittable> {
+    object: T,
+    keyframes: Vec<Keyframe>,
+    bbox: BBox,
+}
+
+impl<T: Hittable> Moving<T> {
+    /// Fails if there are no keyframes, if a keyframe time isn't finite, or if two keyframes
+    /// don't list the same kinds of transforms in the same order.
+    pub fn new(object: T, mut keyframes: Vec<Keyframe>) -> Result<Moving<T>, String> {
+        if keyframes.is_empty() {
+            return Err("a moving object needs at least one keyframe".to_string());
+        }
+        if let Some(keyframe) = keyframes.iter().find(|keyframe| !keyframe.time.is_finite()) {
+            return Err(format!("keyframe time {} isn't finite", keyframe.time));
+        }
+
+        keyframes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+        for pair in keyframes.windows(2) {
+            let compatible = pair[0].transform.len() == pair[1].transform.len()
+                && pair[0].transform.iter().zip(pair[1].transform.iter()).all(|(a, b)| a.lerp(b, 0.0).is_some());
+            if !compatible {
+                return Err(format!("keyframes at {} and {} list different transforms", pair[0].time, pair[1].time));
+            }
+        }
+
+        let bbox = motion_bbox(object.bounding_box(), &keyframes);
+        Ok(Moving { object, keyframes, bbox })
+    }
+
+    fn to_world(&self, time: f64) -> Matrix4 {
+        let next = self.keyframes.iter().position(|keyframe| keyframe.time > time);
+        let (from, to) = match next {
+            Some(0) => return compose(&self.keyframes[0].transform),
+            Some(next) => (&self.keyframes[next - 1], &self.keyframes[next]),
+            None => return compose(&self.keyframes[self.keyframes.len() - 1].transform)
+        };
+
+        interpolate(from, to, (time - from.time) / (to.time - from.time))
+    }
+}
+
+fn interpolate(from: &Keyframe, to: &Keyframe, t: f64) -> Matrix4 {
+    let transform = from.transform.iter().zip(to.transform.iter())
+        .map(|(a, b)| a.lerp(b, t).unwrap())
+        .collect::<Vec<_>>();
+    compose(&transform)
+}
+
+/// Union of the bounds at evenly spaced instants of every interval, grown by how far a corner
+/// moves between two instants so that curved paths in between stay covered.
+fn motion_bbox(bbox: BBox, keyframes: &[Keyframe]) -> BBox {
+    let mut result = transform_bbox(&compose(&keyframes[0].transform), bbox);
+    let mut padding = 0.0f64;
+    for pair in keyframes.windows(2) {
+        let mut previous = transform_bbox(&compose(&pair[0].transform), bbox);
+        for step in 1..=BBOX_STEPS {
+            let current = transform_bbox(&interpolate(&pair[0], &pair[1], step as f64 / BBOX_STEPS as f64), bbox);
+            padding = padding.max((current.min - previous.min).length()).max((current.max - previous.max).length());
+            result = BBox::surrounding_box(result, current);
+            previous = current;
+        }
+    }
+
+    BBox {
+        min: result.min - Vec3::new(padding, padding, padding),
+        max: result.max + Vec3::new(padding, padding, padding),
+    }
+}
+
+impl<T: Hittable> Hittable for Moving<T> {
+    fn hit_by(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let to_world = self.to_world(ray.time);
+        let to_object = to_world.inverse()?;
+        hit_transformed(rng, &self.object, &to_world, &to_object, ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self) -> BBox {
+        self.bbox
+    }
+
+    fn is_bounded(&self) -> bool {
+        self.object.is_bounded()
+    }
+
+    fn transmittance(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> f64 {
+        match self.to_world(ray.time).inverse() {
+            Some(to_object) => self.object.transmittance(rng, &object_ray(&to_object, ray), t_min, t_max),
+            None => 1.0
+        }
+    }
+
+    // Emissive with no area, so lights are only collected from the objects that stay still.
+    fn is_emissive(&self) -> bool {
+        self.object.is_emissive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    use crate::bvh::BVHBuilder;
+    use crate::geometry::{ArcHittable, Sphere};
+    use crate::group::Group;
+    use crate::light::Light;
+    use crate::material::Material;
+    use crate::point3::Point3;
+    use crate::test_util::{assert_close, ray};
+
+    use super::*;
+
+    fn keyframe(time: f64, transform: Vec<TransformSpec>) -> Keyframe {
+        Keyframe { time, transform }
+    }
+
+    fn translate(x: f64, y: f64, z: f64) -> TransformSpec {
+        TransformSpec::Translate { offset: Vec3::new(x, y, z) }
+    }
+
+    fn rotate(angle_deg: f64) -> TransformSpec {
+        TransformSpec::Rotate { axis: Vec3::new(0.0, 0.0, 1.0), angle_deg }
+    }
+
+    fn ball() -> Sphere {
+        Sphere { center: Point3::new(0.0, 0.0, 0.0), radius: 0.5, material: Material::BlackBody }
+    }
+
+    fn contains(outer: BBox, inner: BBox) -> bool {
+        (0..3).all(|i| outer.min[i] <= inner.min[i] && inner.max[i] <= outer.max[i])
+    }
+
+    #[test]
+    fn objects_are_hit_where_they_are_at_the_time_of_the_ray() {
+        let rng = &mut SmallRng::seed_from_u64(0);
+        let moving = Moving::new(ball(), vec![keyframe(1.0, vec![translate(4.0, 0.0, 0.0)]), keyframe(0.0, vec![translate(0.0, 0.0, 0.0)])]).unwrap();
+        let mut down = |x: f64, time: f64| {
+            let mut r = ray(Point3::new(x, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+            r.time = time;
+            moving.hit_by(rng, &r, 0.001, f64::INFINITY).map(|rec| rec.t)
+        };
+
+        assert_close(down(0.0, 0.0).unwrap(), 4.5, 1e-9);
+        assert_close(down(1.0, 0.25).unwrap(), 4.5, 1e-9);
+        assert!(down(0.0, 0.5).is_none());
+        // Still at the last keyframe after it.
+        assert_close(down(4.0, 2.0).unwrap(), 4.5, 1e-9);
+    }
+
+    #[test]
+    fn bounds_cover_curved_paths() {
+        // Swinging around the origin at the end of an arm, through angles the keyframes skip.
+        let keyframes = vec![
+            keyframe(0.0, vec![translate(3.0, 0.0, 0.0), rotate(0.0)]),
+            keyframe(1.0, vec![translate(3.0, 0.0, 0.0), rotate(270.0)]),
+        ];
+        let moving = Moving::new(ball(), keyframes).unwrap();
+        for step in 0..=100 {
+            let time = step as f64 / 100.0;
+            let placed = transform_bbox(&moving.to_world(time), ball().bounding_box());
+            assert!(contains(moving.bounding_box(), placed), "bounds at {} aren't covered", time);
+        }
+    }
+
+    #[test]
+    fn inconsistent_keyframes_are_errors() {
+        assert!(Moving::new(ball(), vec![]).is_err());
+        assert!(Moving::new(ball(), vec![keyframe(f64::NAN, vec![])]).is_err());
+        let mismatched = vec![keyframe(0.0, vec![translate(1.0, 0.0, 0.0)]), keyframe(1.0, vec![rotate(90.0)])];
+        assert!(Moving::new(ball(), mismatched).is_err());
+    }
+
+    #[test]
+    fn lights_are_probed_at_the_time_of_the_shading_ray() {
+        let rng = &mut SmallRng::seed_from_u64(18);
+        let lamp: ArcHittable = Arc::new(Sphere {
+            center: Point3::new(0.0, 10.0, 0.0),
+            radius: 1.0,
+            material: serde_yaml::from_str("type: DiffuseLight\nemit: [1.0, 1.0, 1.0]").unwrap(),
+        });
+        // Covers the lamp at time 0 and has moved aside by time 1.
+        let shade: ArcHittable = Arc::new(Moving::new(ball(), vec![
+            keyframe(0.0, vec![TransformSpec::Scale { factors: Vec3::new(6.0, 6.0, 6.0) }, translate(0.0, 5.0, 0.0)]),
+            keyframe(1.0, vec![TransformSpec::Scale { factors: Vec3::new(6.0, 6.0, 6.0) }, translate(20.0, 5.0, 0.0)]),
+        ]).unwrap());
+        let light = Light::Area(Arc::new(Group::new(vec![lamp, shade], BVHBuilder::default())));
+        let origin = Point3::new(0.0, 0.0, 0.0);
+
+        assert!((0..100).all(|_| light.sample(rng, origin, 0.0).is_none()));
+        assert!((0..100).any(|_| light.sample(rng, origin, 1.0).is_some()));
+    }
+}
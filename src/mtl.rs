@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+use crate::color::Color;
+use crate::material::Material;
+use crate::texture::{ImageTexture, ImageTextureSpec, Texture};
+
+/// Subset of a Wavefront `.mtl` material that maps onto this crate's materials.
+#[derive(Debug, Clone)]
+pub struct MtlMaterial {
+    pub diffuse: Color,
+    pub specular: Color,
+    pub specular_exponent: f64,
+    pub optical_density: f64,
+    pub dissolve: f64,
+    pub emissive: Color,
+    pub diffuse_map: Option<String>,
+}
+
+impl Default for MtlMaterial {
+    fn default() -> Self {
+        MtlMaterial {
+            diffuse: Color::new(0.8, 0.8, 0.8),
+            specular: Color::new(0.0, 0.0, 0.0),
+            specular_exponent: 0.0,
+            optical_density: 1.5,
+            dissolve: 1.0,
+            emissive: Color::new(0.0, 0.0, 0.0),
+            diffuse_map: None,
+        }
+    }
+}
+
+impl MtlMaterial {
+    /// Picks the closest material: emissive if `Ke` is set, dielectric if the material is
+    /// transparent, metal if the specular color dominates the diffuse one and lambertian
+    /// otherwise. The Phong exponent `Ns` is turned into the fuzz of the metal.
+    pub fn to_material(&self) -> Result<Material, Box<dyn Error>> {
+        if !self.emissive.is_black() {
+            return Ok(Material::DiffuseLight { emit: self.emissive, intensity: 1.0 });
+        }
+
+        if self.dissolve < 1.0 {
+            return Ok(Material::Dielectric { index_of_refraction: self.optical_density });
+        }
+
+        if self.specular.luminance() > self.diffuse.luminance() {
+            let fuzz = (2.0 / (self.specular_exponent.max(0.0) + 2.0)).sqrt();
+            return Ok(Material::Metal { albedo: Texture::Constant(self.specular), fuzz });
+        }
+
+        let albedo = match &self.diffuse_map {
+            Some(filename) => Texture::Image(ImageTexture::load(ImageTextureSpec { filename: filename.clone() })?),
+            None => Texture::Constant(self.diffuse)
+        };
+        Ok(Material::Lambertian { albedo })
+    }
+}
+
+/// Reads the materials of a `.mtl` file by name. Texture paths are resolved relative to the
+/// file, and statements that can't be represented are skipped.
+pub fn read_mtl(filename: &Path) -> Result<HashMap<String, MtlMaterial>, Box<dyn Error>> {
+    let contents = std::fs::read_to_string(filename)?;
+    let directory = filename.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlMaterial)> = None;
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut tokens = line.split_whitespace();
+        let statement = match tokens.next() {
+            Some(statement) => statement,
+            None => continue
+        };
+        let args = tokens.collect::<Vec<_>>();
+
+        if statement == "newmtl" {
+            if let Some((name, material)) = current.take() {
+                materials.insert(name, material);
+            }
+            current = Some((args.join(" "), MtlMaterial::default()));
+            continue;
+        }
+
+        let material = match current.as_mut() {
+            Some((_, material)) => material,
+            None => return Err(format!("{} before newmtl in {}", statement, filename.display()).into())
+        };
+        match statement {
+            "Kd" => material.diffuse = parse_color(&args)?,
+            "Ks" => material.specular = parse_color(&args)?,
+            "Ke" => material.emissive = parse_color(&args)?,
+            "Ns" => material.specular_exponent = parse_scalar(&args)?,
+            "Ni" => material.optical_density = parse_scalar(&args)?,
+            "d" => material.dissolve = parse_scalar(&args)?,
+            "Tr" => material.dissolve = 1.0 - parse_scalar(&args)?,
+            // Options such as `-s` come before the file name, which is the last argument.
+            "map_Kd" => if let Some(file) = args.last() {
+                material.diffuse_map = Some(directory.join(file).to_string_lossy().into_owned());
+            },
+            _ => {}
+        }
+    }
+
+    if let Some((name, material)) = current {
+        materials.insert(name, material);
+    }
+
+    Ok(materials)
+}
+
+fn parse_scalar(args: &[&str]) -> Result<f64, Box<dyn Error>> {
+    match args.first() {
+        Some(arg) => Ok(arg.parse()?),
+        None => Err("missing value in mtl statement".into())
+    }
+}
+
+fn parse_color(args: &[&str]) -> Result<Color, Box<dyn Error>> {
+    let values = args.iter().map(|arg| arg.parse::<f64>()).collect::<Result<Vec<_>, _>>()?;
+    match values[..] {
+        [r] => Ok(Color::new(r, r, r)),
+        [r, g, b] => Ok(Color::new(r, g, b)),
+        _ => Err(format!("expected 1 or 3 color values, got {}", values.len()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::temp_path;
+
+    use super::*;
+
+    fn parse(name: &str, contents: &str) -> Result<HashMap<String, MtlMaterial>, Box<dyn Error>> {
+        let path = temp_path(name);
+        std::fs::write(&path, contents).unwrap();
+        let materials = read_mtl(&path);
+        std::fs::remove_file(&path).unwrap();
+        materials
+    }
+
+    fn rgb(color: Color) -> (f64, f64, f64) {
+        (color.r, color.g, color.b)
+    }
+
+    #[test]
+    fn statements_fill_the_current_material() {
+        let materials = parse("statements.mtl", "\
+            # exported materials\n\
+            newmtl shiny metal\n\
+            Kd 0.1 0.2 0.3\n\
+            Ks 0.9  # trailing comment\n\
+            Ns 250\n\
+            illum 2\n\
+            newmtl glass\n\
+            Tr 0.75\n\
+            Ni 1.33\n\
+            map_Kd -s 2 2 2 textures/glass.png\n").unwrap();
+
+        assert_eq!(materials.len(), 2);
+        let metal = &materials["shiny metal"];
+        assert_eq!(rgb(metal.diffuse), (0.1, 0.2, 0.3));
+        assert_eq!(rgb(metal.specular), (0.9, 0.9, 0.9));
+        assert_eq!(metal.specular_exponent, 250.0);
+
+        let glass = &materials["glass"];
+        assert_eq!((glass.dissolve, glass.optical_density), (0.25, 1.33));
+        let directory = temp_path("statements.mtl").parent().unwrap().to_path_buf();
+        assert_eq!(glass.diffuse_map.as_deref(), directory.join("textures/glass.png").to_str());
+    }
+
+    #[test]
+    fn malformed_statements_are_rejected() {
+        assert!(parse("orphan.mtl", "Kd 1 1 1\n").is_err());
+        assert!(parse("two_values.mtl", "newmtl a\nKd 1 1\n").is_err());
+        assert!(parse("not_a_number.mtl", "newmtl a\nNs shiny\n").is_err());
+    }
+
+    #[test]
+    fn materials_are_picked_by_their_dominant_term() {
+        let light = MtlMaterial { emissive: Color::new(2.0, 2.0, 2.0), ..MtlMaterial::default() };
+        assert!(matches!(light.to_material().unwrap(), Material::DiffuseLight { .. }));
+
+        let glass = MtlMaterial { dissolve: 0.5, optical_density: 1.33, ..MtlMaterial::default() };
+        assert!(matches!(glass.to_material().unwrap(), Material::Dielectric { index_of_refraction } if index_of_refraction == 1.33));
+
+        let mirror = MtlMaterial { specular: Color::new(1.0, 1.0, 1.0), specular_exponent: 1e6, ..MtlMaterial::default() };
+        match mirror.to_material().unwrap() {
+            Material::Metal { fuzz, .. } => assert!(fuzz < 0.01),
+            material => panic!("expected a metal, got {:?}", material)
+        }
+
+        assert!(matches!(MtlMaterial::default().to_material().unwrap(), Material::Lambertian { .. }));
+    }
+}
@@ -4,6 +4,8 @@ use crate::point3::Point3;
 pub struct Ray {
     pub orig: Point3,
     pub dir: Vec3,
+    /// Instant within the camera shutter interval at which the ray travels.
+    pub time: f64,
 }
 
 impl Ray {
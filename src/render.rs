@@ -1,4 +1,5 @@
 use std::f64;
+use std::time::{Duration, Instant};
 
 use image::RgbImage;
 use itertools::iproduct;
@@ -8,34 +9,130 @@ use rayon::prelude::*;
 
 use crate::color::{Color, put_color};
 use crate::geometry::{HitRecord, Hittable};
+use crate::light::Light;
 use crate::ray::Ray;
 use crate::scene::Scene;
 
-pub fn ray_color<T: Hittable>(rng: &mut dyn RngCore, ray: &Ray, world: &T, depth: u32) -> Color {
+pub fn ray_color<T: Hittable>(rng: &mut dyn RngCore, ray: &Ray, world: &T, scene: &Scene, depth: u32) -> Color {
+    trace(rng, ray, world, scene, depth, None)
+}
+
+/// `bsdf_pdf` is the density with which the previous bounce sampled `ray`, or `None` for camera
+/// rays and specular bounces. Emission reached by a sampled ray is weighted against the
+/// probability of having found it through light sampling instead.
+fn trace<T: Hittable>(
+    rng: &mut dyn RngCore,
+    ray: &Ray,
+    world: &T,
+    scene: &Scene,
+    depth: u32,
+    bsdf_pdf: Option<f64>,
+) -> Color {
     if depth <= 0 {
         return Color::new(0.0, 0.0, 0.0);
     }
 
-    let hit_record: Option<HitRecord> = world.hit_by(ray, 0.001, f64::INFINITY);
+    let hit_record: Option<HitRecord> = world.hit_by(rng, ray, 0.001, f64::INFINITY);
     return match hit_record {
         Some(rec) => {
+            let mut emitted = rec.material.emitted(&rec);
+            if let Some(pdf) = bsdf_pdf {
+                if !emitted.is_black() {
+                    emitted = emitted * power_heuristic(pdf, hit_light_pdf(rng, scene, ray, &rec));
+                }
+            }
+
             match rec.material.scatter(rng, ray, &rec) {
+                Some(scatter_rec) if scatter_rec.is_specular => {
+                    emitted + scatter_rec.attenuation * trace(rng, &scatter_rec.ray, world, scene, depth - 1, None)
+                }
+
                 Some(scatter_rec) => {
-                    scatter_rec.attenuation * ray_color(rng, &scatter_rec.ray, world, depth - 1)
+                    let direct = sample_direct(rng, world, scene, ray, &rec);
+                    let indirect = trace(rng, &scatter_rec.ray, world, scene, depth - 1, Some(scatter_rec.pdf));
+                    emitted + direct + scatter_rec.attenuation * indirect
                 }
 
-                None => Color::new(0.0, 0.0, 0.0)
+                None => emitted
             }
         }
 
-        _ => {
-            let normalized_dir = ray.dir.normalize();
-            let t = 0.5 * (normalized_dir[1] + 1.0);
-            (1.0 - t) * Color::new(1.0, 1.0, 1.0) + t * Color::new(0.5, 0.7, 1.0)
+        None => {
+            // The background is only sampled through the environment map, and every light at
+            // infinity is weighted against its own density.
+            let weight = |light_pdf| bsdf_pdf.map_or(1.0, |pdf| power_heuristic(pdf, light_pdf));
+            let environment_pdf = scene.lights.iter()
+                .find(|light| matches!(light, Light::Environment(_)))
+                .map_or(0.0, |light| light_pdf(rng, scene, light, ray));
+            scene.lights.iter()
+                .map(|light| light.escaped_radiance(ray.dir) * weight(light_pdf(rng, scene, light, ray)))
+                .fold(scene.background.color(ray.dir) * weight(environment_pdf), |accum, radiance| accum + radiance)
         }
     };
 }
 
+/// Estimates direct lighting at `rec` by sampling a single light picked uniformly at random
+/// and tracing a shadow ray towards it, weighted against BSDF sampling.
+fn sample_direct<T: Hittable>(
+    rng: &mut dyn RngCore,
+    world: &T,
+    scene: &Scene,
+    ray_in: &Ray,
+    rec: &HitRecord,
+) -> Color {
+    if scene.lights.is_empty() {
+        return Color::new(0.0, 0.0, 0.0);
+    }
+
+    let light = &scene.lights[rng.gen_range(0..scene.lights.len())];
+    let light_sample = match light.sample(rng, rec.point, ray_in.time) {
+        Some(sample) if sample.pdf > 0.0 => sample,
+        _ => return Color::new(0.0, 0.0, 0.0)
+    };
+
+    let f = rec.material.eval(ray_in, rec, light_sample.dir);
+    if f.is_black() || light_sample.radiance.is_black() {
+        return Color::new(0.0, 0.0, 0.0);
+    }
+
+    let shadow_ray = Ray { orig: rec.point, dir: light_sample.dir, time: ray_in.time };
+    let transmittance = world.transmittance(rng, &shadow_ray, 0.001, light_sample.distance - 0.001);
+    if transmittance <= 0.0 {
+        return Color::new(0.0, 0.0, 0.0);
+    }
+
+    let light_pdf = light_sample.pdf / scene.lights.len() as f64;
+    let weight = if light_sample.is_delta {
+        1.0
+    } else {
+        power_heuristic(light_pdf, rec.material.scattering_pdf(ray_in, rec, light_sample.dir))
+    };
+    light_sample.radiance * f * (transmittance * weight / light_pdf)
+}
+
+/// Density of light sampling picking the direction of `ray` by sampling `light`.
+fn light_pdf(rng: &mut dyn RngCore, scene: &Scene, light: &Light, ray: &Ray) -> f64 {
+    light.pdf(rng, ray.orig, ray.dir.normalize(), ray.time) / scene.lights.len() as f64
+}
+
+/// Density of light sampling picking the direction of `ray`, which hit `rec`. Only the light
+/// whose surface was hit counts, lights further along the ray can't be seen from its origin.
+fn hit_light_pdf(rng: &mut dyn RngCore, scene: &Scene, ray: &Ray, rec: &HitRecord) -> f64 {
+    scene.lights.iter()
+        .find(|light| light.is_hit_at(rng, ray, rec.t))
+        .map_or(0.0, |light| light_pdf(rng, scene, light, ray))
+}
+
+fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    let pdf2 = pdf * pdf;
+    let other_pdf2 = other_pdf * other_pdf;
+    if pdf2 + other_pdf2 == 0.0 {
+        return 0.0;
+    }
+
+    pdf2 / (pdf2 + other_pdf2)
+}
+
 fn render_pixel<T: Hittable>(rng: &mut dyn RngCore, world: &T, x: u32, y: u32, scene: &Scene) -> Color {
     let image_width = scene.render_config.image_width;
     let image_height = scene.render_config.image_height;
@@ -43,7 +140,7 @@ fn render_pixel<T: Hittable>(rng: &mut dyn RngCore, world: &T, x: u32, y: u32, s
     let u = (x as f64 + rng.gen::<f64>()) / (image_width - 1) as f64;
     let v = (y as f64 + rng.gen::<f64>()) / (image_height - 1) as f64;
     let r = scene.camera.get_ray(rng, u, 1.0 - v);
-    ray_color(rng, &r, world, scene.render_config.max_depth)
+    ray_color(rng, &r, world, scene, scene.render_config.max_depth)
 }
 
 pub fn render_image_sequential<T: Hittable>(scene: &Scene, world: &T) -> RgbImage {
@@ -101,14 +198,97 @@ pub fn render_image_parallel<T>(scene: &Scene, world: &T) -> RgbImage
     img
 }
 
-pub fn render_scene(scene: &Scene, filename: &str, parallel: bool, bvh: bool) {
+pub struct RenderTimings {
+    pub bvh_build: Duration,
+    pub render: Duration,
+}
+
+pub fn render_scene(scene: &Scene, filename: &str, parallel: bool, bvh: bool) -> RenderTimings {
     let mut rng = thread_rng();
-    let img = match (parallel, bvh) {
-        (true, true) => render_image_parallel(scene, &scene.bvh(&mut rng)),
-        (true, false) => render_image_parallel(scene, &scene.hittables),
-        (false, true) => render_image_sequential(scene, &scene.bvh(&mut rng)),
-        (false, false) => render_image_sequential(scene, &scene.hittables)
+    let start = Instant::now();
+    let world = if bvh { Some(scene.bvh(&mut rng)) } else { None };
+    let bvh_build = start.elapsed();
+
+    let start = Instant::now();
+    let img = match (parallel, &world) {
+        (true, Some(world)) => render_image_parallel(scene, world),
+        (true, None) => render_image_parallel(scene, &scene.hittables),
+        (false, Some(world)) => render_image_sequential(scene, world),
+        (false, None) => render_image_sequential(scene, &scene.hittables)
     };
+    let render = start.elapsed();
 
     img.save(filename).unwrap();
+    RenderTimings { bvh_build, render }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::SmallRng;
+
+    use std::sync::Arc;
+
+    use crate::geometry::Sphere;
+    use crate::material::Material;
+    use crate::point3::Point3;
+    use crate::scene::{RenderConfig, setup_scene};
+    use crate::test_util::{assert_close, ray};
+    use crate::vec3::Vec3;
+
+    use super::*;
+
+    fn empty_scene(rng: &mut dyn RngCore) -> Scene {
+        let render_config = RenderConfig { image_width: 4, image_height: 3, samples_per_pixel: 1, max_depth: 10 };
+        setup_scene(rng, render_config, 0)
+    }
+
+    fn light(yaml: &str) -> Sphere {
+        Sphere { center: Point3::new(0.0, 0.0, -3.0), radius: 1.0, material: serde_yaml::from_str(yaml).unwrap() }
+    }
+
+    #[test]
+    fn lights_are_seen_with_their_emission() {
+        let rng = &mut SmallRng::seed_from_u64(1);
+        let light = light("type: DiffuseLight\nemit: [1.0, 0.5, 0.25]\nintensity: 4.0");
+        let scene = empty_scene(rng);
+        let color = ray_color(rng, &ray(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0)), &light, &scene, 10);
+        assert_eq!((color.r, color.g, color.b), (4.0, 2.0, 1.0));
+    }
+
+    #[test]
+    fn light_intensity_defaults_to_one() {
+        let rng = &mut SmallRng::seed_from_u64(1);
+        let light = light("type: DiffuseLight\nemit: [1.0, 0.5, 0.25]");
+        let scene = empty_scene(rng);
+        let color = ray_color(rng, &ray(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0)), &light, &scene, 10);
+        assert_eq!((color.r, color.g, color.b), (1.0, 0.5, 0.25));
+    }
+
+    #[test]
+    fn power_heuristic_weights_sum_to_one() {
+        assert_eq!(power_heuristic(1.0, 1.0), 0.5);
+        assert_eq!(power_heuristic(2.0, 0.0), 1.0);
+        assert_eq!(power_heuristic(0.0, 0.0), 0.0);
+        for &(pdf, other_pdf) in &[(1.0, 3.0), (0.2, 7.5), (4.0, 0.5)] {
+            assert_close(power_heuristic(pdf, other_pdf) + power_heuristic(other_pdf, pdf), 1.0, 1e-12);
+        }
+        assert_close(power_heuristic(1.0, 3.0), 0.1, 1e-12);
+    }
+
+    #[test]
+    fn only_the_light_hit_weighs_emission() {
+        let rng = &mut SmallRng::seed_from_u64(2);
+        let mut scene = empty_scene(rng);
+        scene.lights = vec![Light::Area(Arc::new(light("type: DiffuseLight\nemit: [1.0, 1.0, 1.0]")))];
+        let blocker = Sphere { center: Point3::new(0.0, 0.0, -6.0), radius: 1.0, material: Material::BlackBody };
+        let ray = ray(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+
+        let light_shape = light("type: BlackBody");
+        let light_rec = light_shape.hit_by(rng, &ray, 0.001, f64::INFINITY).unwrap();
+        assert!(hit_light_pdf(rng, &scene, &ray, &light_rec) > 0.0);
+
+        // A surface behind the light can't have been reached by light sampling.
+        let blocker_rec = blocker.hit_by(rng, &ray, 0.001, f64::INFINITY).unwrap();
+        assert_eq!(hit_light_pdf(rng, &scene, &ray, &blocker_rec), 0.0);
+    }
 }
@@ -0,0 +1,158 @@
+/// Piecewise-constant 1D distribution used to importance sample tabulated functions.
+pub struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>,
+    func_int: f64,
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<f64>) -> Distribution1D {
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 1..=n {
+            cdf[i] = cdf[i - 1] + func[i - 1].abs() / n as f64;
+        }
+
+        let func_int = cdf[n];
+        for (i, c) in cdf.iter_mut().enumerate().skip(1) {
+            *c = if func_int == 0.0 { i as f64 / n as f64 } else { *c / func_int };
+        }
+
+        Distribution1D { func, cdf, func_int }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> f64 {
+        self.func_int
+    }
+
+    fn offset(&self, u: f64) -> usize {
+        let offset = self.cdf.partition_point(|&c| c <= u);
+        offset.saturating_sub(1).min(self.count() - 1)
+    }
+
+    /// Returns a sample in [0, 1), its density and the index of the segment it falls into.
+    pub fn sample_continuous(&self, u: f64) -> (f64, f64, usize) {
+        let offset = self.offset(u);
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0.0 {
+            du /= width;
+        }
+
+        let pdf = self.density(offset);
+        ((offset as f64 + du) / self.count() as f64, pdf, offset)
+    }
+
+    /// Returns a segment index and the probability of picking it.
+    pub fn sample_discrete(&self, u: f64) -> (usize, f64) {
+        let offset = self.offset(u);
+        (offset, self.discrete_pdf(offset))
+    }
+
+    pub fn discrete_pdf(&self, index: usize) -> f64 {
+        self.cdf[index + 1] - self.cdf[index]
+    }
+
+    fn density(&self, index: usize) -> f64 {
+        if self.func_int == 0.0 {
+            1.0
+        } else {
+            self.func[index].abs() / self.func_int
+        }
+    }
+}
+
+/// Piecewise-constant 2D distribution over [0, 1)^2, sampled as a marginal over rows
+/// followed by a conditional distribution within the chosen row.
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(func: &[f64], nu: usize, nv: usize) -> Distribution2D {
+        let conditional = (0..nv)
+            .map(|v| Distribution1D::new(func[v * nu..(v + 1) * nu].to_vec()))
+            .collect::<Vec<_>>();
+        let marginal = Distribution1D::new(conditional.iter().map(|d| d.integral()).collect());
+        Distribution2D { conditional, marginal }
+    }
+
+    pub fn sample_continuous(&self, u0: f64, u1: f64) -> ((f64, f64), f64) {
+        let (v, pdf_v, row) = self.marginal.sample_continuous(u1);
+        let (u, pdf_u, _) = self.conditional[row].sample_continuous(u0);
+        ((u, v), pdf_u * pdf_v)
+    }
+
+    pub fn pdf(&self, u: f64, v: f64) -> f64 {
+        let nv = self.marginal.count();
+        let row = ((v * nv as f64) as usize).min(nv - 1);
+        let nu = self.conditional[row].count();
+        let column = ((u * nu as f64) as usize).min(nu - 1);
+        if self.marginal.integral() == 0.0 {
+            1.0
+        } else {
+            self.conditional[row].func[column].abs() / self.marginal.integral()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::SmallRng;
+
+    use crate::test_util::assert_close;
+
+    use super::*;
+
+    #[test]
+    fn discrete_samples_follow_the_function() {
+        let distribution = Distribution1D::new(vec![1.0, 0.0, 3.0]);
+        assert_eq!(distribution.integral(), 4.0 / 3.0);
+        assert_eq!(distribution.sample_discrete(0.1), (0, 0.25));
+        assert_eq!(distribution.sample_discrete(0.5), (2, 0.75));
+        assert_eq!(distribution.discrete_pdf(1), 0.0);
+
+        let (x, pdf, offset) = distribution.sample_continuous(0.625);
+        assert_close(x, 2.5 / 3.0, 1e-12);
+        assert_eq!((pdf, offset), (2.25, 2));
+    }
+
+    #[test]
+    fn zero_functions_are_sampled_uniformly() {
+        let distribution = Distribution1D::new(vec![0.0; 4]);
+        let (x, pdf, offset) = distribution.sample_continuous(0.6);
+        assert_close(x, 0.6, 1e-12);
+        assert_eq!((pdf, offset), (1.0, 2));
+    }
+
+    #[test]
+    fn samples_2d_match_their_pdf() {
+        let rng = &mut SmallRng::seed_from_u64(3);
+        let (nu, nv) = (4, 3);
+        let func = (0..nu * nv).map(|i| (i % 5) as f64).collect::<Vec<_>>();
+        let distribution = Distribution2D::new(&func, nu, nv);
+        let total = func.iter().sum::<f64>();
+
+        let count = 100000;
+        let mut histogram = vec![0; nu * nv];
+        for _ in 0..count {
+            let ((u, v), pdf) = distribution.sample_continuous(rng.gen(), rng.gen());
+            assert!((0.0..1.0).contains(&u) && (0.0..1.0).contains(&v));
+            assert_close(pdf, distribution.pdf(u, v), 1e-9);
+            histogram[(v * nv as f64) as usize * nu + (u * nu as f64) as usize] += 1;
+        }
+
+        for (cell, &hits) in histogram.iter().enumerate() {
+            assert_close(hits as f64 / count as f64, func[cell] / total, 0.01);
+            // The density is constant over a cell, and integrates to one over the unit square.
+            let (u, v) = ((cell % nu) as f64 + 0.5, (cell / nu) as f64 + 0.5);
+            assert_close(distribution.pdf(u / nu as f64, v / nv as f64), func[cell] / total * (nu * nv) as f64, 1e-12);
+        }
+    }
+}
@@ -1,22 +1,41 @@
 use std::{fs, io};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
 use std::io::{BufReader, Write};
 use std::ops::Range;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use itertools::Itertools;
-use obj::{load_obj, Obj, Vertex};
+use obj::raw::object::{parse_obj, Polygon, RawObj};
 use rand::{Rng, RngCore, SeedableRng};
 use rand::rngs::SmallRng;
 use serde::{Deserialize, Serialize};
 
-use crate::bvh::BVHNode;
+use crate::background::Background;
+use crate::bounding_box::BBox;
+use crate::bvh::{BVHBuilder, SceneBVH};
+use crate::bvh_cache::{BVHCache, mesh_bvh};
 use crate::camera::Camera;
 use crate::color::Color;
-use crate::geometry::{ArcHittable, Parallelepiped, Plane, Sphere, Triangle, TriangleMesh};
+use crate::csg::{Csg, CsgOperation};
+use crate::geometry::{ArcHittable, Hittable, Parallelepiped, Plane, smooth_normals, Sphere, Triangle, TriangleMesh};
+use crate::group::Group;
+use crate::heightfield::Heightfield;
+use crate::light::Light;
 use crate::material::Material;
+use crate::mesh_formats::{read_ply, read_stl};
+use crate::medium::{ConstantMedium, GridMedium, VoxelGrid};
+use crate::motion::{Keyframe, Moving};
+use crate::mtl::read_mtl;
 use crate::point3::Point3;
+use crate::sdf::{Sdf, SdfNode};
+use crate::shapes::{Cone, Cylinder, Disk, Quad, Torus};
+use crate::texture::Texture;
+use crate::transform::{compose, Transform, TransformSpec};
 use crate::vec3::Vec3;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -27,6 +46,11 @@ struct CameraSpec {
     vfov_deg: f64,
     aperture: f64,
     focus_dist: f64,
+    /// Times at which the shutter opens and closes, both zero without motion blur.
+    #[serde(default)]
+    shutter_open: f64,
+    #[serde(default)]
+    shutter_close: f64,
 }
 
 impl CameraSpec {
@@ -40,7 +64,7 @@ impl CameraSpec {
             aspect_ratio,
             self.aperture,
             self.focus_dist,
-        );
+        ).with_shutter(self.shutter_open, self.shutter_close);
     }
 }
 
@@ -59,57 +83,468 @@ pub enum ShapeSpec {
     },
     Triangle {
         vertices: [Point3; 3],
+        #[serde(default)]
+        uvs: Option<[(f64, f64); 3]>,
         material: Material,
     },
     Parallelepiped {
         basis: [Point3; 4],
         material: Material,
     },
+    Disk {
+        center: Point3,
+        normal: Vec3,
+        radius: f64,
+        material: Material,
+    },
+    /// Parallelogram with corners `corner`, `corner + edge1`, `corner + edge2` and
+    /// `corner + edge1 + edge2`.
+    Quad {
+        corner: Point3,
+        edge1: Vec3,
+        edge2: Vec3,
+        material: Material,
+    },
+    /// Cylinder from the disk at `base` to the disk at `base + axis`.
+    Cylinder {
+        base: Point3,
+        axis: Vec3,
+        radius: f64,
+        #[serde(default = "default_capped")]
+        capped: bool,
+        material: Material,
+    },
+    /// Cone from the disk at `base` to the apex at `base + axis`.
+    Cone {
+        base: Point3,
+        axis: Vec3,
+        radius: f64,
+        #[serde(default = "default_capped")]
+        capped: bool,
+        material: Material,
+    },
+    Torus {
+        center: Point3,
+        axis: Vec3,
+        major_radius: f64,
+        minor_radius: f64,
+        material: Material,
+    },
+    /// OBJ, PLY or STL mesh, told apart by the extension of `filename`.
     Object {
         filename: String,
+        /// Used for faces without a `usemtl` material.
+        #[serde(default = "default_object_material")]
+        material: Material,
+        /// Replaces `.mtl` materials by name.
+        #[serde(default)]
+        materials: HashMap<String, Material>,
+        /// Renames `usemtl` materials before they're looked up.
+        #[serde(default)]
+        material_remap: HashMap<String, String>,
+        /// Faces meeting at a sharper angle keep a hard edge when normals are generated.
+        #[serde(default = "default_smoothing_angle")]
+        smoothing_angle_deg: f64,
+    },
+    Transform {
+        transform: Vec<TransformSpec>,
+        object: Box<ShapeSpec>,
+    },
+    /// Places a shape declared under `meshes`, which is only built once however many
+    /// instances reference it.
+    Instance {
+        mesh: String,
+        #[serde(default)]
+        transform: Vec<TransformSpec>,
+    },
+    /// Sub-assembly whose transform applies on top of the transforms of its children.
+    Group {
+        #[serde(default)]
+        transform: Vec<TransformSpec>,
+        objects: Vec<ShapeSpec>,
+    },
+    /// Shape whose transform is interpolated between keyframes over the camera shutter
+    /// interval. Every keyframe must list the same kinds of transforms in the same order.
+    Moving {
+        keyframes: Vec<Keyframe>,
+        object: Box<ShapeSpec>,
+    },
+    /// Terrain from a grayscale image, see `Heightfield`.
+    Heightfield {
+        filename: String,
+        origin: Point3,
+        /// Extent along X and Z.
+        size: (f64, f64),
+        height_scale: f64,
+        material: Material,
+    },
+    /// Union, intersection or difference of two closed shapes.
+    Csg {
+        operation: CsgOperation,
+        left: Box<ShapeSpec>,
+        right: Box<ShapeSpec>,
+    },
+    /// Signed distance field rendered by sphere tracing within `bounds`, given as its min and
+    /// max corners.
+    Sdf {
+        sdf: SdfNode,
+        bounds: [Point3; 2],
+        #[serde(default = "default_step_scale")]
+        step_scale: f64,
         material: Material,
     },
+    /// Fog or smoke of uniform `density` filling the closed shape `boundary`, with an
+    /// `Isotropic` or `HenyeyGreenstein` material as its phase function.
+    ConstantMedium {
+        boundary: Box<ShapeSpec>,
+        density: f64,
+        material: Material,
+    },
+    /// Heterogeneous medium read from a voxel grid file, see `VoxelGrid::load`.
+    GridMedium {
+        filename: String,
+        #[serde(default = "default_density_scale")]
+        density_scale: f64,
+        albedo: Color,
+        /// Henyey-Greenstein asymmetry, scattering is isotropic when zero.
+        #[serde(default)]
+        g: f64,
+    },
+}
+
+fn default_object_material() -> Material {
+    Material::Lambertian { albedo: Texture::Constant(Color::new(0.8, 0.8, 0.8)) }
+}
+
+fn default_capped() -> bool {
+    true
+}
+
+fn default_step_scale() -> f64 {
+    1.0
+}
+
+fn default_density_scale() -> f64 {
+    1.0
+}
+
+fn default_smoothing_angle() -> f64 {
+    60.0
+}
+
+/// Resolves the material of every polygon of an OBJ file from its `usemtl` name, looking in
+/// the YAML overrides first and then in the `.mtl` libraries next to the OBJ file. Libraries
+/// and textures that can't be read leave `default` in place and add to `warnings`.
+fn obj_polygon_materials(
+    filename: &str,
+    object: &RawObj,
+    default: &Material,
+    overrides: &HashMap<String, Material>,
+    remap: &HashMap<String, String>,
+    warnings: &mut Vec<String>,
+) -> Vec<Material> {
+    let directory = Path::new(filename).parent().unwrap_or_else(|| Path::new(""));
+    let mut library = HashMap::new();
+    for mtllib in object.material_libraries.iter() {
+        let path = directory.join(mtllib);
+        match read_mtl(&path) {
+            Ok(materials) => library.extend(materials),
+            Err(e) => warnings.push(format!("could not read material library {}: {}", path.display(), e))
+        }
+    }
+
+    let mut materials = vec![default.clone(); object.polygons.len()];
+    for (name, group) in object.meshes.iter() {
+        let name = remap.get(name).unwrap_or(name);
+        let material = match (overrides.get(name), library.get(name)) {
+            (Some(material), _) => material.clone(),
+            (None, Some(mtl)) => mtl.to_material().unwrap_or_else(|e| {
+                warnings.push(format!("could not load material {} of {}: {}", name, filename, e));
+                default.clone()
+            }),
+            (None, None) => default.clone(),
+        };
+        for range in group.polygons.iter() {
+            for polygon_material in materials[range.start..range.end].iter_mut() {
+                *polygon_material = material.clone();
+            }
+        }
+    }
+
+    materials
 }
 
-fn read_obj(filename: &String, material: Material) -> TriangleMesh {
-    let input = BufReader::new(File::open(filename).unwrap());
-    let object: Obj<Vertex, usize> = load_obj(input).unwrap();
-    let vertices =
-        object.vertices.iter()
-            .map(|v|
-                Point3::new(
-                    v.position[0] as f64,
-                    v.position[1] as f64,
-                    v.position[2] as f64,
-                )
-            )
-            .collect_vec();
-    TriangleMesh::new(&vertices, &object.indices, material)
+/// Loads the triangles of a mesh file with the reader for its extension, OBJ being the default.
+/// Material overrides only apply to OBJ files, which are the only ones with named materials.
+fn read_mesh(
+    filename: &str,
+    material: &Material,
+    overrides: &HashMap<String, Material>,
+    remap: &HashMap<String, String>,
+    smoothing_angle_deg: f64,
+    warnings: &mut Vec<String>,
+) -> Result<Vec<Triangle>, Box<dyn Error>> {
+    let extension = Path::new(filename).extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "ply" => read_ply(filename, material, smoothing_angle_deg),
+        "stl" => read_stl(filename, material, smoothing_angle_deg),
+        _ => read_obj(filename, material, overrides, remap, smoothing_angle_deg, warnings)
+    }
+}
+
+/// Loads the triangles of an OBJ file, fanning out polygons into triangles. Faces without
+/// per-vertex `vn` normals get normals generated from their neighbours, unless
+/// `smoothing_angle_deg` is 0. Faces whose `.mtl` material can't be loaded get `material`.
+pub fn read_obj(
+    filename: &str,
+    material: &Material,
+    overrides: &HashMap<String, Material>,
+    remap: &HashMap<String, String>,
+    smoothing_angle_deg: f64,
+    warnings: &mut Vec<String>,
+) -> Result<Vec<Triangle>, Box<dyn Error>> {
+    let input = BufReader::new(File::open(filename)?);
+    let object = parse_obj(input)?;
+    let polygon_materials = obj_polygon_materials(filename, &object, material, overrides, remap, warnings);
+    let positions = object.positions.iter()
+        .map(|&(x, y, z, _)| Point3::new(x as f64, y as f64, z as f64))
+        .collect_vec();
+    let tex_coords = object.tex_coords.iter()
+        .map(|&(u, v, _)| (u as f64, v as f64))
+        .collect_vec();
+    let normals = object.normals.iter()
+        .map(|&(x, y, z)| Vec3::new(x as f64, y as f64, z as f64).normalize())
+        .collect_vec();
+
+    // Position, texture coordinate and normal indices of every triangle corner.
+    let mut corners: Vec<(usize, Option<usize>, Option<usize>)> = vec![];
+    let mut face_polygons = vec![];
+    for (polygon_index, polygon) in object.polygons.iter().enumerate() {
+        let polygon_corners = match polygon {
+            Polygon::P(ps) => ps.iter().map(|&p| (p, None, None)).collect_vec(),
+            Polygon::PT(ps) => ps.iter().map(|&(p, t)| (p, Some(t), None)).collect_vec(),
+            Polygon::PN(ps) => ps.iter().map(|&(p, n)| (p, None, Some(n))).collect_vec(),
+            Polygon::PTN(ps) => ps.iter().map(|&(p, t, n)| (p, Some(t), Some(n))).collect_vec(),
+        };
+        for i in 1..polygon_corners.len().saturating_sub(1) {
+            corners.push(polygon_corners[0]);
+            corners.push(polygon_corners[i]);
+            corners.push(polygon_corners[i + 1]);
+            face_polygons.push(polygon_index);
+        }
+    }
+
+    let vertices_index = corners.iter().map(|corner| corner.0).collect_vec();
+    let generated_normals = if smoothing_angle_deg > 0.0 {
+        Some(smooth_normals(&positions, &vertices_index, smoothing_angle_deg))
+    } else {
+        None
+    };
+
+    let triangles = corners.chunks_exact(3).enumerate()
+        .map(|(i, face)| {
+            let uvs = match (face[0].1, face[1].1, face[2].1) {
+                (Some(a), Some(b), Some(c)) => Some([tex_coords[a], tex_coords[b], tex_coords[c]]),
+                _ => None
+            };
+            // A face whose corners all reference the same `vn` only carries its flat normal.
+            let face_normals = match (face[0].2, face[1].2, face[2].2) {
+                (Some(a), Some(b), Some(c)) if a != b || b != c => Some([normals[a], normals[b], normals[c]]),
+                _ => generated_normals.as_ref()
+                    .map(|normals| [normals[3 * i], normals[3 * i + 1], normals[3 * i + 2]])
+            };
+            Triangle {
+                vertices: [positions[face[0].0], positions[face[1].0], positions[face[2].0]],
+                uvs,
+                normals: face_normals,
+                material: polygon_materials[face_polygons[i]].clone(),
+            }
+        })
+        .collect_vec();
+    Ok(triangles)
 }
 
 impl ShapeSpec {
-    fn to_hittable(&self) -> ArcHittable {
-        match self {
+    fn to_hittable(&self, context: &BuildContext) -> Result<ArcHittable, Box<dyn Error>> {
+        let hittable: ArcHittable = match self {
             ShapeSpec::Sphere { center, radius, material } => Arc::new(Sphere {
                 center: *center,
                 radius: *radius,
-                material: *material,
+                material: material.clone(),
             }),
             ShapeSpec::Plane { center, normal, material } => Arc::new(Plane {
                 center: *center,
                 normal: *normal,
-                material: *material,
+                material: material.clone(),
             }),
-            ShapeSpec::Triangle { vertices, material } => Arc::new(Triangle {
+            ShapeSpec::Triangle { vertices, uvs, material } => Arc::new(Triangle {
                 vertices: *vertices,
-                material: *material,
+                uvs: *uvs,
+                normals: None,
+                material: material.clone(),
             }),
             ShapeSpec::Parallelepiped { basis, material } => Arc::new(
-                Parallelepiped::new(basis[0], basis[1], basis[2], basis[3], *material)
+                Parallelepiped::new(basis[0], basis[1], basis[2], basis[3], material.clone())
             ),
-            ShapeSpec::Object { filename, material } => Arc::new(
-                read_obj(filename, *material)
-            )
+            ShapeSpec::Disk { center, normal, radius, material } => Arc::new(
+                Disk::new(*center, *normal, *radius, material.clone())
+            ),
+            ShapeSpec::Quad { corner, edge1, edge2, material } => Arc::new(
+                Quad::new(*corner, *edge1, *edge2, material.clone())
+            ),
+            ShapeSpec::Cylinder { base, axis, radius, capped, material } => Arc::new(
+                Cylinder::new(*base, *axis, *radius, *capped, material.clone())
+            ),
+            ShapeSpec::Cone { base, axis, radius, capped, material } => Arc::new(
+                Cone::new(*base, *axis, *radius, *capped, material.clone())
+            ),
+            ShapeSpec::Torus { center, axis, major_radius, minor_radius, material } => Arc::new(
+                Torus::new(*center, *axis, *major_radius, *minor_radius, material.clone())
+            ),
+            ShapeSpec::Object { filename, material, materials, material_remap, smoothing_angle_deg } => {
+                let mut warnings = vec![];
+                let triangles = read_mesh(filename, material, materials, material_remap, *smoothing_angle_deg, &mut warnings)
+                    .map_err(|e| format!("could not read mesh {}: {}", filename, e))?;
+                let bvh = context.timed(|| {
+                    mesh_bvh(filename, triangles, context.bvh_builder, context.bvh_cache, &mut warnings)
+                });
+                context.warnings.borrow_mut().extend(warnings);
+                Arc::new(TriangleMesh::from_bvh(bvh))
+            }
+            ShapeSpec::Transform { transform, object } => transformed(object.to_hittable(context)?, transform)?,
+            ShapeSpec::Instance { mesh, transform } => match context.meshes.get(mesh) {
+                Some(mesh) => transformed(mesh.clone(), transform)?,
+                None => return Err(format!("unknown mesh {}", mesh).into())
+            },
+            ShapeSpec::Group { transform, objects } => {
+                if objects.is_empty() {
+                    return Err("a group needs at least one object".into());
+                }
+                let children = objects.iter()
+                    .map(|o| o.to_hittable(context))
+                    .collect::<Result<Vec<_>, _>>()?;
+                transformed(Arc::new(context.timed(|| Group::new(children, context.bvh_builder))), transform)?
+            }
+            ShapeSpec::Moving { keyframes, object } => {
+                Arc::new(Moving::new(object.to_hittable(context)?, keyframes.clone())?)
+            }
+            ShapeSpec::Heightfield { filename, origin, size, height_scale, material } => Arc::new(
+                Heightfield::load(filename, *origin, *size, *height_scale, material.clone())
+                    .map_err(|e| format!("could not read heightfield {}: {}", filename, e))?
+            ),
+            ShapeSpec::Csg { operation, left, right } => {
+                Arc::new(Csg::new(*operation, left.to_hittable(context)?, right.to_hittable(context)?))
+            }
+            ShapeSpec::Sdf { sdf, bounds, step_scale, material } => Arc::new(
+                Sdf::new(sdf.clone(), BBox { min: bounds[0], max: bounds[1] }, *step_scale, material.clone())
+            ),
+            ShapeSpec::ConstantMedium { boundary, density, material } => {
+                Arc::new(ConstantMedium::new(boundary.to_hittable(context)?, *density, material.clone()))
+            }
+            ShapeSpec::GridMedium { filename, density_scale, albedo, g } => {
+                let grid = VoxelGrid::load(filename)
+                    .map_err(|e| format!("could not read voxel grid {}: {}", filename, e))?;
+                let albedo = Texture::Constant(*albedo);
+                let phase_function = if *g == 0.0 {
+                    Material::Isotropic { albedo }
+                } else {
+                    Material::HenyeyGreenstein { albedo, g: *g }
+                };
+                Arc::new(GridMedium::new(grid, *density_scale, phase_function))
+            }
+        };
+        Ok(hittable)
+    }
+}
+
+/// State shared by the shapes of a scene while they're built.
+struct BuildContext {
+    /// Meshes declared once in the scene, which `Instance` shapes refer to by name.
+    meshes: HashMap<String, ArcHittable>,
+    warnings: RefCell<Vec<String>>,
+    bvh_builder: BVHBuilder,
+    bvh_cache: BVHCache,
+    bvh_build_time: Cell<Duration>,
+}
+
+impl BuildContext {
+    fn new(bvh_builder: BVHBuilder, bvh_cache: BVHCache) -> BuildContext {
+        BuildContext {
+            meshes: HashMap::new(),
+            warnings: RefCell::new(vec![]),
+            bvh_builder,
+            bvh_cache,
+            bvh_build_time: Cell::new(Duration::default()),
+        }
+    }
+
+    /// Runs `build`, counting its duration as BVH construction time.
+    fn timed<R>(&self, build: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+        let result = build();
+        self.bvh_build_time.set(self.bvh_build_time.get() + start.elapsed());
+        result
+    }
+}
+
+fn transformed(hittable: ArcHittable, transform: &[TransformSpec]) -> Result<ArcHittable, Box<dyn Error>> {
+    if transform.is_empty() {
+        return Ok(hittable);
+    }
+
+    match Transform::new(hittable, compose(transform)) {
+        Some(transform) => Ok(Arc::new(transform)),
+        None => Err(format!("transform {:?} is not invertible", transform).into())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum LightSpec {
+    Point {
+        position: Point3,
+        intensity: Color,
+    },
+    Spot {
+        position: Point3,
+        direction: Vec3,
+        intensity: Color,
+        inner_angle_deg: f64,
+        outer_angle_deg: f64,
+        #[serde(default = "default_falloff")]
+        falloff: f64,
+    },
+    Directional {
+        direction: Vec3,
+        irradiance: Color,
+        #[serde(default)]
+        angular_radius_deg: f64,
+    },
+}
+
+fn default_falloff() -> f64 {
+    1.0
+}
+
+impl LightSpec {
+    fn to_light(&self) -> Light {
+        match self {
+            LightSpec::Point { position, intensity } => Light::Point {
+                position: *position,
+                intensity: *intensity,
+            },
+            LightSpec::Spot { position, direction, intensity, inner_angle_deg, outer_angle_deg, falloff } => Light::Spot {
+                position: *position,
+                direction: direction.normalize(),
+                intensity: *intensity,
+                cos_inner: inner_angle_deg.to_radians().cos(),
+                cos_outer: outer_angle_deg.to_radians().cos(),
+                falloff: *falloff,
+            },
+            LightSpec::Directional { direction, irradiance, angular_radius_deg } =>
+                Light::directional(*direction, *irradiance, *angular_radius_deg),
         }
     }
 }
@@ -126,36 +561,85 @@ pub struct RenderConfig {
 struct SceneSpec {
     pub render_config: RenderConfig,
     pub camera: CameraSpec,
+    #[serde(default)]
+    pub background: Background,
+    #[serde(default)]
+    pub lights: Vec<LightSpec>,
+    /// Shapes that can be placed any number of times with `Instance`.
+    #[serde(default)]
+    pub meshes: HashMap<String, ShapeSpec>,
     pub objects: Vec<ShapeSpec>,
+    #[serde(default)]
+    pub bvh: BVHBuilder,
 }
 
 impl SceneSpec {
-    fn scene(self) -> Scene {
-        return Scene {
+    fn scene(self, bvh_cache: BVHCache) -> Result<Scene, Box<dyn Error>> {
+        let mut context = BuildContext::new(self.bvh, bvh_cache);
+        context.meshes = self.meshes.iter()
+            .map(|(name, spec)| Ok((name.clone(), spec.to_hittable(&context)?)))
+            .collect::<Result<HashMap<_, _>, Box<dyn Error>>>()?;
+        let hittables = self.objects.iter()
+            .map(|o| o.to_hittable(&context))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut lights = collect_lights(&hittables, &self.background);
+        lights.extend(self.lights.iter().map(|l| l.to_light()));
+        Ok(Scene {
             render_config: self.render_config,
             camera: self.camera.to_camera(self.render_config),
-            hittables: self.objects.iter().map(|o| o.to_hittable()).collect_vec(),
-        };
+            background: self.background,
+            hittables,
+            lights,
+            warnings: context.warnings.into_inner(),
+            bvh_builder: self.bvh,
+            bvh_build_time: context.bvh_build_time.get(),
+        })
     }
 }
 
+/// Emissive shapes with a finite area, and the environment map if it's importance sampled.
+fn collect_lights(hittables: &[ArcHittable], background: &Background) -> Vec<Light> {
+    let mut lights = hittables.iter()
+        .filter(|hittable| hittable.is_emissive() && hittable.area() > 0.0)
+        .map(|hittable| Light::Area(hittable.clone()))
+        .collect_vec();
+
+    if let Some(map) = background.sampled_environment() {
+        lights.push(Light::Environment(map.clone()));
+    }
+
+    lights
+}
+
 pub struct Scene {
     pub render_config: RenderConfig,
     pub camera: Camera,
+    pub background: Background,
     pub hittables: Vec<ArcHittable>,
+    pub lights: Vec<Light>,
+    /// Problems that didn't stop the scene from loading, like missing `.mtl` files.
+    pub warnings: Vec<String>,
+    pub bvh_builder: BVHBuilder,
+    /// Time spent building the BVHs of meshes and groups while the scene was set up.
+    pub bvh_build_time: Duration,
 }
 
 impl Scene {
-    pub fn bvh(&self, rng: &mut dyn RngCore) -> BVHNode<ArcHittable> {
-        let mut shapes = self.hittables.clone();
-        BVHNode::from_shapes(rng, shapes.as_mut_slice())
+    /// Top-level BVH over the scene objects. Groups and meshes carry their own BVH, which
+    /// the top level treats as a single leaf.
+    pub fn bvh(&self, rng: &mut dyn RngCore) -> SceneBVH<ArcHittable> {
+        SceneBVH::build(rng, self.bvh_builder, &self.hittables)
     }
 }
 
-pub fn read_scene(filename: &str) -> Result<Scene, Box<dyn Error>> {
+/// Reads a scene, with `bvh_builder` taking precedence over the builder set in the file.
+pub fn read_scene(filename: &str, bvh_builder: Option<BVHBuilder>, bvh_cache: BVHCache) -> Result<Scene, Box<dyn Error>> {
     let contents = fs::read_to_string(filename)?;
-    let scene_spec: SceneSpec = serde_yaml::from_str(contents.as_str())?;
-    Ok(scene_spec.scene())
+    let mut scene_spec: SceneSpec = serde_yaml::from_str(contents.as_str())?;
+    if let Some(bvh_builder) = bvh_builder {
+        scene_spec.bvh = bvh_builder;
+    }
+    scene_spec.scene(bvh_cache)
 }
 
 fn random_large_scene_spec(rng: &mut dyn RngCore) -> SceneSpec {
@@ -165,7 +649,7 @@ fn random_large_scene_spec(rng: &mut dyn RngCore) -> SceneSpec {
         radius: 1000.0,
         center: Point3::new(0.0, -1000.0, -1.0),
         material: Material::Lambertian {
-            albedo: Color::new(0.5, 0.5, 0.5)
+            albedo: Texture::Constant(Color::new(0.5, 0.5, 0.5))
         },
     });
 
@@ -184,13 +668,13 @@ fn random_large_scene_spec(rng: &mut dyn RngCore) -> SceneSpec {
                 let material: Material = if choose_mat < 0.8 {
                     let albedo = Color::random(rng) * Color::random(rng);
                     Material::Lambertian {
-                        albedo,
+                        albedo: Texture::Constant(albedo),
                     }
                 } else if choose_mat < 0.95 {
                     let albedo = Color::random(rng) / 2.0 + 0.5;
                     let fuzz = rng.gen_range(0.0..0.5);
                     Material::Metal {
-                        albedo,
+                        albedo: Texture::Constant(albedo),
                         fuzz,
                     }
                 } else {
@@ -220,7 +704,7 @@ fn random_large_scene_spec(rng: &mut dyn RngCore) -> SceneSpec {
         radius: 1.0,
         center: Point3::new(-4.0, 1.0, 0.0),
         material: Material::Lambertian {
-            albedo: Color::new(0.4, 0.2, 0.1),
+            albedo: Texture::Constant(Color::new(0.4, 0.2, 0.1)),
         },
     });
 
@@ -228,7 +712,7 @@ fn random_large_scene_spec(rng: &mut dyn RngCore) -> SceneSpec {
         radius: 1.0,
         center: Point3::new(4.0, 1.0, 0.0),
         material: Material::Metal {
-            albedo: Color::new(0.7, 0.6, 0.5),
+            albedo: Texture::Constant(Color::new(0.7, 0.6, 0.5)),
             fuzz: 0.0,
         },
     });
@@ -247,25 +731,31 @@ fn random_large_scene_spec(rng: &mut dyn RngCore) -> SceneSpec {
             focus_dist: 10.0,
             aperture: 0.1,
             vfov_deg: 20.0,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
         },
+        background: Background::default(),
+        lights: vec![],
+        meshes: HashMap::new(),
         objects,
+        bvh: BVHBuilder::default(),
     };
 }
 
 pub fn setup_small_scene(render_config: RenderConfig) -> Scene {
-    let world = vec![
+    let objects = [
         ShapeSpec::Plane {
             center: Point3::new(0.0, -0.5, 0.0),
             normal: Vec3::new(0.0, 1.0, 0.0),
             material: Material::Lambertian {
-                albedo: Color::new(0.1, 0.2, 0.5),
+                albedo: Texture::Constant(Color::new(0.1, 0.2, 0.5)),
             },
         },
         ShapeSpec::Sphere {
             center: Point3::new(0.0, 0.0, -1.0),
             radius: 0.5,
             material: Material::Lambertian {
-                albedo: Color::new(0.1, 0.2, 0.5),
+                albedo: Texture::Constant(Color::new(0.1, 0.2, 0.5)),
             },
         },
         ShapeSpec::Sphere {
@@ -286,11 +776,15 @@ pub fn setup_small_scene(render_config: RenderConfig) -> Scene {
             center: Point3::new(1.0, 0.0, -1.0),
             radius: 0.5,
             material: Material::Metal {
-                albedo: Color::new(0.1, 0.2, 0.5),
+                albedo: Texture::Constant(Color::new(0.1, 0.2, 0.5)),
                 fuzz: 0.0,
             },
         },
-    ].iter().map(|s| s.to_hittable()).collect_vec();
+    ];
+    let context = BuildContext::new(BVHBuilder::default(), BVHCache::Disabled);
+    let world = objects.iter().map(|s| s.to_hittable(&context).unwrap()).collect_vec();
+    let background = Background::default();
+    let lights = collect_lights(&world, &background);
 
     let camera = Camera::create(
         Point3::new(-2.0, 2.0, 1.0),
@@ -304,7 +798,12 @@ pub fn setup_small_scene(render_config: RenderConfig) -> Scene {
 
     Scene {
         camera,
+        background,
         hittables: world,
+        lights,
+        warnings: vec![],
+        bvh_builder: BVHBuilder::default(),
+        bvh_build_time: Duration::default(),
         render_config,
     }
 }
@@ -318,7 +817,7 @@ fn random_sphere(rng: &mut dyn RngCore, coord_range: Range<f64>, radius_range: R
     );
 
     let material = Material::Lambertian {
-        albedo: Color::new(0.1, 0.2, 0.5),
+        albedo: Texture::Constant(Color::new(0.1, 0.2, 0.5)),
     };
 
     Sphere {
@@ -347,14 +846,19 @@ pub fn setup_scene(rng: &mut dyn RngCore, render_config: RenderConfig, num_spher
 
     Scene {
         camera,
+        background: Background::default(),
         hittables: world,
+        lights: vec![],
+        warnings: vec![],
+        bvh_builder: BVHBuilder::default(),
+        bvh_build_time: Duration::default(),
         render_config,
     }
 }
 
 
 pub fn random_large_scene(rng: &mut dyn RngCore) -> Scene {
-    return random_large_scene_spec(rng).scene();
+    return random_large_scene_spec(rng).scene(BVHCache::Disabled).unwrap();
 }
 
 fn _write_scene_spec(filename: &str, scene_spec: &SceneSpec) -> Result<(), io::Error> {
@@ -365,4 +869,206 @@ fn _write_scene_spec(filename: &str, scene_spec: &SceneSpec) -> Result<(), io::E
 pub fn _write_large_random_scene(filename: &str) -> Result<(), io::Error> {
     let spec = random_large_scene_spec(&mut SmallRng::from_entropy());
     _write_scene_spec(filename, &spec)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    use crate::test_util::{assert_close, assert_vec_close, ray, temp_path};
+
+    use super::*;
+
+    /// Writes `files` to a fresh directory and reads `mesh.obj` from it.
+    fn read_test_obj(name: &str, files: &[(&str, &str)], smoothing_angle_deg: f64) -> (TriangleMesh, Vec<String>) {
+        let directory = temp_path(name);
+        fs::create_dir_all(&directory).unwrap();
+        for (filename, contents) in files {
+            fs::write(directory.join(filename), contents).unwrap();
+        }
+
+        let mut warnings = vec![];
+        let obj = directory.join("mesh.obj").to_str().unwrap().to_string();
+        let triangles = read_obj(&obj, &Material::BlackBody, &HashMap::new(), &HashMap::new(), smoothing_angle_deg, &mut warnings).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        (TriangleMesh::from_triangles(triangles, BVHBuilder::default()), warnings)
+    }
+
+    fn material_at_origin(mesh: &TriangleMesh) -> Material {
+        let rec = mesh.hit_by(&mut SmallRng::seed_from_u64(0), &ray(Point3::new(0.25, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f64::INFINITY).unwrap();
+        rec.material.clone()
+    }
+
+    #[test]
+    fn obj_polygons_are_fanned_with_their_uvs_and_normals() {
+        let rng = &mut SmallRng::seed_from_u64(0);
+        // A tilted quad, with the same normal at every corner.
+        let source = "v 0 0 0\nv 1 0 0.5\nv 1 1 0.5\nv 0 1 0\n\
+                      vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\n\
+                      vn -1 0 2\n\
+                      f 1/1/1 2/2/1 3/3/1 4/4/1\n";
+        let (mesh, _) = read_test_obj("quad", &[("mesh.obj", source)], 0.0);
+        assert_close(mesh.area(), 1.25f64.sqrt(), 1e-9);
+
+        for &(x, y) in &[(0.75, 0.25), (0.25, 0.75)] {
+            let rec = mesh.hit_by(rng, &ray(Point3::new(x, y, 2.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f64::INFINITY).unwrap();
+            assert_close(rec.u, x, 1e-9);
+            assert_close(rec.v, y, 1e-9);
+            assert_vec_close(rec.normal, Vec3::new(-1.0, 0.0, 2.0).normalize(), 1e-9);
+        }
+    }
+
+    #[test]
+    fn obj_faces_without_normals_are_smoothed() {
+        let rng = &mut SmallRng::seed_from_u64(0);
+        let source = "v 0 0 0\nv 1 0 0\nv 0 1 0\nv -1 0 0.2\nf 1 2 3\nf 1 3 4\n";
+        let (flat, _) = read_test_obj("flat", &[("mesh.obj", source)], 0.0);
+        let (smooth, _) = read_test_obj("smooth", &[("mesh.obj", source)], 60.0);
+
+        // Right next to the shared vertex the smoothed normal leans towards the other face.
+        let down = ray(Point3::new(0.01, 0.01, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let flat_normal = flat.hit_by(rng, &down, 0.001, f64::INFINITY).unwrap().normal;
+        let smooth_normal = smooth.hit_by(rng, &down, 0.001, f64::INFINITY).unwrap().normal;
+        assert_vec_close(flat_normal, Vec3::new(0.0, 0.0, 1.0), 1e-9);
+        assert!(smooth_normal[0] > 0.05);
+    }
+
+    const TRIANGLE: &str = "v 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl red\nf 1 2 3\n";
+
+    #[test]
+    fn mtl_material_is_used() {
+        let obj = format!("mtllib mesh.mtl\n{}", TRIANGLE);
+        let (mesh, warnings) = read_test_obj("mtl", &[("mesh.obj", &obj), ("mesh.mtl", "newmtl red\nKd 1 0 0\n")], 0.0);
+
+        match material_at_origin(&mesh) {
+            Material::Lambertian { albedo: Texture::Constant(color) } => assert_eq!((color.r, color.g, color.b), (1.0, 0.0, 0.0)),
+            material => panic!("expected a red lambertian, got {:?}", material)
+        }
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn yaml_overrides_replace_mtl_materials_after_remapping() {
+        let obj = format!("mtllib mesh.mtl\n{}", TRIANGLE);
+        let path = temp_path("overrides");
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("mesh.obj"), obj).unwrap();
+        fs::write(path.join("mesh.mtl"), "newmtl red\nKd 1 0 0\n").unwrap();
+
+        let overrides = vec![("glass".to_string(), Material::Dielectric { index_of_refraction: 1.5 })].into_iter().collect();
+        let remap = vec![("red".to_string(), "glass".to_string())].into_iter().collect();
+        let filename = path.join("mesh.obj").to_str().unwrap().to_string();
+        let triangles = read_obj(&filename, &Material::BlackBody, &overrides, &remap, 0.0, &mut vec![]).unwrap();
+        let mesh = TriangleMesh::from_triangles(triangles, BVHBuilder::default());
+        fs::remove_dir_all(&path).unwrap();
+
+        assert!(matches!(material_at_origin(&mesh), Material::Dielectric { .. }));
+    }
+
+    #[test]
+    fn missing_mtl_file_falls_back_to_object_material() {
+        let obj = format!("mtllib missing.mtl\n{}", TRIANGLE);
+        let (mesh, warnings) = read_test_obj("missing_mtl", &[("mesh.obj", &obj)], 0.0);
+
+        assert!(matches!(material_at_origin(&mesh), Material::BlackBody));
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("missing.mtl"));
+    }
+
+    #[test]
+    fn missing_texture_falls_back_to_object_material() {
+        let obj = format!("mtllib mesh.mtl\n{}", TRIANGLE);
+        let mtl = "newmtl red\nKd 1 0 0\nmap_Kd missing.png\n";
+        let (mesh, warnings) = read_test_obj("missing_texture", &[("mesh.obj", &obj), ("mesh.mtl", mtl)], 0.0);
+
+        assert!(matches!(material_at_origin(&mesh), Material::BlackBody));
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("red"));
+    }
+
+    fn scene_from(meshes_and_objects: &str) -> Result<Scene, Box<dyn Error>> {
+        let yaml = format!("\
+render_config: {{image_width: 4, image_height: 3, samples_per_pixel: 1, max_depth: 4}}
+camera: {{lookfrom: [0, 0, 0], lookat: [0, 0, -1], vup: [0, 1, 0], vfov_deg: 90, aperture: 0, focus_dist: 1}}
+{}", meshes_and_objects);
+        serde_yaml::from_str::<SceneSpec>(&yaml)?.scene(BVHCache::Disabled)
+    }
+
+    #[test]
+    fn instances_place_shared_meshes() {
+        let rng = &mut SmallRng::seed_from_u64(0);
+        let scene = scene_from("
+meshes:
+  ball: {type: Sphere, center: [0, 0, 0], radius: 1, material: {type: BlackBody}}
+objects:
+  - {type: Instance, mesh: ball, transform: [{type: Translate, offset: [0, 0, -5]}]}
+  - {type: Instance, mesh: ball, transform: [{type: Translate, offset: [3, 0, -5]}]}").unwrap();
+
+        assert_eq!(scene.hittables.len(), 2);
+        let rec = scene.hittables[1].hit_by(rng, &ray(Point3::new(3.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f64::INFINITY).unwrap();
+        assert_close(rec.t, 4.0, 1e-9);
+    }
+
+    #[test]
+    fn unknown_meshes_and_singular_transforms_are_errors() {
+        let unknown = scene_from("objects:\n  - {type: Instance, mesh: missing, transform: []}");
+        assert!(unknown.err().unwrap().to_string().contains("missing"));
+
+        let singular = scene_from("
+objects:
+  - type: Transform
+    transform: [{type: Scale, factors: [1, 0, 1]}]
+    object: {type: Sphere, center: [0, 0, 0], radius: 1, material: {type: BlackBody}}");
+        assert!(singular.err().unwrap().to_string().contains("not invertible"));
+    }
+
+    #[test]
+    fn unreadable_voxel_grids_are_errors() {
+        let missing = scene_from("objects:\n  - {type: GridMedium, filename: missing.vol, albedo: [1, 1, 1]}");
+        assert!(missing.err().unwrap().to_string().contains("could not read voxel grid missing.vol"));
+    }
+
+    #[test]
+    fn unreadable_heightfields_are_errors() {
+        let missing = scene_from("
+objects:
+  - type: Heightfield
+    filename: missing.png
+    origin: [0, 0, 0]
+    size: [1, 1]
+    height_scale: 1
+    material: {type: BlackBody}");
+        assert!(missing.err().unwrap().to_string().contains("could not read heightfield missing.png"));
+    }
+
+    #[test]
+    fn unreadable_meshes_are_errors() {
+        for filename in ["missing.obj", "missing.ply", "missing.stl"].iter() {
+            let missing = scene_from(&format!("objects:\n  - {{type: Object, filename: {}}}", filename));
+            assert!(missing.err().unwrap().to_string().contains(&format!("could not read mesh {}", filename)));
+        }
+    }
+
+    #[test]
+    fn groups_nest_with_their_own_transforms() {
+        let rng = &mut SmallRng::seed_from_u64(0);
+        let scene = scene_from("
+objects:
+  - type: Group
+    transform: [{type: Translate, offset: [0, 0, -5]}]
+    objects:
+      - {type: Sphere, center: [0, 0, 0], radius: 1, material: {type: BlackBody}}
+      - type: Group
+        transform: [{type: Translate, offset: [3, 0, 0]}]
+        objects:
+          - {type: Sphere, center: [0, 0, 0], radius: 1, material: {type: BlackBody}}").unwrap();
+
+        assert_eq!(scene.hittables.len(), 1);
+        let rec = scene.hittables[0].hit_by(rng, &ray(Point3::new(3.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f64::INFINITY).unwrap();
+        assert_close(rec.t, 4.0, 1e-9);
+
+        let empty = scene_from("objects:\n  - {type: Group, transform: [], objects: []}");
+        assert!(empty.is_err());
+    }
+}
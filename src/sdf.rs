@@ -0,0 +1,310 @@
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::bounding_box::BBox;
+use crate::geometry::{HitRecord, Hittable};
+use crate::material::Material;
+use crate::point3::Point3;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+const MAX_STEPS: usize = 512;
+/// Distance to the surface under which a point is on it.
+const SURFACE_EPSILON: f64 = 1e-4;
+/// Step of the central differences giving normals.
+const NORMAL_EPSILON: f64 = 1e-4;
+
+/// Signed distance function, negative inside the shape. Primitives and operators nest to
+/// build up a shape, with the torus lying in the XZ plane and twists going around Y.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SdfNode {
+    Sphere {
+        center: Point3,
+        radius: f64,
+    },
+    Box {
+        center: Point3,
+        half_size: Vec3,
+    },
+    /// Box whose edges and corners are rounded with `radius`, within the same `half_size`.
+    RoundedBox {
+        center: Point3,
+        half_size: Vec3,
+        radius: f64,
+    },
+    Torus {
+        center: Point3,
+        major_radius: f64,
+        minor_radius: f64,
+    },
+    /// Segment from `a` to `b` thickened by `radius`.
+    Capsule {
+        a: Point3,
+        b: Point3,
+        radius: f64,
+    },
+    /// Union blending the surfaces over about `smoothness`, a sharp union when zero.
+    SmoothUnion {
+        #[serde(default)]
+        smoothness: f64,
+        objects: Vec<SdfNode>,
+    },
+    /// `object` with `subtracted` carved out of it, blended over about `smoothness`.
+    Subtraction {
+        #[serde(default)]
+        smoothness: f64,
+        object: Box<SdfNode>,
+        subtracted: Box<SdfNode>,
+    },
+    /// Repeats the cell of `object` around the origin every `period`, a zero component
+    /// leaving that axis alone.
+    Repeat {
+        period: Vec3,
+        object: Box<SdfNode>,
+    },
+    /// Rotates `object` around the Y axis by `rate` radians per unit of height.
+    Twist {
+        rate: f64,
+        object: Box<SdfNode>,
+    },
+}
+
+impl SdfNode {
+    pub fn distance(&self, p: Vec3) -> f64 {
+        match self {
+            SdfNode::Sphere { center, radius } => (p - position(*center)).length() - radius,
+            SdfNode::Box { center, half_size } => box_distance(p - position(*center), *half_size),
+            SdfNode::RoundedBox { center, half_size, radius } => {
+                box_distance(p - position(*center), *half_size - *radius) - radius
+            }
+            SdfNode::Torus { center, major_radius, minor_radius } => {
+                let q = p - position(*center);
+                (q[0].hypot(q[2]) - major_radius).hypot(q[1]) - minor_radius
+            }
+            SdfNode::Capsule { a, b, radius } => {
+                let pa = p - position(*a);
+                let ba = *b - *a;
+                let h = (pa.dot(ba) / ba.length2()).clamp(0.0, 1.0);
+                (pa - h * ba).length() - radius
+            }
+            SdfNode::SmoothUnion { smoothness, objects } => objects.iter()
+                .map(|object| object.distance(p))
+                .reduce(|a, b| smooth_min(a, b, *smoothness))
+                .unwrap_or(f64::INFINITY),
+            SdfNode::Subtraction { smoothness, object, subtracted } => {
+                -smooth_min(-object.distance(p), subtracted.distance(p), *smoothness)
+            }
+            SdfNode::Repeat { period, object } => {
+                let mut q = p;
+                for i in 0..3 {
+                    if period[i] > 0.0 {
+                        q[i] -= period[i] * (q[i] / period[i]).round();
+                    }
+                }
+                object.distance(q)
+            }
+            SdfNode::Twist { rate, object } => {
+                let (sin, cos) = (rate * p[1]).sin_cos();
+                object.distance(Vec3::new(cos * p[0] - sin * p[2], p[1], sin * p[0] + cos * p[2]))
+            }
+        }
+    }
+}
+
+fn position(p: Point3) -> Vec3 {
+    p - Point3::zero()
+}
+
+fn box_distance(p: Vec3, half_size: Vec3) -> f64 {
+    let q = Vec3::new(p[0].abs() - half_size[0], p[1].abs() - half_size[1], p[2].abs() - half_size[2]);
+    let outside = Vec3::new(q[0].max(0.0), q[1].max(0.0), q[2].max(0.0)).length();
+    outside + q[0].max(q[1]).max(q[2]).min(0.0)
+}
+
+/// Polynomial smooth minimum, which rounds the crease where `a` and `b` meet over a width
+/// of about `k`.
+fn smooth_min(a: f64, b: f64, k: f64) -> f64 {
+    if k <= 0.0 {
+        return a.min(b);
+    }
+
+    let h = (0.5 + 0.5 * (b - a) / k).clamp(0.0, 1.0);
+    b + (a - b) * h - k * h * (1.0 - h)
+}
+
+/// Surface where `sdf` is zero, found by sphere tracing inside `bbox`, which must contain it.
+///
+/// Operators like twists and smooth blends can make the field overestimate distances.
+/// A `step_scale` below one makes smaller steps to avoid going through the surface.
+/// SDF shapes have no parameterization, so `u` and `v` are zero, and they aren't sampled as lights.
+pub struct Sdf {
+    sdf: SdfNode,
+    bbox: BBox,
+    step_scale: f64,
+    material: Material,
+}
+
+impl Sdf {
+    pub fn new(sdf: SdfNode, bbox: BBox, step_scale: f64, material: Material) -> Sdf {
+        Sdf { sdf, bbox, step_scale, material }
+    }
+
+    fn distance(&self, p: Point3) -> f64 {
+        self.sdf.distance(position(p))
+    }
+
+    /// Gradient of the field by central differences on the vertices of a tetrahedron.
+    fn normal(&self, p: Point3) -> Vec3 {
+        let offsets = [
+            Vec3::new(1.0, -1.0, -1.0),
+            Vec3::new(-1.0, -1.0, 1.0),
+            Vec3::new(-1.0, 1.0, -1.0),
+            Vec3::new(1.0, 1.0, 1.0),
+        ];
+        let gradient = offsets.iter()
+            .fold(Vec3::new(0.0, 0.0, 0.0), |sum, &offset| sum + offset * self.distance(p + NORMAL_EPSILON * offset));
+        if gradient.near_zero() { Vec3::new(0.0, 1.0, 0.0) } else { gradient.normalize() }
+    }
+}
+
+impl Hittable for Sdf {
+    /// Steps along the ray by the distance to the surface until it's closer than
+    /// `SURFACE_EPSILON`. Rays leaving the surface first get clear of it, and the side
+    /// they're on then tells whether they look for the surface from outside or inside.
+    fn hit_by(&self, _rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let (mut t, t_end) = self.bbox.clip(ray, t_min, t_max)?;
+        let dir_length = ray.dir.length();
+        let mut side = 0.0;
+        for _ in 0..MAX_STEPS {
+            let distance = self.distance(ray.at(t));
+            let step = if side == 0.0 {
+                if distance.abs() < SURFACE_EPSILON {
+                    SURFACE_EPSILON
+                } else {
+                    side = distance.signum();
+                    distance.abs()
+                }
+            } else if side * distance < SURFACE_EPSILON {
+                let point = ray.at(t);
+                return Some(HitRecord::create(
+                    ray,
+                    point,
+                    self.normal(point),
+                    t,
+                    (0.0, 0.0),
+                    Vec3::new(0.0, 0.0, 0.0),
+                    &self.material,
+                ));
+            } else {
+                side * distance
+            };
+
+            t += self.step_scale * step / dir_length;
+            if t > t_end {
+                return None;
+            }
+        }
+
+        None
+    }
+
+    fn bounding_box(&self) -> BBox {
+        self.bbox
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::SmallRng;
+
+    use crate::test_util::{assert_close, ray};
+
+    use super::*;
+
+    fn unit_sphere(step_scale: f64) -> Sdf {
+        let bbox = BBox { min: Point3::new(-2.0, -2.0, -2.0), max: Point3::new(2.0, 2.0, 2.0) };
+        Sdf::new(SdfNode::Sphere { center: Point3::zero(), radius: 1.0 }, bbox, step_scale, Material::BlackBody)
+    }
+
+    #[test]
+    fn sphere_is_hit_at_its_surface() {
+        let rng = &mut SmallRng::seed_from_u64(23);
+        for step_scale in [1.0, 0.5].iter() {
+            let sphere = unit_sphere(*step_scale);
+            let rec = sphere.hit_by(rng, &ray(Point3::new(-5.0, 0.0, 0.0), Vec3::new(2.0, 0.0, 0.0)), 0.001, f64::INFINITY).unwrap();
+            assert_close(rec.t, 2.0, SURFACE_EPSILON);
+            assert!(rec.front_face);
+            assert!((rec.normal - Vec3::new(-1.0, 0.0, 0.0)).length() < 1e-3);
+
+            let diagonal = Vec3::new(1.0, 1.0, 1.0).normalize();
+            let rec = sphere.hit_by(rng, &ray(Point3::new(0.0, 0.0, 0.0) - 3.0 * diagonal, diagonal), 0.001, f64::INFINITY).unwrap();
+            assert_close(rec.t, 2.0, SURFACE_EPSILON);
+            assert!((rec.normal + diagonal).length() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn rays_inside_hit_the_back_of_the_surface() {
+        let rng = &mut SmallRng::seed_from_u64(23);
+        let sphere = unit_sphere(1.0);
+        let rec = sphere.hit_by(rng, &ray(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)), 0.001, f64::INFINITY).unwrap();
+        assert_close(rec.t, 1.0, SURFACE_EPSILON);
+        assert!(!rec.front_face);
+        assert!((rec.normal - Vec3::new(0.0, -1.0, 0.0)).length() < 1e-3);
+
+        // Leaving the surface, the ray looks for it again from outside.
+        let leaving = ray(rec.point, Vec3::new(0.0, 1.0, 0.0));
+        assert!(sphere.hit_by(rng, &leaving, 0.001, f64::INFINITY).is_none());
+    }
+
+    #[test]
+    fn rays_miss_the_sphere() {
+        let rng = &mut SmallRng::seed_from_u64(23);
+        let sphere = unit_sphere(1.0);
+        assert!(sphere.hit_by(rng, &ray(Point3::new(-5.0, 1.1, 0.0), Vec3::new(1.0, 0.0, 0.0)), 0.001, f64::INFINITY).is_none());
+        assert!(sphere.hit_by(rng, &ray(Point3::new(-5.0, 0.0, 0.0), Vec3::new(-1.0, 0.0, 0.0)), 0.001, f64::INFINITY).is_none());
+        assert!(sphere.hit_by(rng, &ray(Point3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0)), 0.001, 3.5).is_none());
+    }
+
+    #[test]
+    fn primitives_give_signed_distances() {
+        let origin = Point3::zero();
+        let cases = [
+            (SdfNode::Box { center: origin, half_size: Vec3::new(1.0, 2.0, 3.0) }, Vec3::new(4.0, 6.0, 0.0), 5.0),
+            (SdfNode::Box { center: origin, half_size: Vec3::new(1.0, 2.0, 3.0) }, Vec3::new(0.5, 0.0, 0.0), -0.5),
+            (SdfNode::RoundedBox { center: origin, half_size: Vec3::new(1.0, 1.0, 1.0), radius: 0.5 }, Vec3::new(2.0, 0.0, 0.0), 1.0),
+            (SdfNode::Torus { center: origin, major_radius: 2.0, minor_radius: 0.5 }, Vec3::new(0.0, 1.0, 2.0), 0.5),
+            (SdfNode::Torus { center: origin, major_radius: 2.0, minor_radius: 0.5 }, Vec3::new(0.0, 0.0, 0.0), 1.5),
+            (SdfNode::Capsule { a: origin, b: Point3::new(0.0, 2.0, 0.0), radius: 0.5 }, Vec3::new(0.0, 3.0, 0.0), 0.5),
+            (SdfNode::Capsule { a: origin, b: Point3::new(0.0, 2.0, 0.0), radius: 0.5 }, Vec3::new(1.0, 1.0, 0.0), 0.5),
+        ];
+        for (sdf, p, expected) in cases.iter() {
+            assert_close(sdf.distance(*p), *expected, 1e-12);
+        }
+    }
+
+    #[test]
+    fn operators_combine_distances() {
+        let sphere = |x: f64| SdfNode::Sphere { center: Point3::new(x, 0.0, 0.0), radius: 1.0 };
+        let union = SdfNode::SmoothUnion { smoothness: 0.0, objects: vec![sphere(-2.0), sphere(2.0)] };
+        assert_close(union.distance(Vec3::new(0.0, 0.0, 0.0)), 1.0, 1e-12);
+        let smooth = SdfNode::SmoothUnion { smoothness: 0.5, objects: vec![sphere(-2.0), sphere(2.0)] };
+        assert!(smooth.distance(Vec3::new(0.0, 0.0, 0.0)) < 1.0);
+
+        let carved = SdfNode::Subtraction { smoothness: 0.0, object: Box::new(sphere(0.0)), subtracted: Box::new(sphere(1.0)) };
+        assert_close(carved.distance(Vec3::new(0.5, 0.0, 0.0)), 0.5, 1e-12);
+        assert_close(carved.distance(Vec3::new(-0.5, 0.0, 0.0)), -0.5, 1e-12);
+
+        let repeated = SdfNode::Repeat { period: Vec3::new(4.0, 0.0, 0.0), object: Box::new(sphere(0.0)) };
+        assert_close(repeated.distance(Vec3::new(8.5, 0.0, 0.0)), -0.5, 1e-12);
+        assert_close(repeated.distance(Vec3::new(0.0, 3.0, 0.0)), 2.0, 1e-12);
+
+        let bar = SdfNode::Box { center: Point3::zero(), half_size: Vec3::new(2.0, 10.0, 0.5) };
+        let twisted = SdfNode::Twist { rate: std::f64::consts::FRAC_PI_2, object: Box::new(bar) };
+        // A quarter turn up, the bar lies along z.
+        assert!(twisted.distance(Vec3::new(0.0, 1.0, 1.5)) < 0.0);
+        assert!(twisted.distance(Vec3::new(1.5, 1.0, 0.0)) > 0.0);
+    }
+}
@@ -0,0 +1,821 @@
+use std::f64::consts::PI;
+
+use rand::{Rng, RngCore};
+
+use crate::bounding_box::BBox;
+use crate::geometry::{HitRecord, Hittable, SurfaceSample};
+use crate::material::Material;
+use crate::point3::Point3;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+/// Orthonormal frame of a shape, `w` being along its axis. Shapes intersect rays in these
+/// local coordinates, where their equations are the simplest.
+struct Frame {
+    origin: Point3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+}
+
+impl Frame {
+    fn new(origin: Point3, axis: Vec3) -> Frame {
+        let w = axis.normalize();
+        let (u, v) = w.orthonormal_basis();
+        Frame { origin, u, v, w }
+    }
+
+    /// Origin and direction of `ray` in local coordinates. Distances along the ray are the
+    /// same in both frames.
+    fn local_ray(&self, ray: &Ray) -> (Vec3, Vec3) {
+        (self.to_local(ray.orig - self.origin), self.to_local(ray.dir))
+    }
+
+    fn to_local(&self, v: Vec3) -> Vec3 {
+        Vec3::new(v.dot(self.u), v.dot(self.v), v.dot(self.w))
+    }
+
+    fn to_world(&self, v: Vec3) -> Vec3 {
+        v[0] * self.u + v[1] * self.v + v[2] * self.w
+    }
+
+    fn point(&self, local: Vec3) -> Point3 {
+        self.origin + self.to_world(local)
+    }
+}
+
+/// Intersection in the local coordinates of a `Frame`.
+struct LocalHit {
+    t: f64,
+    normal: Vec3,
+    uv: (f64, f64),
+    dpdu: Vec3,
+}
+
+impl LocalHit {
+    fn record<'a>(&self, ray: &Ray, frame: &Frame, material: &'a Material) -> HitRecord<'a> {
+        HitRecord::create(
+            ray,
+            ray.at(self.t),
+            frame.to_world(self.normal),
+            self.t,
+            self.uv,
+            frame.to_world(self.dpdu),
+            material,
+        )
+    }
+}
+
+/// Keeps the nearest of two optional hits.
+fn nearest(a: Option<LocalHit>, b: Option<LocalHit>) -> Option<LocalHit> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if b.t < a.t { b } else { a }),
+        (a, b) => a.or(b)
+    }
+}
+
+/// Angle around the local `w` axis, between 0 and 2π.
+fn azimuth(x: f64, y: f64) -> f64 {
+    let phi = y.atan2(x);
+    if phi < 0.0 { phi + 2.0 * PI } else { phi }
+}
+
+/// Hit with the disk of the given radius lying in the local plane at height `z`.
+fn hit_disk(orig: Vec3, dir: Vec3, z: f64, radius: f64, normal: Vec3, t_min: f64, t_max: f64) -> Option<LocalHit> {
+    if dir[2].abs() < 1e-8 {
+        return None;
+    }
+
+    let t = (z - orig[2]) / dir[2];
+    if t <= t_min || t >= t_max {
+        return None;
+    }
+
+    let (x, y) = (orig[0] + t * dir[0], orig[1] + t * dir[1]);
+    let distance2 = x * x + y * y;
+    if distance2 > radius * radius {
+        return None;
+    }
+
+    Some(LocalHit {
+        t,
+        normal,
+        uv: (azimuth(x, y) / (2.0 * PI), distance2.sqrt() / radius),
+        dpdu: Vec3::new(-y, x, 0.0),
+    })
+}
+
+/// Uniformly sampled point of a disk in the local plane at height `z`.
+fn sample_disk(rng: &mut dyn RngCore, z: f64, radius: f64) -> Vec3 {
+    let r = radius * rng.gen::<f64>().sqrt();
+    let phi = 2.0 * PI * rng.gen::<f64>();
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Bounds of a disk, which extends by `radius * sin` of the angle between its normal and
+/// each axis.
+fn disk_bbox(center: Point3, normal: Vec3, radius: f64) -> BBox {
+    let normal = normal.normalize();
+    let extent = Vec3::new(
+        radius * (1.0 - normal[0] * normal[0]).max(0.0).sqrt(),
+        radius * (1.0 - normal[1] * normal[1]).max(0.0).sqrt(),
+        radius * (1.0 - normal[2] * normal[2]).max(0.0).sqrt(),
+    );
+    BBox { min: center - extent, max: center + extent }
+}
+
+pub struct Disk {
+    frame: Frame,
+    radius: f64,
+    material: Material,
+}
+
+impl Disk {
+    pub fn new(center: Point3, normal: Vec3, radius: f64, material: Material) -> Disk {
+        Disk { frame: Frame::new(center, normal), radius, material }
+    }
+}
+
+impl Hittable for Disk {
+    fn hit_by(&self, _rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let (orig, dir) = self.frame.local_ray(ray);
+        hit_disk(orig, dir, 0.0, self.radius, Vec3::new(0.0, 0.0, 1.0), t_min, t_max)
+            .map(|hit| hit.record(ray, &self.frame, &self.material))
+    }
+
+    fn bounding_box(&self) -> BBox {
+        disk_bbox(self.frame.origin, self.frame.w, self.radius)
+    }
+
+    fn area(&self) -> f64 {
+        PI * self.radius * self.radius
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        Some(SurfaceSample {
+            point: self.frame.point(sample_disk(rng, 0.0, self.radius)),
+            normal: self.frame.w,
+            pdf: 1.0 / self.area(),
+        })
+    }
+}
+
+/// Parallelogram spanned by `edge1` and `edge2` from `corner`, which is a rectangle when the
+/// edges are perpendicular. `u` runs along `edge1` and `v` along `edge2`.
+pub struct Quad {
+    corner: Point3,
+    edge1: Vec3,
+    edge2: Vec3,
+    normal: Vec3,
+    material: Material,
+}
+
+impl Quad {
+    pub fn new(corner: Point3, edge1: Vec3, edge2: Vec3, material: Material) -> Quad {
+        Quad { corner, edge1, edge2, normal: edge1.cross(edge2), material }
+    }
+}
+
+impl Hittable for Quad {
+    fn hit_by(&self, _rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let dir_dot_normal = ray.dir.dot(self.normal);
+        if dir_dot_normal.abs() < 1e-12 {
+            return None;
+        }
+
+        let t = (self.corner - ray.orig).dot(self.normal) / dir_dot_normal;
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+
+        let point = ray.at(t);
+        let to_point = point - self.corner;
+        let inv_length2 = 1.0 / self.normal.length2();
+        let u = to_point.cross(self.edge2).dot(self.normal) * inv_length2;
+        let v = self.edge1.cross(to_point).dot(self.normal) * inv_length2;
+        if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) {
+            return None;
+        }
+
+        Some(HitRecord::create(
+            ray,
+            point,
+            self.normal.normalize(),
+            t,
+            (u, v),
+            self.edge1,
+            &self.material,
+        ))
+    }
+
+    fn bounding_box(&self) -> BBox {
+        let corners = [
+            self.corner + self.edge1,
+            self.corner + self.edge2,
+            self.corner + self.edge1 + self.edge2,
+        ];
+        corners.iter().fold(BBox { min: self.corner, max: self.corner }, |bbox, &corner| {
+            BBox::surrounding_box(bbox, BBox { min: corner, max: corner })
+        })
+    }
+
+    fn area(&self) -> f64 {
+        self.normal.length()
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        let area = self.area();
+        if area <= 0.0 {
+            return None;
+        }
+
+        Some(SurfaceSample {
+            point: self.corner + rng.gen::<f64>() * self.edge1 + rng.gen::<f64>() * self.edge2,
+            normal: self.normal / area,
+            pdf: 1.0 / area,
+        })
+    }
+}
+
+/// Cylinder going from the disk at `base` to the disk at `base + axis`, closed by those disks
+/// when `capped`. On the side `u` goes around the axis and `v` along it.
+pub struct Cylinder {
+    frame: Frame,
+    height: f64,
+    radius: f64,
+    capped: bool,
+    material: Material,
+}
+
+impl Cylinder {
+    pub fn new(base: Point3, axis: Vec3, radius: f64, capped: bool, material: Material) -> Cylinder {
+        Cylinder { frame: Frame::new(base, axis), height: axis.length(), radius, capped, material }
+    }
+
+    fn side_area(&self) -> f64 {
+        2.0 * PI * self.radius * self.height
+    }
+
+    fn hit_side(&self, orig: Vec3, dir: Vec3, t_min: f64, t_max: f64) -> Option<LocalHit> {
+        let a = dir[0] * dir[0] + dir[1] * dir[1];
+        let half_b = orig[0] * dir[0] + orig[1] * dir[1];
+        let c = orig[0] * orig[0] + orig[1] * orig[1] - self.radius * self.radius;
+        let t = quadratic_roots(a, half_b, c)?.iter().copied().find(|&t| {
+            let z = orig[2] + t * dir[2];
+            t_min < t && t < t_max && (0.0..=self.height).contains(&z)
+        })?;
+
+        let p = orig + t * dir;
+        Some(LocalHit {
+            t,
+            normal: Vec3::new(p[0], p[1], 0.0) / self.radius,
+            uv: (azimuth(p[0], p[1]) / (2.0 * PI), p[2] / self.height),
+            dpdu: Vec3::new(-p[1], p[0], 0.0),
+        })
+    }
+}
+
+impl Hittable for Cylinder {
+    fn hit_by(&self, _rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let (orig, dir) = self.frame.local_ray(ray);
+        let mut hit = self.hit_side(orig, dir, t_min, t_max);
+        if self.capped {
+            let t_max = hit.as_ref().map_or(t_max, |hit| hit.t);
+            hit = nearest(hit, hit_disk(orig, dir, 0.0, self.radius, Vec3::new(0.0, 0.0, -1.0), t_min, t_max));
+            let t_max = hit.as_ref().map_or(t_max, |hit| hit.t);
+            hit = nearest(hit, hit_disk(orig, dir, self.height, self.radius, Vec3::new(0.0, 0.0, 1.0), t_min, t_max));
+        }
+        hit.map(|hit| hit.record(ray, &self.frame, &self.material))
+    }
+
+    fn bounding_box(&self) -> BBox {
+        let top = self.frame.point(Vec3::new(0.0, 0.0, self.height));
+        BBox::surrounding_box(
+            disk_bbox(self.frame.origin, self.frame.w, self.radius),
+            disk_bbox(top, self.frame.w, self.radius),
+        )
+    }
+
+    fn area(&self) -> f64 {
+        let cap_area = if self.capped { PI * self.radius * self.radius } else { 0.0 };
+        self.side_area() + 2.0 * cap_area
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        let area = self.area();
+        let x = rng.gen::<f64>() * area;
+        let (local, normal) = if x < self.side_area() {
+            let phi = 2.0 * PI * rng.gen::<f64>();
+            let normal = Vec3::new(phi.cos(), phi.sin(), 0.0);
+            (self.radius * normal + Vec3::new(0.0, 0.0, self.height * rng.gen::<f64>()), normal)
+        } else if x < 0.5 * (self.side_area() + area) {
+            (sample_disk(rng, 0.0, self.radius), Vec3::new(0.0, 0.0, -1.0))
+        } else {
+            (sample_disk(rng, self.height, self.radius), Vec3::new(0.0, 0.0, 1.0))
+        };
+
+        Some(SurfaceSample {
+            point: self.frame.point(local),
+            normal: self.frame.to_world(normal),
+            pdf: 1.0 / area,
+        })
+    }
+}
+
+/// Cone with its base disk at `base` and its apex at `base + axis`, closed by the base disk
+/// when `capped`. On the side `u` goes around the axis and `v` from the base to the apex.
+pub struct Cone {
+    frame: Frame,
+    height: f64,
+    radius: f64,
+    capped: bool,
+    material: Material,
+}
+
+impl Cone {
+    pub fn new(base: Point3, axis: Vec3, radius: f64, capped: bool, material: Material) -> Cone {
+        Cone { frame: Frame::new(base, axis), height: axis.length(), radius, capped, material }
+    }
+
+    fn side_area(&self) -> f64 {
+        PI * self.radius * self.radius.hypot(self.height)
+    }
+
+    /// Intersects x² + y² = (k (h - z))², keeping the nappe between the base and the apex.
+    fn hit_side(&self, orig: Vec3, dir: Vec3, t_min: f64, t_max: f64) -> Option<LocalHit> {
+        let k = self.radius / self.height;
+        let k2 = k * k;
+        let to_apex = self.height - orig[2];
+        let a = dir[0] * dir[0] + dir[1] * dir[1] - k2 * dir[2] * dir[2];
+        let half_b = orig[0] * dir[0] + orig[1] * dir[1] + k2 * to_apex * dir[2];
+        let c = orig[0] * orig[0] + orig[1] * orig[1] - k2 * to_apex * to_apex;
+        let t = quadratic_roots(a, half_b, c)?.iter().copied().find(|&t| {
+            let z = orig[2] + t * dir[2];
+            t_min < t && t < t_max && (0.0..=self.height).contains(&z)
+        })?;
+
+        let p = orig + t * dir;
+        let distance = p[0].hypot(p[1]);
+        let normal = if distance > 0.0 {
+            Vec3::new(p[0] / distance, p[1] / distance, k).normalize()
+        } else {
+            Vec3::new(0.0, 0.0, 1.0)
+        };
+        Some(LocalHit {
+            t,
+            normal,
+            uv: (azimuth(p[0], p[1]) / (2.0 * PI), p[2] / self.height),
+            dpdu: Vec3::new(-p[1], p[0], 0.0),
+        })
+    }
+}
+
+impl Hittable for Cone {
+    fn hit_by(&self, _rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let (orig, dir) = self.frame.local_ray(ray);
+        let mut hit = self.hit_side(orig, dir, t_min, t_max);
+        if self.capped {
+            let t_max = hit.as_ref().map_or(t_max, |hit| hit.t);
+            hit = nearest(hit, hit_disk(orig, dir, 0.0, self.radius, Vec3::new(0.0, 0.0, -1.0), t_min, t_max));
+        }
+        hit.map(|hit| hit.record(ray, &self.frame, &self.material))
+    }
+
+    fn bounding_box(&self) -> BBox {
+        let apex = self.frame.point(Vec3::new(0.0, 0.0, self.height));
+        BBox::surrounding_box(
+            disk_bbox(self.frame.origin, self.frame.w, self.radius),
+            BBox { min: apex, max: apex },
+        )
+    }
+
+    fn area(&self) -> f64 {
+        let cap_area = if self.capped { PI * self.radius * self.radius } else { 0.0 };
+        self.side_area() + cap_area
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        let area = self.area();
+        let (local, normal) = if rng.gen::<f64>() * area < self.side_area() {
+            // The area within a distance s of the apex grows as s².
+            let s = rng.gen::<f64>().sqrt();
+            let phi = 2.0 * PI * rng.gen::<f64>();
+            let (cos_phi, sin_phi) = (phi.cos(), phi.sin());
+            let local = Vec3::new(s * self.radius * cos_phi, s * self.radius * sin_phi, (1.0 - s) * self.height);
+            (local, Vec3::new(self.height * cos_phi, self.height * sin_phi, self.radius).normalize())
+        } else {
+            (sample_disk(rng, 0.0, self.radius), Vec3::new(0.0, 0.0, -1.0))
+        };
+
+        Some(SurfaceSample {
+            point: self.frame.point(local),
+            normal: self.frame.to_world(normal),
+            pdf: 1.0 / area,
+        })
+    }
+}
+
+/// Ring torus around `axis` through `center`, whose tube of radius `minor_radius` follows a
+/// circle of radius `major_radius`. `u` goes around the axis and `v` around the tube.
+pub struct Torus {
+    frame: Frame,
+    major_radius: f64,
+    minor_radius: f64,
+    material: Material,
+}
+
+impl Torus {
+    pub fn new(center: Point3, axis: Vec3, major_radius: f64, minor_radius: f64, material: Material) -> Torus {
+        Torus { frame: Frame::new(center, axis), major_radius, minor_radius, material }
+    }
+}
+
+impl Hittable for Torus {
+    /// Solves (|p|² + R² - r²)² = 4R² (x² + y²) along the ray. The direction is normalized
+    /// and the origin moved to the point closest to the center, which keeps the quartic well
+    /// conditioned for distant rays.
+    fn hit_by(&self, _rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let (local_orig, local_dir) = self.frame.local_ray(ray);
+        let dir_length = local_dir.length();
+        let dir = local_dir / dir_length;
+        let shift = -local_orig.dot(dir);
+        let orig = local_orig + shift * dir;
+
+        let (major2, minor2) = (self.major_radius * self.major_radius, self.minor_radius * self.minor_radius);
+        let bounding_radius = self.major_radius + self.minor_radius;
+        if orig.length2() > bounding_radius * bounding_radius {
+            return None;
+        }
+
+        let s = orig.dot(dir);
+        let q = orig.length2() + major2 - minor2;
+        let roots = solve_quartic(
+            4.0 * s,
+            4.0 * s * s + 2.0 * q - 4.0 * major2 * (dir[0] * dir[0] + dir[1] * dir[1]),
+            4.0 * s * q - 8.0 * major2 * (orig[0] * dir[0] + orig[1] * dir[1]),
+            q * q - 4.0 * major2 * (orig[0] * orig[0] + orig[1] * orig[1]),
+        );
+        let t = roots.into_iter()
+            .map(|root| (root + shift) / dir_length)
+            .filter(|&t| t_min < t && t < t_max)
+            .fold(f64::INFINITY, f64::min);
+        if t == f64::INFINITY {
+            return None;
+        }
+
+        let p = local_orig + t * local_dir;
+        let distance = p[0].hypot(p[1]);
+        let to_ring = if distance > 0.0 {
+            Vec3::new(p[0], p[1], 0.0) * (self.major_radius / distance)
+        } else {
+            Vec3::new(self.major_radius, 0.0, 0.0)
+        };
+        let normal = (p - to_ring).normalize();
+        let hit = LocalHit {
+            t,
+            normal,
+            uv: (azimuth(p[0], p[1]) / (2.0 * PI), azimuth(distance - self.major_radius, p[2]) / (2.0 * PI)),
+            dpdu: Vec3::new(-p[1], p[0], 0.0),
+        };
+        Some(hit.record(ray, &self.frame, &self.material))
+    }
+
+    fn bounding_box(&self) -> BBox {
+        let bbox = disk_bbox(self.frame.origin, self.frame.w, self.major_radius);
+        let padding = Vec3::new(self.minor_radius, self.minor_radius, self.minor_radius);
+        BBox { min: bbox.min - padding, max: bbox.max + padding }
+    }
+
+    fn area(&self) -> f64 {
+        4.0 * PI * PI * self.major_radius * self.minor_radius
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    /// The area element around the tube is proportional to the distance to the axis, so
+    /// angles around the tube are drawn by rejection.
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        let bounding_radius = self.major_radius + self.minor_radius;
+        let theta = loop {
+            let theta = 2.0 * PI * rng.gen::<f64>();
+            if rng.gen::<f64>() * bounding_radius <= self.major_radius + self.minor_radius * theta.cos() {
+                break theta;
+            }
+        };
+
+        let phi = 2.0 * PI * rng.gen::<f64>();
+        let (cos_phi, sin_phi) = (phi.cos(), phi.sin());
+        let normal = Vec3::new(theta.cos() * cos_phi, theta.cos() * sin_phi, theta.sin());
+        let local = Vec3::new(self.major_radius * cos_phi, self.major_radius * sin_phi, 0.0) + self.minor_radius * normal;
+        Some(SurfaceSample {
+            point: self.frame.point(local),
+            normal: self.frame.to_world(normal),
+            pdf: 1.0 / self.area(),
+        })
+    }
+}
+
+/// Real roots of a t² + 2 half_b t + c in increasing order, for positive `a`.
+fn quadratic_roots(a: f64, half_b: f64, c: f64) -> Option<[f64; 2]> {
+    let discr = half_b * half_b - a * c;
+    if a.abs() < 1e-12 || discr < 0.0 {
+        return None;
+    }
+
+    let sqrt_discr = discr.sqrt();
+    let (t1, t2) = ((-half_b - sqrt_discr) / a, (-half_b + sqrt_discr) / a);
+    Some([t1.min(t2), t1.max(t2)])
+}
+
+/// Largest real root of x³ + a x² + b x + c, by Cardano's formula or the trigonometric
+/// method when there are three real roots.
+fn largest_cubic_root(a: f64, b: f64, c: f64) -> f64 {
+    let p = b - a * a / 3.0;
+    let q = 2.0 * a * a * a / 27.0 - a * b / 3.0 + c;
+    let discr = q * q / 4.0 + p * p * p / 27.0;
+    let y = if discr > 0.0 {
+        let sqrt_discr = discr.sqrt();
+        (-q / 2.0 + sqrt_discr).cbrt() + (-q / 2.0 - sqrt_discr).cbrt()
+    } else {
+        let r = (-p / 3.0).sqrt();
+        let cos_3theta = if r > 0.0 { (-q / (2.0 * r * r * r)).clamp(-1.0, 1.0) } else { 0.0 };
+        2.0 * r * (cos_3theta.acos() / 3.0).cos()
+    };
+
+    let mut x = y - a / 3.0;
+    // One Newton step cleans up the cancellation in Cardano's formula.
+    let derivative = (3.0 * x + 2.0 * a) * x + b;
+    if derivative.abs() > 1e-12 {
+        x -= (((x + a) * x + b) * x + c) / derivative;
+    }
+    x
+}
+
+/// Real roots of x⁴ + a x³ + b x² + c x + d with Ferrari's method, polished with Newton's
+/// method on the original polynomial.
+fn solve_quartic(a: f64, b: f64, c: f64, d: f64) -> Vec<f64> {
+    // Depressed quartic y⁴ + p y² + q y + r with x = y - a / 4.
+    let a2 = a * a;
+    let p = b - 3.0 * a2 / 8.0;
+    let q = c - a * b / 2.0 + a2 * a / 8.0;
+    let r = d - a * c / 4.0 + a2 * b / 16.0 - 3.0 * a2 * a2 / 256.0;
+
+    let mut roots = Vec::with_capacity(4);
+
+    if q.abs() < 1e-12 {
+        // Biquadratic: y² is a root of z² + p z + r.
+        let discr = p * p / 4.0 - r;
+        if discr >= 0.0 {
+            for z in [-p / 2.0 - discr.sqrt(), -p / 2.0 + discr.sqrt()] {
+                if z >= 0.0 {
+                    push_quadratic_roots(&mut roots, 0.0, -z);
+                }
+            }
+        }
+    } else {
+        // (y² + p/2 + m)² = 2m (y - q / 4m)² for a positive root m of the resolvent cubic.
+        let m = largest_cubic_root(p, p * p / 4.0 - r, -q * q / 8.0);
+        if m <= 0.0 {
+            return roots;
+        }
+        let sqrt_2m = (2.0 * m).sqrt();
+        push_quadratic_roots(&mut roots, -sqrt_2m, p / 2.0 + m + q / (2.0 * sqrt_2m));
+        push_quadratic_roots(&mut roots, sqrt_2m, p / 2.0 + m - q / (2.0 * sqrt_2m));
+    }
+
+    for root in roots.iter_mut() {
+        let mut x = *root - a / 4.0;
+        for _ in 0..2 {
+            let f = (((x + a) * x + b) * x + c) * x + d;
+            let derivative = ((4.0 * x + 3.0 * a) * x + 2.0 * b) * x + c;
+            if derivative.abs() < 1e-12 {
+                break;
+            }
+            x -= f / derivative;
+        }
+        *root = x;
+    }
+    roots
+}
+
+/// Adds the real roots of x² + b x + c to `roots`.
+fn push_quadratic_roots(roots: &mut Vec<f64>, b: f64, c: f64) {
+    let discr = b * b / 4.0 - c;
+    if discr >= 0.0 {
+        let sqrt_discr = discr.sqrt();
+        roots.push(-b / 2.0 - sqrt_discr);
+        roots.push(-b / 2.0 + sqrt_discr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::SmallRng;
+
+    use crate::test_util::{assert_vec_close, ray};
+
+    use super::*;
+
+    /// Coefficients a, b, c, d of the monic quartic with the given real roots, completed with
+    /// factors of `x² + 1` that have none.
+    fn quartic_with_roots(roots: &[f64]) -> [f64; 4] {
+        // Coefficients from the highest degree down, the leading one being 1.
+        let mut coefficients = vec![1.0];
+        let mut multiply = |factor: &[f64]| {
+            let mut product = vec![0.0; coefficients.len() + factor.len() - 1];
+            for (i, a) in coefficients.iter().enumerate() {
+                for (j, b) in factor.iter().enumerate() {
+                    product[i + j] += a * b;
+                }
+            }
+            coefficients = product;
+        };
+        for &root in roots {
+            multiply(&[1.0, -root]);
+        }
+        for _ in 0..(4 - roots.len()) / 2 {
+            multiply(&[1.0, 0.0, 1.0]);
+        }
+        [coefficients[1], coefficients[2], coefficients[3], coefficients[4]]
+    }
+
+    fn assert_roots(mut roots: Vec<f64>, expected: &[f64]) {
+        roots.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(roots.len(), expected.len(), "roots {:?}, expected {:?}", roots, expected);
+        for (root, expected) in roots.iter().zip(expected) {
+            assert!((root - expected).abs() < 1e-9, "roots {:?}, expected {:?}", roots, expected);
+        }
+    }
+
+    #[test]
+    fn quartics_are_solved() {
+        let cases: [&[f64]; 6] = [
+            &[-3.0, 0.5, 1.0, 2.0],
+            &[-2.0, -1.0, 1.0, 2.0],
+            &[1.0, 4.0],
+            &[-0.25, 7.5],
+            &[-10.0, -9.0, 9.5, 10.0],
+            &[],
+        ];
+        for roots in cases.iter() {
+            let [a, b, c, d] = quartic_with_roots(roots);
+            assert_roots(solve_quartic(a, b, c, d), roots);
+        }
+    }
+
+    #[test]
+    fn quartic_double_roots_are_found() {
+        let [a, b, c, d] = quartic_with_roots(&[1.0, 1.0, -2.0, 3.0]);
+        let roots = solve_quartic(a, b, c, d);
+        for expected in [1.0, -2.0, 3.0].iter() {
+            assert!(roots.iter().any(|root| (root - expected).abs() < 1e-6), "{} not in {:?}", expected, roots);
+        }
+    }
+
+    fn ring() -> Torus {
+        Torus::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0), 2.0, 0.5, Material::BlackBody)
+    }
+
+    #[test]
+    fn torus_is_hit_on_its_tube() {
+        let rng = &mut SmallRng::seed_from_u64(21);
+        let torus = ring();
+
+        let rec = torus.hit_by(rng, &ray(Point3::new(-5.0, 0.0, 0.0), Vec3::new(2.0, 0.0, 0.0)), 0.001, f64::INFINITY).unwrap();
+        assert!((rec.t - 1.25).abs() < 1e-9);
+        assert_vec_close(rec.normal, Vec3::new(-1.0, 0.0, 0.0), 1e-9);
+
+        let rec = torus.hit_by(rng, &ray(Point3::new(2.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f64::INFINITY).unwrap();
+        assert!((rec.t - 4.5).abs() < 1e-9);
+        assert_vec_close(rec.normal, Vec3::new(0.0, 0.0, 1.0), 1e-9);
+
+        // From the center, the inside of the ring faces the ray.
+        let rec = torus.hit_by(rng, &ray(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)), 0.001, f64::INFINITY).unwrap();
+        assert!((rec.t - 1.5).abs() < 1e-9);
+        assert!(rec.front_face);
+        assert_vec_close(rec.normal, Vec3::new(0.0, -1.0, 0.0), 1e-9);
+
+        // From inside the tube, the far wall.
+        let rec = torus.hit_by(rng, &ray(Point3::new(2.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0)), 0.001, f64::INFINITY).unwrap();
+        assert!((rec.t - 0.5).abs() < 1e-9);
+        assert!(!rec.front_face);
+
+        assert!(torus.hit_by(rng, &ray(Point3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f64::INFINITY).is_none());
+        assert!(torus.hit_by(rng, &ray(Point3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0)), 0.001, 2.0).is_none());
+        assert!(torus.hit_by(rng, &ray(Point3::new(-5.0, 0.0, 0.6), Vec3::new(1.0, 0.0, 0.0)), 0.001, f64::INFINITY).is_none());
+    }
+
+    #[test]
+    fn distant_rays_hit_the_torus_accurately() {
+        let rng = &mut SmallRng::seed_from_u64(21);
+        let torus = ring();
+        let rec = torus.hit_by(rng, &ray(Point3::new(-1e4, 0.0, 0.1), Vec3::new(1.0, 0.0, 0.0)), 0.001, f64::INFINITY).unwrap();
+        let expected = 1e4 - 2.0 - 0.24f64.sqrt();
+        assert!((rec.t - expected).abs() < 1e-8, "{} isn't {}", rec.t, expected);
+    }
+
+    #[test]
+    fn tilted_torus_hits_lie_on_the_surface() {
+        let rng = &mut SmallRng::seed_from_u64(21);
+        let center = Point3::new(1.0, -2.0, 0.5);
+        let axis = Vec3::new(1.0, 1.0, 0.5).normalize();
+        let torus = Torus::new(center, axis, 1.5, 0.4, Material::BlackBody);
+
+        let mut hits = 0;
+        for _ in 0..1000 {
+            let orig = center + 5.0 * Vec3::random_unit_vector(rng);
+            let target = center + 1.5 * Vec3::random_in_unit_sphere(rng);
+            if let Some(rec) = torus.hit_by(rng, &ray(orig, target - orig), 0.001, f64::INFINITY) {
+                hits += 1;
+                let p = rec.point - center;
+                let height = p.dot(axis);
+                let distance = (p - height * axis).length();
+                let tube = (distance - 1.5).hypot(height);
+                assert!((tube - 0.4).abs() < 1e-9, "{} off the tube", tube);
+                assert_vec_close(rec.normal.cross(rec.point - (center + 1.5 / distance * (p - height * axis))), Vec3::new(0.0, 0.0, 0.0), 1e-9);
+            }
+        }
+        assert!(hits > 100);
+    }
+
+    /// Rays aimed at sampled surface points hit the shape no later than at the point, with the
+    /// sampled normal when it's the point they hit.
+    fn assert_samples_are_hit(rng: &mut SmallRng, shape: &dyn Hittable) {
+        let bbox = shape.bounding_box();
+        let center = bbox.min + 0.5 * (bbox.max - bbox.min);
+        let radius = (bbox.max - bbox.min).length();
+        for _ in 0..1000 {
+            let sample = shape.sample_surface(rng).unwrap();
+            let orig = center + radius * Vec3::random_unit_vector(rng);
+            let to_sample = sample.point - orig;
+            let rec = shape.hit_by(rng, &ray(orig, to_sample), 0.001, f64::INFINITY).unwrap();
+            assert!(rec.t <= 1.0 + 1e-9);
+            if rec.t > 1.0 - 1e-9 {
+                assert!((rec.normal.dot(sample.normal).abs() - 1.0).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn sampled_points_are_hit() {
+        let rng = &mut SmallRng::seed_from_u64(21);
+        let base = Point3::new(0.5, -1.0, 2.0);
+        let axis = Vec3::new(0.3, 2.0, -0.5);
+        let shapes: Vec<Box<dyn Hittable>> = vec![
+            Box::new(Disk::new(base, axis, 1.5, Material::BlackBody)),
+            Box::new(Quad::new(base, Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.5, 1.0, 1.0), Material::BlackBody)),
+            Box::new(Cylinder::new(base, axis, 0.7, true, Material::BlackBody)),
+            Box::new(Cylinder::new(base, axis, 0.7, false, Material::BlackBody)),
+            Box::new(Cone::new(base, axis, 0.9, true, Material::BlackBody)),
+            Box::new(Torus::new(base, axis, 1.5, 0.4, Material::BlackBody)),
+        ];
+        for shape in shapes.iter() {
+            assert_samples_are_hit(rng, shape.as_ref());
+        }
+    }
+
+    #[test]
+    fn capped_shapes_are_hit_on_their_caps() {
+        let rng = &mut SmallRng::seed_from_u64(21);
+        let down = ray(Point3::new(0.2, 0.1, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let axis = Vec3::new(0.0, 0.0, 2.0);
+
+        let cylinder = Cylinder::new(Point3::new(0.0, 0.0, 0.0), axis, 1.0, true, Material::BlackBody);
+        let rec = cylinder.hit_by(rng, &down, 0.001, f64::INFINITY).unwrap();
+        assert!((rec.t - 3.0).abs() < 1e-9);
+        assert_vec_close(rec.normal, Vec3::new(0.0, 0.0, 1.0), 1e-9);
+
+        // Without caps the ray goes through.
+        let open = Cylinder::new(Point3::new(0.0, 0.0, 0.0), axis, 1.0, false, Material::BlackBody);
+        assert!(open.hit_by(rng, &down, 0.001, f64::INFINITY).is_none());
+
+        // The cone's side narrows to the apex at z = 2, where x² + y² = (2 - z)² / 4.
+        let cone = Cone::new(Point3::new(0.0, 0.0, 0.0), axis, 1.0, true, Material::BlackBody);
+        let rec = cone.hit_by(rng, &down, 0.001, f64::INFINITY).unwrap();
+        let expected_z = 2.0 - 2.0 * 0.2f64.hypot(0.1);
+        assert!((rec.t - (5.0 - expected_z)).abs() < 1e-9);
+        let up = ray(Point3::new(0.2, 0.1, -5.0), Vec3::new(0.0, 0.0, 1.0));
+        let rec = cone.hit_by(rng, &up, 0.001, f64::INFINITY).unwrap();
+        assert!((rec.t - 5.0).abs() < 1e-9);
+        assert_vec_close(rec.normal, Vec3::new(0.0, 0.0, -1.0), 1e-9);
+    }
+}
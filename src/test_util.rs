@@ -0,0 +1,24 @@
+//! Helpers shared by the unit tests.
+
+use std::path::PathBuf;
+
+use crate::point3::Point3;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+pub fn ray(orig: Point3, dir: Vec3) -> Ray {
+    Ray { orig, dir, time: 0.0 }
+}
+
+pub fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+    assert!((actual - expected).abs() <= tolerance, "{} isn't within {} of {}", actual, tolerance, expected);
+}
+
+pub fn assert_vec_close(actual: Vec3, expected: Vec3, tolerance: f64) {
+    assert!((actual - expected).length() <= tolerance, "{:?} isn't within {} of {:?}", actual, tolerance, expected);
+}
+
+/// Path in the temporary directory that other test processes won't use, ending with `name`.
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rust_ray_tracer_{}_{}", std::process::id(), name))
+}
@@ -0,0 +1,327 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use image::RgbImage;
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
+use serde::{Deserialize, Serialize};
+
+use crate::color::Color;
+use crate::point3::Point3;
+use crate::vec3::Vec3;
+
+/// Spatially varying color. A bare color in the scene file is a constant texture.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Texture {
+    Checker {
+        odd: Box<Texture>,
+        even: Box<Texture>,
+        /// Number of checks per unit of distance (or per unit of UV).
+        scale: f64,
+        #[serde(default = "solid")]
+        space: CheckerSpace,
+    },
+    Image(ImageTexture),
+    Noise {
+        scale: f64,
+        #[serde(default = "white")]
+        color: Color,
+    },
+    Turbulence {
+        scale: f64,
+        #[serde(default = "default_depth")]
+        depth: u32,
+        #[serde(default = "white")]
+        color: Color,
+    },
+    Marble {
+        scale: f64,
+        #[serde(default = "default_depth")]
+        depth: u32,
+        #[serde(default = "white")]
+        color: Color,
+    },
+    #[serde(untagged)]
+    Constant(Color),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum CheckerSpace {
+    Solid,
+    Uv,
+}
+
+fn white() -> Color {
+    Color::new(1.0, 1.0, 1.0)
+}
+
+fn solid() -> CheckerSpace {
+    CheckerSpace::Solid
+}
+
+fn default_depth() -> u32 {
+    7
+}
+
+impl Texture {
+    pub fn value(&self, u: f64, v: f64, point: Point3) -> Color {
+        match self {
+            Texture::Constant(color) => *color,
+            Texture::Checker { odd, even, scale, space } => {
+                let cells = match space {
+                    CheckerSpace::Solid =>
+                        (scale * point[0]).floor() + (scale * point[1]).floor() + (scale * point[2]).floor(),
+                    CheckerSpace::Uv => (scale * u).floor() + (scale * v).floor(),
+                };
+                if cells.rem_euclid(2.0) < 1.0 {
+                    even.value(u, v, point)
+                } else {
+                    odd.value(u, v, point)
+                }
+            }
+            Texture::Image(image) => image.value(u, v),
+            Texture::Noise { scale, color } => {
+                *color * 0.5 * (1.0 + PERLIN.with(|perlin| perlin.noise(scaled(point, *scale))))
+            }
+            Texture::Turbulence { scale, depth, color } => {
+                *color * PERLIN.with(|perlin| perlin.turbulence(scaled(point, *scale), *depth))
+            }
+            Texture::Marble { scale, depth, color } => {
+                let phase = scale * point[2] + 10.0 * PERLIN.with(|perlin| perlin.turbulence(point, *depth));
+                *color * 0.5 * (1.0 + phase.sin())
+            }
+        }
+    }
+}
+
+fn scaled(point: Point3, scale: f64) -> Point3 {
+    Point3::new(scale * point[0], scale * point[1], scale * point[2])
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageTextureSpec {
+    pub filename: String,
+}
+
+/// Texture looked up from an image file, wrapping around outside of [0, 1] UV coordinates.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "ImageTextureSpec", into = "ImageTextureSpec")]
+pub struct ImageTexture {
+    spec: ImageTextureSpec,
+    image: Arc<RgbImage>,
+}
+
+impl ImageTexture {
+    pub fn load(spec: ImageTextureSpec) -> Result<ImageTexture, Box<dyn Error>> {
+        let image = image::open(&spec.filename)?.to_rgb8();
+        Ok(ImageTexture { spec, image: Arc::new(image) })
+    }
+
+    pub fn value(&self, u: f64, v: f64) -> Color {
+        let (width, height) = self.image.dimensions();
+        let u = u - u.floor();
+        let v = 1.0 - (v - v.floor());
+        let x = ((u * width as f64) as u32).min(width - 1);
+        let y = ((v * height as f64) as u32).min(height - 1);
+
+        // Undo the gamma applied when writing images so textures round-trip.
+        let pixel = self.image.get_pixel(x, y);
+        let decode = |c: u8| (c as f64 / 255.0).powi(2);
+        Color::new(decode(pixel[0]), decode(pixel[1]), decode(pixel[2]))
+    }
+}
+
+impl TryFrom<ImageTextureSpec> for ImageTexture {
+    type Error = Box<dyn Error>;
+
+    fn try_from(spec: ImageTextureSpec) -> Result<Self, Self::Error> {
+        ImageTexture::load(spec)
+    }
+}
+
+impl From<ImageTexture> for ImageTextureSpec {
+    fn from(texture: ImageTexture) -> Self {
+        texture.spec
+    }
+}
+
+impl fmt::Debug for ImageTexture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ImageTexture")
+            .field("spec", &self.spec)
+            .field("dimensions", &self.image.dimensions())
+            .finish()
+    }
+}
+
+const POINT_COUNT: usize = 256;
+
+/// Gradient noise over random unit vectors, shared by all noise textures.
+struct Perlin {
+    gradients: Vec<Vec3>,
+    perm_x: Vec<usize>,
+    perm_y: Vec<usize>,
+    perm_z: Vec<usize>,
+}
+
+thread_local! {
+    // Every thread builds the same tables from the fixed seed.
+    static PERLIN: Perlin = Perlin::new();
+}
+
+impl Perlin {
+    fn new() -> Perlin {
+        // A fixed seed keeps noise textures identical between renders.
+        let mut rng = SmallRng::seed_from_u64(0x5eed);
+        let gradients = (0..POINT_COUNT).map(|_| Vec3::random_unit_vector(&mut rng)).collect();
+
+        let permutation = |rng: &mut SmallRng| {
+            let mut p = (0..POINT_COUNT).collect::<Vec<_>>();
+            for i in (1..POINT_COUNT).rev() {
+                p.swap(i, rng.gen_range(0..=i));
+            }
+            p
+        };
+
+        Perlin {
+            gradients,
+            perm_x: permutation(&mut rng),
+            perm_y: permutation(&mut rng),
+            perm_z: permutation(&mut rng),
+        }
+    }
+
+    fn noise(&self, point: Point3) -> f64 {
+        let u = point[0] - point[0].floor();
+        let v = point[1] - point[1].floor();
+        let w = point[2] - point[2].floor();
+
+        let i = point[0].floor() as i64;
+        let j = point[1].floor() as i64;
+        let k = point[2].floor() as i64;
+
+        let uu = u * u * (3.0 - 2.0 * u);
+        let vv = v * v * (3.0 - 2.0 * v);
+        let ww = w * w * (3.0 - 2.0 * w);
+
+        let mut accum = 0.0;
+        for di in 0..2 {
+            for dj in 0..2 {
+                for dk in 0..2 {
+                    let index = self.perm_x[((i + di) & 255) as usize]
+                        ^ self.perm_y[((j + dj) & 255) as usize]
+                        ^ self.perm_z[((k + dk) & 255) as usize];
+                    let weight = Vec3::new(u - di as f64, v - dj as f64, w - dk as f64);
+
+                    let (fi, fj, fk) = (di as f64, dj as f64, dk as f64);
+                    accum += (fi * uu + (1.0 - fi) * (1.0 - uu))
+                        * (fj * vv + (1.0 - fj) * (1.0 - vv))
+                        * (fk * ww + (1.0 - fk) * (1.0 - ww))
+                        * self.gradients[index].dot(weight);
+                }
+            }
+        }
+
+        accum
+    }
+
+    fn turbulence(&self, point: Point3, depth: u32) -> f64 {
+        let mut accum = 0.0;
+        let mut p = point;
+        let mut weight = 1.0;
+
+        for _ in 0..depth {
+            accum += weight * self.noise(p);
+            weight *= 0.5;
+            p = scaled(p, 2.0);
+        }
+
+        accum.abs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use crate::test_util::temp_path;
+
+    use super::*;
+
+    fn texture(yaml: &str) -> Texture {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn red(texture: &Texture, u: f64, v: f64, point: Point3) -> f64 {
+        texture.value(u, v, point).r
+    }
+
+    #[test]
+    fn bare_colors_are_constant_textures() {
+        let texture = texture("[0.25, 0.5, 0.75]");
+        let color = texture.value(0.3, 0.7, Point3::new(1.0, 2.0, 3.0));
+        assert_eq!((color.r, color.g, color.b), (0.25, 0.5, 0.75));
+    }
+
+    #[test]
+    fn solid_checker_alternates_across_cells_including_negative_ones() {
+        let checker = texture("type: Checker\nodd: [1.0, 1.0, 1.0]\neven: [0.0, 0.0, 0.0]\nscale: 2.0");
+        assert_eq!(red(&checker, 0.0, 0.0, Point3::new(0.1, 0.1, 0.1)), 0.0);
+        assert_eq!(red(&checker, 0.0, 0.0, Point3::new(0.6, 0.1, 0.1)), 1.0);
+        assert_eq!(red(&checker, 0.0, 0.0, Point3::new(-0.1, 0.1, 0.1)), 1.0);
+        assert_eq!(red(&checker, 0.0, 0.0, Point3::new(-0.1, -0.1, 0.1)), 0.0);
+        assert_eq!(red(&checker, 0.0, 0.0, Point3::new(-0.1, -0.1, -0.1)), 1.0);
+    }
+
+    #[test]
+    fn uv_checker_ignores_the_point() {
+        let checker = texture("type: Checker\nodd: [1.0, 1.0, 1.0]\neven: [0.0, 0.0, 0.0]\nscale: 4.0\nspace: Uv");
+        let point = Point3::new(0.6, 0.1, 0.1);
+        assert_eq!(red(&checker, 0.1, 0.1, point), 0.0);
+        assert_eq!(red(&checker, 0.3, 0.1, point), 1.0);
+        assert_eq!(red(&checker, 0.3, 0.3, point), 0.0);
+    }
+
+    #[test]
+    fn images_are_looked_up_top_down_and_wrap_around() {
+        let path = temp_path("texture.png");
+        let mut image = RgbImage::new(2, 2);
+        image.put_pixel(0, 0, Rgb([255, 0, 0]));
+        image.put_pixel(1, 0, Rgb([0, 255, 0]));
+        image.put_pixel(0, 1, Rgb([0, 0, 255]));
+        image.put_pixel(1, 1, Rgb([255, 255, 255]));
+        image.save(&path).unwrap();
+        let texture = texture(&format!("type: Image\nfilename: {}", path.display()));
+        std::fs::remove_file(&path).unwrap();
+
+        let rgb = |u: f64, v: f64| {
+            let color = texture.value(u, v, Point3::new(0.0, 0.0, 0.0));
+            (color.r, color.g, color.b)
+        };
+        assert_eq!(rgb(0.25, 0.75), (1.0, 0.0, 0.0));
+        assert_eq!(rgb(0.75, 0.75), (0.0, 1.0, 0.0));
+        assert_eq!(rgb(0.25, 0.25), (0.0, 0.0, 1.0));
+        assert_eq!(rgb(1.25, 0.75), (1.0, 0.0, 0.0));
+        assert_eq!(rgb(-0.25, -0.25), (0.0, 1.0, 0.0));
+        assert_eq!(rgb(0.75, 0.25), (1.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn missing_images_are_rejected() {
+        let result = serde_yaml::from_str::<Texture>("type: Image\nfilename: /nonexistent/texture.png");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn noise_is_the_same_on_every_thread() {
+        let noise = texture("type: Turbulence\nscale: 3.0");
+        let point = Point3::new(0.3, 1.7, -2.2);
+        let here = noise.value(0.0, 0.0, point).r;
+        let there = std::thread::spawn(move || noise.value(0.0, 0.0, point).r).join().unwrap();
+        assert_eq!(here, there);
+        assert!(here > 0.0);
+    }
+}
@@ -0,0 +1,480 @@
+use std::ops;
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::bounding_box::BBox;
+use crate::geometry::{area_to_solid_angle_pdf, HitRecord, Hittable, SurfaceSample, tangent_frame};
+use crate::point3::Point3;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+/// Affine transformation stored as a row-major 4x4 matrix acting on column vectors.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Matrix4 {
+    pub m: [[f64; 4]; 4],
+}
+
+impl Matrix4 {
+    pub fn identity() -> Matrix4 {
+        Matrix4::scaling(Vec3::new(1.0, 1.0, 1.0))
+    }
+
+    pub fn translation(offset: Vec3) -> Matrix4 {
+        Matrix4 {
+            m: [
+                [1.0, 0.0, 0.0, offset[0]],
+                [0.0, 1.0, 0.0, offset[1]],
+                [0.0, 0.0, 1.0, offset[2]],
+                [0.0, 0.0, 0.0, 1.0],
+            ]
+        }
+    }
+
+    pub fn scaling(factors: Vec3) -> Matrix4 {
+        Matrix4 {
+            m: [
+                [factors[0], 0.0, 0.0, 0.0],
+                [0.0, factors[1], 0.0, 0.0],
+                [0.0, 0.0, factors[2], 0.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ]
+        }
+    }
+
+    /// Counter-clockwise rotation around `axis` when looking down the axis towards the origin.
+    pub fn rotation(axis: Vec3, angle_deg: f64) -> Matrix4 {
+        let a = axis.normalize();
+        let (sin, cos) = angle_deg.to_radians().sin_cos();
+        let t = 1.0 - cos;
+        Matrix4 {
+            m: [
+                [t * a[0] * a[0] + cos, t * a[0] * a[1] - sin * a[2], t * a[0] * a[2] + sin * a[1], 0.0],
+                [t * a[0] * a[1] + sin * a[2], t * a[1] * a[1] + cos, t * a[1] * a[2] - sin * a[0], 0.0],
+                [t * a[0] * a[2] - sin * a[1], t * a[1] * a[2] + sin * a[0], t * a[2] * a[2] + cos, 0.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ]
+        }
+    }
+
+    /// Rotation around X, then Y, then Z.
+    pub fn euler(angles_deg: Vec3) -> Matrix4 {
+        Matrix4::rotation(Vec3::new(0.0, 0.0, 1.0), angles_deg[2])
+            * Matrix4::rotation(Vec3::new(0.0, 1.0, 0.0), angles_deg[1])
+            * Matrix4::rotation(Vec3::new(1.0, 0.0, 0.0), angles_deg[0])
+    }
+
+    pub fn transpose(&self) -> Matrix4 {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = self.m[j][i];
+            }
+        }
+        Matrix4 { m }
+    }
+
+    /// Gauss-Jordan elimination with partial pivoting, `None` if the matrix is singular.
+    pub fn inverse(&self) -> Option<Matrix4> {
+        let mut a = self.m;
+        let mut inv = Matrix4::identity().m;
+        for col in 0..4 {
+            let pivot = (col..4).fold(col, |pivot, row| if a[row][col].abs() > a[pivot][col].abs() { row } else { pivot });
+            if a[pivot][col].abs() < 1e-12 {
+                return None;
+            }
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+
+            let scale = 1.0 / a[col][col];
+            for j in 0..4 {
+                a[col][j] *= scale;
+                inv[col][j] *= scale;
+            }
+
+            for row in 0..4 {
+                if row == col {
+                    continue;
+                }
+                let factor = a[row][col];
+                for j in 0..4 {
+                    a[row][j] -= factor * a[col][j];
+                    inv[row][j] -= factor * inv[col][j];
+                }
+            }
+        }
+
+        Some(Matrix4 { m: inv })
+    }
+
+    /// Determinant of the upper 3x3 block, i.e. how much the transform scales volumes.
+    pub fn determinant3(&self) -> f64 {
+        let m = &self.m;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    pub fn transform_point(&self, p: Point3) -> Point3 {
+        let m = &self.m;
+        Point3::new(
+            m[0][0] * p[0] + m[0][1] * p[1] + m[0][2] * p[2] + m[0][3],
+            m[1][0] * p[0] + m[1][1] * p[1] + m[1][2] * p[2] + m[1][3],
+            m[2][0] * p[0] + m[2][1] * p[1] + m[2][2] * p[2] + m[2][3],
+        )
+    }
+
+    pub fn transform_vector(&self, v: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0] * v[0] + m[0][1] * v[1] + m[0][2] * v[2],
+            m[1][0] * v[0] + m[1][1] * v[1] + m[1][2] * v[2],
+            m[2][0] * v[0] + m[2][1] * v[1] + m[2][2] * v[2],
+        )
+    }
+}
+
+impl_op_ex!(* |lhs: &Matrix4, rhs: &Matrix4| -> Matrix4 {
+    let mut m = [[0.0; 4]; 4];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..4).map(|k| lhs.m[i][k] * rhs.m[k][j]).sum();
+        }
+    }
+    Matrix4 { m }
+});
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum TransformSpec {
+    Translate {
+        offset: Vec3,
+    },
+    Rotate {
+        axis: Vec3,
+        angle_deg: f64,
+    },
+    Euler {
+        angles_deg: Vec3,
+    },
+    Scale {
+        factors: Vec3,
+    },
+    Matrix {
+        rows: [[f64; 4]; 4],
+    },
+}
+
+impl TransformSpec {
+    pub fn to_matrix(&self) -> Matrix4 {
+        match self {
+            TransformSpec::Translate { offset } => Matrix4::translation(*offset),
+            TransformSpec::Rotate { axis, angle_deg } => Matrix4::rotation(*axis, *angle_deg),
+            TransformSpec::Euler { angles_deg } => Matrix4::euler(*angles_deg),
+            TransformSpec::Scale { factors } => Matrix4::scaling(*factors),
+            TransformSpec::Matrix { rows } => Matrix4 { m: *rows },
+        }
+    }
+
+    /// Interpolates the parameters of two transforms of the same kind, `None` if the kinds
+    /// differ. Rotations go through the intermediate angles rather than blending matrices.
+    pub fn lerp(&self, other: &TransformSpec, t: f64) -> Option<TransformSpec> {
+        let mix = |a: f64, b: f64| a + t * (b - a);
+        let mix_vec = |a: Vec3, b: Vec3| a + t * (b - a);
+        let spec = match (self, other) {
+            (TransformSpec::Translate { offset: a }, TransformSpec::Translate { offset: b }) => {
+                TransformSpec::Translate { offset: mix_vec(*a, *b) }
+            }
+            (TransformSpec::Rotate { axis: a, angle_deg: angle_a }, TransformSpec::Rotate { axis: b, angle_deg: angle_b }) => {
+                TransformSpec::Rotate { axis: mix_vec(a.normalize(), b.normalize()), angle_deg: mix(*angle_a, *angle_b) }
+            }
+            (TransformSpec::Euler { angles_deg: a }, TransformSpec::Euler { angles_deg: b }) => {
+                TransformSpec::Euler { angles_deg: mix_vec(*a, *b) }
+            }
+            (TransformSpec::Scale { factors: a }, TransformSpec::Scale { factors: b }) => {
+                TransformSpec::Scale { factors: mix_vec(*a, *b) }
+            }
+            (TransformSpec::Matrix { rows: a }, TransformSpec::Matrix { rows: b }) => {
+                let mut rows = *a;
+                for (i, row) in rows.iter_mut().enumerate() {
+                    for (j, value) in row.iter_mut().enumerate() {
+                        *value = mix(a[i][j], b[i][j]);
+                    }
+                }
+                TransformSpec::Matrix { rows }
+            }
+            _ => return None
+        };
+        Some(spec)
+    }
+}
+
+/// Composes a list of transforms, applying them to the object in the order they're listed.
+pub fn compose(transforms: &[TransformSpec]) -> Matrix4 {
+    transforms.iter().fold(Matrix4::identity(), |matrix, transform| transform.to_matrix() * matrix)
+}
+
+/// Places `object` in the world through an affine transformation. Rays are moved into object
+/// space for intersection, and hit points, normals and tangents are mapped back.
+pub struct Transform<T: Hittable> {
+    object: T,
+    to_world: Matrix4,
+    to_object: Matrix4,
+    /// Inverse transpose of `to_world`, which maps normals to world space.
+    normal_to_world: Matrix4,
+    bbox: BBox,
+}
+
+impl<T: Hittable> Transform<T> {
+    /// `None` if `to_world` is singular.
+    pub fn new(object: T, to_world: Matrix4) -> Option<Transform<T>> {
+        let to_object = to_world.inverse()?;
+        let bbox = transform_bbox(&to_world, object.bounding_box());
+        Some(Transform { object, to_world, to_object, normal_to_world: to_object.transpose(), bbox })
+    }
+
+    /// Ratio between a world space area element and its object space preimage with
+    /// normal `object_normal` (Nanson's formula).
+    fn area_scale(&self, object_normal: Vec3) -> f64 {
+        self.to_world.determinant3().abs() * self.normal_to_world.transform_vector(object_normal).length()
+            / object_normal.length()
+    }
+}
+
+/// `ray` in the space that `to_object` maps to. The direction isn't renormalized so that `t`
+/// means the same in both spaces.
+pub fn object_ray(to_object: &Matrix4, ray: &Ray) -> Ray {
+    Ray {
+        orig: to_object.transform_point(ray.orig),
+        dir: to_object.transform_vector(ray.dir),
+        time: ray.time,
+    }
+}
+
+/// Intersects `object` placed in the world by `to_world`, whose inverse is `to_object`.
+pub fn hit_transformed<'a, T: Hittable>(
+    rng: &mut dyn RngCore,
+    object: &'a T,
+    to_world: &Matrix4,
+    to_object: &Matrix4,
+    ray: &Ray,
+    t_min: f64,
+    t_max: f64,
+) -> Option<HitRecord<'a>> {
+    object.hit_by(rng, &object_ray(to_object, ray), t_min, t_max).map(|mut rec| {
+        rec.point = to_world.transform_point(rec.point);
+        rec.normal = to_object.transpose().transform_vector(rec.normal).normalize();
+        let (tangent, bitangent) = tangent_frame(rec.normal, to_world.transform_vector(rec.tangent));
+        rec.tangent = tangent;
+        rec.bitangent = bitangent;
+        rec
+    })
+}
+
+pub fn transform_bbox(matrix: &Matrix4, bbox: BBox) -> BBox {
+    let infinite = (0..3).any(|i| bbox.min[i].is_infinite() || bbox.max[i].is_infinite());
+    if infinite {
+        return bbox;
+    }
+
+    let mut min = Point3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY);
+    let mut max = Point3::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY);
+    for corner in 0..8 {
+        let p = matrix.transform_point(Point3::new(
+            if corner & 1 == 0 { bbox.min[0] } else { bbox.max[0] },
+            if corner & 2 == 0 { bbox.min[1] } else { bbox.max[1] },
+            if corner & 4 == 0 { bbox.min[2] } else { bbox.max[2] },
+        ));
+        for i in 0..3 {
+            min[i] = min[i].min(p[i]);
+            max[i] = max[i].max(p[i]);
+        }
+    }
+
+    BBox { min, max }
+}
+
+impl<T: Hittable> Hittable for Transform<T> {
+    fn hit_by(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        hit_transformed(rng, &self.object, &self.to_world, &self.to_object, ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self) -> BBox {
+        self.bbox
+    }
+
+    fn is_bounded(&self) -> bool {
+        self.object.is_bounded()
+    }
+
+    fn transmittance(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: f64, t_max: f64) -> f64 {
+        self.object.transmittance(rng, &object_ray(&self.to_object, ray), t_min, t_max)
+    }
+
+    /// Exact for rotations, translations and uniform scales. Other transforms stretch the
+    /// surface unevenly, but sampling stays consistent with `pdf_value`.
+    fn area(&self) -> f64 {
+        self.object.area() * self.to_world.determinant3().abs().powf(2.0 / 3.0)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.object.is_emissive()
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        let sample = self.object.sample_surface(rng)?;
+        Some(SurfaceSample {
+            point: self.to_world.transform_point(sample.point),
+            normal: self.normal_to_world.transform_vector(sample.normal).normalize(),
+            pdf: sample.pdf / self.area_scale(sample.normal),
+        })
+    }
+
+    fn pdf_value(&self, rng: &mut dyn RngCore, origin: Point3, dir: Vec3, time: f64) -> f64 {
+        let object_origin = self.to_object.transform_point(origin);
+        let object_dir = self.to_object.transform_vector(dir);
+        let object_pdf = self.object.pdf_value(rng, object_origin, object_dir, time);
+        if object_pdf <= 0.0 {
+            return 0.0;
+        }
+
+        // Undo the object space solid angle conversion, then redo it in world space.
+        let rec = match self.object.hit_by(rng, &Ray { orig: object_origin, dir: object_dir, time }, 0.001, f64::INFINITY) {
+            Some(rec) => rec,
+            None => return 0.0
+        };
+        let object_jacobian = area_to_solid_angle_pdf(1.0, object_origin, rec.point, rec.normal);
+        if object_jacobian <= 0.0 {
+            return 0.0;
+        }
+
+        let object_area_pdf = object_pdf / object_jacobian;
+        let world_normal = self.normal_to_world.transform_vector(rec.normal).normalize();
+        let world_point = self.to_world.transform_point(rec.point);
+        area_to_solid_angle_pdf(object_area_pdf / self.area_scale(rec.normal), origin, world_point, world_normal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    use crate::geometry::Sphere;
+    use crate::light::Light;
+    use crate::material::Material;
+    use crate::test_util::{assert_close, assert_vec_close, ray};
+
+    use super::*;
+
+    fn assert_matrix_close(actual: &Matrix4, expected: &Matrix4) {
+        for i in 0..4 {
+            for j in 0..4 {
+                assert_close(actual.m[i][j], expected.m[i][j], 1e-9);
+            }
+        }
+    }
+
+    fn transforms(yaml: &str) -> Vec<TransformSpec> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn inverse_undoes_the_matrix() {
+        let matrix = Matrix4::translation(Vec3::new(1.0, -2.0, 3.0))
+            * Matrix4::rotation(Vec3::new(1.0, 1.0, 0.0), 30.0)
+            * Matrix4::scaling(Vec3::new(2.0, 0.5, 3.0));
+        let inverse = matrix.inverse().unwrap();
+        assert_matrix_close(&(matrix * inverse), &Matrix4::identity());
+        assert_matrix_close(&(inverse * matrix), &Matrix4::identity());
+        assert!(Matrix4::scaling(Vec3::new(1.0, 0.0, 1.0)).inverse().is_none());
+    }
+
+    #[test]
+    fn rotations_are_counter_clockwise() {
+        let rotation = Matrix4::rotation(Vec3::new(0.0, 0.0, 1.0), 90.0);
+        assert_vec_close(rotation.transform_vector(Vec3::new(1.0, 0.0, 0.0)), Vec3::new(0.0, 1.0, 0.0), 1e-12);
+    }
+
+    #[test]
+    fn transforms_apply_in_the_order_listed() {
+        let scale_then_move = compose(&transforms("
+            - type: Scale
+              factors: [2.0, 2.0, 2.0]
+            - type: Translate
+              offset: [1.0, 0.0, 0.0]"));
+        let move_then_scale = compose(&transforms("
+            - type: Translate
+              offset: [1.0, 0.0, 0.0]
+            - type: Scale
+              factors: [2.0, 2.0, 2.0]"));
+
+        let p = Point3::new(1.0, 0.0, 0.0);
+        assert_close(scale_then_move.transform_point(p)[0], 3.0, 1e-12);
+        assert_close(move_then_scale.transform_point(p)[0], 4.0, 1e-12);
+        assert_vec_close(scale_then_move.transform_vector(Vec3::new(1.0, 0.0, 0.0)), Vec3::new(2.0, 0.0, 0.0), 1e-12);
+    }
+
+    #[test]
+    fn hits_are_mapped_back_to_world_space() {
+        let rng = &mut SmallRng::seed_from_u64(0);
+        // The unit sphere stretched along x and moved to x = 5.
+        let to_world = Matrix4::translation(Vec3::new(5.0, 0.0, 0.0)) * Matrix4::scaling(Vec3::new(2.0, 1.0, 1.0));
+        let sphere = Sphere { center: Point3::new(0.0, 0.0, 0.0), radius: 1.0, material: Material::BlackBody };
+        let ellipsoid = Transform::new(sphere, to_world).unwrap();
+
+        let rec = ellipsoid.hit_by(rng, &ray(Point3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0)), 0.001, f64::INFINITY).unwrap();
+        assert_close(rec.t, 3.0, 1e-9);
+        assert_vec_close(rec.normal, Vec3::new(-1.0, 0.0, 0.0), 1e-9);
+
+        // Off-axis normals follow the inverse transpose instead of being stretched.
+        let point = Point3::new(5.0 + 2.0 * 0.6, 0.8, 0.0);
+        let rec = ellipsoid.hit_by(rng, &ray(Point3::new(point[0], 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0)), 0.001, f64::INFINITY).unwrap();
+        assert_vec_close(rec.point - point, Vec3::new(0.0, 0.0, 0.0), 1e-9);
+        assert_vec_close(rec.normal, Vec3::new(0.3, 0.8, 0.0).normalize(), 1e-9);
+
+        let bbox = ellipsoid.bounding_box();
+        assert_vec_close(bbox.min - Point3::new(3.0, -1.0, -1.0), Vec3::new(0.0, 0.0, 0.0), 1e-9);
+        assert_vec_close(bbox.max - Point3::new(7.0, 1.0, 1.0), Vec3::new(0.0, 0.0, 0.0), 1e-9);
+    }
+
+    #[test]
+    fn transformed_light_samples_match_their_pdf() {
+        let rng = &mut SmallRng::seed_from_u64(11);
+        let to_world = Matrix4::translation(Vec3::new(0.0, 0.0, -4.0))
+            * Matrix4::rotation(Vec3::new(0.0, 1.0, 0.0), 40.0)
+            * Matrix4::scaling(Vec3::new(2.0, 0.5, 1.0));
+        let material = serde_yaml::from_str("type: DiffuseLight\nemit: [1.0, 1.0, 1.0]").unwrap();
+        let sphere = Sphere { center: Point3::new(0.0, 0.0, 0.0), radius: 1.0, material };
+        let light = Light::Area(Arc::new(Transform::new(sphere, to_world).unwrap()));
+        let origin = Point3::new(0.5, 0.2, 0.0);
+
+        let mut samples = 0;
+        for _ in 0..1000 {
+            if let Some(sample) = light.sample(rng, origin, 0.0) {
+                assert_close(sample.pdf, light.pdf(rng, origin, sample.dir, 0.0), 1e-6 * sample.pdf);
+                samples += 1;
+            }
+        }
+        assert!(samples > 300);
+    }
+
+    #[test]
+    fn lerp_interpolates_parameters_of_the_same_kind() {
+        let from = TransformSpec::Rotate { axis: Vec3::new(0.0, 2.0, 0.0), angle_deg: 0.0 };
+        let to = TransformSpec::Rotate { axis: Vec3::new(0.0, 1.0, 0.0), angle_deg: 180.0 };
+        // Halfway through a half turn, not halfway between the two matrices, which is singular.
+        let halfway = from.lerp(&to, 0.5).unwrap().to_matrix();
+        assert_vec_close(halfway.transform_vector(Vec3::new(1.0, 0.0, 0.0)), Vec3::new(0.0, 0.0, -1.0), 1e-9);
+
+        let from = TransformSpec::Translate { offset: Vec3::new(1.0, 0.0, 0.0) };
+        let to = TransformSpec::Translate { offset: Vec3::new(3.0, 4.0, 0.0) };
+        let quarter = from.lerp(&to, 0.25).unwrap().to_matrix();
+        assert_vec_close(quarter.transform_point(Point3::new(0.0, 0.0, 0.0)) - Point3::new(0.0, 0.0, 0.0), Vec3::new(1.5, 1.0, 0.0), 1e-9);
+
+        let scale = TransformSpec::Scale { factors: Vec3::new(1.0, 1.0, 1.0) };
+        assert!(from.lerp(&scale, 0.5).is_none());
+    }
+}
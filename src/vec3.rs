@@ -85,6 +85,14 @@ impl Vec3 {
         return r_out_perp + r_out_par;
     }
 
+    /// Returns two unit vectors forming an orthonormal basis together with this unit vector.
+    pub fn orthonormal_basis(&self) -> (Vec3, Vec3) {
+        let a = if self[0].abs() > 0.9 { Vec3::new(0.0, 1.0, 0.0) } else { Vec3::new(1.0, 0.0, 0.0) };
+        let t = self.cross(a).normalize();
+        let b = self.cross(t);
+        (t, b)
+    }
+
     pub fn random(rng: &mut dyn RngCore) -> Vec3 {
         return Vec3::new(
             2.0 * rng.gen::<f64>() - 1.0,
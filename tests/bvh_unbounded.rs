@@ -0,0 +1,84 @@
+use std::sync::Arc;
+
+use itertools::iproduct;
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
+
+use rust_ray_tracer::bvh::{BVHBuilder, BVHNode, FlatBVH, SceneBVH};
+use rust_ray_tracer::camera::Camera;
+use rust_ray_tracer::geometry::{ArcHittable, Hittable, Plane, Sphere};
+use rust_ray_tracer::material::Material;
+use rust_ray_tracer::point3::Point3;
+use rust_ray_tracer::ray::Ray;
+use rust_ray_tracer::vec3::Vec3;
+
+/// A ground plane under many small spheres, like `scene.yaml`.
+fn plane_and_spheres(rng: &mut SmallRng) -> Vec<ArcHittable> {
+    let mut shapes: Vec<ArcHittable> = vec![Arc::new(Plane {
+        center: Point3::new(0.0, 0.0, 0.0),
+        normal: Vec3::new(0.0, 1.0, 0.0),
+        material: Material::BlackBody,
+    })];
+    for _ in 0..2000 {
+        shapes.push(Arc::new(Sphere {
+            center: Point3::new(rng.gen_range(-50.0..50.0), rng.gen_range(0.2..1.0), rng.gen_range(-50.0..50.0)),
+            radius: 0.2,
+            material: Material::BlackBody,
+        }));
+    }
+    shapes
+}
+
+/// Primary rays of a camera looking at the spheres from above, the horizon in the middle.
+fn camera_rays(rng: &mut SmallRng) -> Vec<Ray> {
+    let camera = Camera::create(
+        Point3::new(0.0, 10.0, -60.0),
+        Point3::new(0.0, 0.0, 0.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        60.0,
+        4.0 / 3.0,
+        0.0,
+        10.0,
+    );
+    iproduct!(0..40, 0..30)
+        .map(|(x, y)| camera.get_ray(rng, x as f64 / 39.0, y as f64 / 29.0))
+        .collect()
+}
+
+#[test]
+fn unbounded_shapes_are_kept_out_of_the_bvh() {
+    let mut rng = SmallRng::seed_from_u64(42);
+    let shapes = plane_and_spheres(&mut rng);
+    let rays = camera_rays(&mut rng);
+
+    for builder in [BVHBuilder::Median, BVHBuilder::default()].iter() {
+        let mut all_shapes = shapes.clone();
+        let poisoned = FlatBVH::new(BVHNode::build(&mut rng, *builder, all_shapes.as_mut_slice()));
+        let split = SceneBVH::build(&mut rng, *builder, &shapes);
+
+        // Visits of the rays that hit something, and of those that escape to the sky.
+        let mut poisoned_visits = [0, 0];
+        let mut split_visits = [0, 0];
+        for ray in rays.iter() {
+            let expected = poisoned.hit_by(&mut rng, ray, 0.001, f64::INFINITY).map(|rec| rec.t);
+            let actual = split.hit_by(&mut rng, ray, 0.001, f64::INFINITY).map(|rec| rec.t);
+            assert_eq!(expected, actual);
+
+            let escaped = actual.is_none() as usize;
+            poisoned_visits[escaped] += poisoned.nodes_visited(&mut rng, ray, 0.001, f64::INFINITY);
+            split_visits[escaped] += split.nodes_visited(&mut rng, ray, 0.001, f64::INFINITY);
+        }
+
+        assert!(
+            split_visits[0] < poisoned_visits[0],
+            "{:?}: rays hitting something visited {} nodes without the plane in the BVH, {} with it",
+            builder, split_visits[0], poisoned_visits[0]
+        );
+        // Escaping rays no longer have to descend the infinite boxes of the plane's ancestors.
+        assert!(
+            split_visits[1] * 5 < poisoned_visits[1],
+            "{:?}: escaping rays visited {} nodes without the plane in the BVH, {} with it",
+            builder, split_visits[1], poisoned_visits[1]
+        );
+    }
+}